@@ -0,0 +1,367 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use instant::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::mipmap::MipmapGenerator;
+use crate::model::{self, Material, Mesh, Model, ModelData};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AssetHandle(usize);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AssetState {
+    /// Parsing and decoding images on a worker thread, `total` stays 0
+    /// until the parser knows how many images there are
+    Decoding { done: usize, total: usize },
+    /// Waiting for or in the middle of the GPU upload, counted in meshes and materials
+    Uploading { done: usize, total: usize },
+    /// Ready to be taken with [`AssetLoader::take_model`]
+    Ready,
+    /// Parsing failed, the model will never become ready
+    Failed(String),
+}
+
+impl AssetState {
+    /// Overall progress from 0 to 1, decoding and uploading count half each
+    pub fn progress(&self) -> f32 {
+        let fraction = |done: usize, total: usize| if total == 0 { 1.0 } else { done as f32 / total as f32 };
+        match *self {
+            // Nothing is known before the first progress message, OBJ files
+            // send none while tobj parses them
+            AssetState::Decoding { total: 0, .. } => 0.0,
+            AssetState::Decoding { done, total } => 0.5 * fraction(done, total),
+            AssetState::Uploading { done, total } => 0.5 + 0.5 * fraction(done, total),
+            AssetState::Ready | AssetState::Failed(_) => 1.0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self, AssetState::Ready | AssetState::Failed(_))
+    }
+}
+
+enum Message {
+    Progress(AssetHandle, usize, usize),
+    Parsed(AssetHandle, anyhow::Result<ModelData>),
+}
+
+/// A parsed model whose meshes and materials are uploaded a few at a time
+struct PendingUpload {
+    handle: AssetHandle,
+    data: ModelData,
+    materials: Vec<Material>,
+    meshes: Vec<Mesh>,
+}
+
+impl PendingUpload {
+    fn total(&self) -> usize {
+        self.data.materials.len() + self.data.meshes.len()
+    }
+
+    fn done(&self) -> usize {
+        self.materials.len() + self.meshes.len()
+    }
+
+    /// Uploads the next material or mesh, returns false once everything is uploaded
+    fn upload_next(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmaps: &mut MipmapGenerator,
+        layout: &wgpu::BindGroupLayout,
+    ) -> bool {
+        if let Some(material) = self.data.materials.get(self.materials.len()) {
+            self.materials.push(material.upload(device, queue, mipmaps, layout));
+        } else if let Some(mesh) = self.data.meshes.get(self.meshes.len()) {
+            self.meshes.push(mesh.upload(device));
+        }
+        self.done() < self.total()
+    }
+}
+
+/// Loads models without blocking the render thread.
+///
+/// Files are parsed and their images decoded on worker threads, then
+/// [`AssetLoader::poll`] uploads the results to the GPU, spending at most
+/// `upload_budget` per call so frames keep coming while large models load.
+pub struct AssetLoader {
+    sender: mpsc::Sender<Message>,
+    receiver: mpsc::Receiver<Message>,
+    next_id: usize,
+    states: HashMap<AssetHandle, AssetState>,
+    uploads: VecDeque<PendingUpload>,
+    models: HashMap<AssetHandle, Model>,
+    pub upload_budget: Duration,
+}
+
+impl Default for AssetLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AssetLoader {
+    pub fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            sender,
+            receiver,
+            next_id: 0,
+            states: HashMap::new(),
+            uploads: VecDeque::new(),
+            models: HashMap::new(),
+            upload_budget: Duration::from_millis(4),
+        }
+    }
+
+    /// Starts parsing `path` (OBJ or glTF) on a new worker thread
+    pub fn load_model(&mut self, path: impl Into<PathBuf>) -> AssetHandle {
+        let path = path.into();
+        let handle = AssetHandle(self.next_id);
+        self.next_id += 1;
+        self.states.insert(handle, AssetState::Decoding { done: 0, total: 0 });
+
+        info!("Load model {:?} in the background", path);
+        let sender = self.sender.clone();
+        let spawned = std::thread::Builder::new()
+            .name(format!("asset-loader-{}", handle.0))
+            .spawn(move || {
+                let file_name = path.to_string_lossy();
+                let on_progress = |done, total| {
+                    sender.send(Message::Progress(handle, done, total)).ok();
+                };
+                // A panicking decoder must not leave the asset loading forever
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    model::parse_model(&file_name, &on_progress)
+                }))
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("Loader thread panicked while parsing {:?}", file_name)));
+                sender.send(Message::Parsed(handle, result)).ok();
+            });
+        if let Err(e) = spawned {
+            self.states.insert(handle, AssetState::Failed(format!("Failed to spawn loader thread: {}", e)));
+        }
+        handle
+    }
+
+    pub fn state(&self, handle: AssetHandle) -> Option<&AssetState> {
+        self.states.get(&handle)
+    }
+
+    /// True when no asset is decoding or uploading
+    pub fn is_idle(&self) -> bool {
+        self.states.values().all(AssetState::is_finished)
+    }
+
+    /// Hands out a finished model, or why it failed, after which the handle
+    /// is forgotten. Returns `None` while the model is still loading.
+    pub fn take_model(&mut self, handle: AssetHandle) -> Option<anyhow::Result<Model>> {
+        let result = match self.states.get(&handle)? {
+            AssetState::Ready => Ok(self.models.remove(&handle)?),
+            AssetState::Failed(e) => Err(anyhow::anyhow!("{}", e)),
+            AssetState::Decoding { .. } | AssetState::Uploading { .. } => return None,
+        };
+        self.states.remove(&handle);
+        Some(result)
+    }
+
+    /// Collects results from the workers and continues the GPU uploads until
+    /// `upload_budget` is used up. Call once per frame.
+    pub fn poll(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmaps: &mut MipmapGenerator,
+        layout: &wgpu::BindGroupLayout,
+    ) {
+        while let Ok(message) = self.receiver.try_recv() {
+            self.handle_message(message);
+        }
+        self.upload(device, queue, mipmaps, layout, Some(self.upload_budget));
+    }
+
+    /// Blocks until every requested asset is ready or has failed
+    pub fn wait(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmaps: &mut MipmapGenerator,
+        layout: &wgpu::BindGroupLayout,
+    ) {
+        while !self.is_idle() {
+            if self.uploads.is_empty() {
+                // The loader holds a sender itself, so this can't disconnect
+                let message = self.receiver.recv().expect("asset loader channel closed");
+                self.handle_message(message);
+            }
+            while let Ok(message) = self.receiver.try_recv() {
+                self.handle_message(message);
+            }
+            self.upload(device, queue, mipmaps, layout, None);
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        match message {
+            Message::Progress(handle, done, total) => {
+                self.states.insert(handle, AssetState::Decoding { done, total });
+            }
+            Message::Parsed(handle, Ok(data)) => {
+                let upload = PendingUpload {
+                    handle,
+                    data,
+                    materials: Vec::new(),
+                    meshes: Vec::new(),
+                };
+                self.states.insert(
+                    handle,
+                    AssetState::Uploading {
+                        done: 0,
+                        total: upload.total(),
+                    },
+                );
+                self.uploads.push_back(upload);
+            }
+            Message::Parsed(handle, Err(e)) => {
+                warn!("Failed to load asset: {:?}", e);
+                self.states.insert(handle, AssetState::Failed(format!("{:#}", e)));
+            }
+        }
+    }
+
+    /// Uploads at least one item, then more until `budget` runs out
+    fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmaps: &mut MipmapGenerator,
+        layout: &wgpu::BindGroupLayout,
+        budget: Option<Duration>,
+    ) {
+        self.advance_uploads(budget, |upload| upload.upload_next(device, queue, mipmaps, layout));
+    }
+
+    /// Drives the upload queue, `upload_next` does the GPU work of one item
+    fn advance_uploads(&mut self, budget: Option<Duration>, mut upload_next: impl FnMut(&mut PendingUpload) -> bool) {
+        let start = Instant::now();
+        while let Some(upload) = self.uploads.front_mut() {
+            let more = upload.total() > 0 && upload_next(upload);
+            self.states.insert(
+                upload.handle,
+                AssetState::Uploading {
+                    done: upload.done(),
+                    total: upload.total(),
+                },
+            );
+            if !more {
+                let upload = self.uploads.pop_front().unwrap();
+                self.states.insert(upload.handle, AssetState::Ready);
+                self.models.insert(
+                    upload.handle,
+                    Model {
+                        meshes: upload.meshes,
+                        materials: upload.materials,
+                        aabb: upload.data.aabb(),
+                        bounding_sphere: upload.data.bounding_sphere(),
+                    },
+                );
+            }
+            if budget.is_some_and(|budget| start.elapsed() >= budget) {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loading(handle: AssetHandle) -> AssetLoader {
+        let mut loader = AssetLoader::new();
+        loader.next_id = handle.0 + 1;
+        loader.states.insert(handle, AssetState::Decoding { done: 0, total: 0 });
+        loader
+    }
+
+    /// Uploads without a GPU, only works for models without meshes or materials
+    fn upload_all(loader: &mut AssetLoader) {
+        loader.advance_uploads(None, |_| panic!("nothing to upload"));
+    }
+
+    #[test]
+    fn progress_covers_decoding_then_uploading() {
+        assert_eq!(AssetState::Decoding { done: 0, total: 0 }.progress(), 0.0);
+        assert_eq!(AssetState::Decoding { done: 0, total: 4 }.progress(), 0.0);
+        assert_eq!(AssetState::Decoding { done: 2, total: 4 }.progress(), 0.25);
+        assert_eq!(AssetState::Uploading { done: 0, total: 4 }.progress(), 0.5);
+        assert_eq!(AssetState::Uploading { done: 3, total: 4 }.progress(), 0.875);
+        assert_eq!(AssetState::Ready.progress(), 1.0);
+        assert_eq!(AssetState::Failed("broken".to_string()).progress(), 1.0);
+
+        assert!(!AssetState::Uploading { done: 4, total: 4 }.is_finished());
+        assert!(AssetState::Ready.is_finished());
+        assert!(AssetState::Failed(String::new()).is_finished());
+    }
+
+    #[test]
+    fn messages_move_the_asset_to_ready() {
+        let handle = AssetHandle(0);
+        let mut loader = loading(handle);
+        assert!(!loader.is_idle());
+
+        loader.handle_message(Message::Progress(handle, 1, 3));
+        assert_eq!(loader.state(handle), Some(&AssetState::Decoding { done: 1, total: 3 }));
+
+        loader.handle_message(Message::Parsed(handle, Ok(ModelData::default())));
+        assert_eq!(loader.state(handle), Some(&AssetState::Uploading { done: 0, total: 0 }));
+        assert!(!loader.is_idle());
+
+        upload_all(&mut loader);
+        assert_eq!(loader.state(handle), Some(&AssetState::Ready));
+        assert!(loader.is_idle());
+    }
+
+    #[test]
+    fn parse_errors_fail_the_asset() {
+        let handle = AssetHandle(0);
+        let mut loader = loading(handle);
+        loader.handle_message(Message::Progress(handle, 1, 3));
+        loader.handle_message(Message::Parsed(handle, Err(anyhow::anyhow!("no such file"))));
+        assert_eq!(loader.state(handle), Some(&AssetState::Failed("no such file".to_string())));
+        assert!(loader.is_idle());
+    }
+
+    #[test]
+    fn take_model_waits_until_the_asset_is_finished() {
+        let handle = AssetHandle(0);
+        let mut loader = loading(handle);
+        assert!(loader.take_model(handle).is_none());
+
+        loader.handle_message(Message::Parsed(handle, Ok(ModelData::default())));
+        assert!(loader.take_model(handle).is_none());
+        assert!(loader.state(handle).is_some());
+
+        upload_all(&mut loader);
+        let model = loader.take_model(handle).expect("model is ready").unwrap();
+        assert!(model.meshes.is_empty());
+        assert!(loader.state(handle).is_none());
+        assert!(loader.take_model(handle).is_none());
+    }
+
+    #[test]
+    fn take_model_hands_out_failures_once() {
+        let handle = AssetHandle(0);
+        let mut loader = loading(handle);
+        loader.handle_message(Message::Parsed(handle, Err(anyhow::anyhow!("bad header"))));
+
+        let Some(Err(error)) = loader.take_model(handle) else {
+            panic!("expected the failure");
+        };
+        assert_eq!(error.to_string(), "bad header");
+        assert!(loader.state(handle).is_none());
+        assert!(loader.take_model(handle).is_none());
+    }
+}
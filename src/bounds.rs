@@ -0,0 +1,187 @@
+//! Bounding volumes of meshes and models, computed once in model space and
+//! moved into world space with `transform` or `transformed_by`.
+
+use cgmath::{EuclideanSpace, InnerSpace, MetricSpace, Point3, Vector3};
+
+use crate::instance::Instance;
+
+/// Axis aligned bounding box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Default for Aabb {
+    /// An empty box at the origin
+    fn default() -> Self {
+        Self {
+            min: Point3::origin(),
+            max: Point3::origin(),
+        }
+    }
+}
+
+impl Aabb {
+    /// The smallest box containing `points`, a zero sized box at the origin
+    /// when there are none
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>>) -> Self {
+        let mut points = points.into_iter();
+        let Some(first) = points.next() else {
+            return Self::default();
+        };
+        points.fold(Self { min: first, max: first }, |aabb, p| Self {
+            min: Point3::new(aabb.min.x.min(p.x), aabb.min.y.min(p.y), aabb.min.z.min(p.z)),
+            max: Point3::new(aabb.max.x.max(p.x), aabb.max.y.max(p.y), aabb.max.z.max(p.z)),
+        })
+    }
+
+    /// The smallest box containing both boxes
+    pub fn union(&self, other: &Aabb) -> Self {
+        Self::from_points([self.min, self.max, other.min, other.max])
+    }
+
+    pub fn center(&self) -> Point3<f32> {
+        self.min.midpoint(self.max)
+    }
+
+    pub fn size(&self) -> Vector3<f32> {
+        self.max - self.min
+    }
+
+    pub fn corners(&self) -> [Point3<f32>; 8] {
+        let (min, max) = (self.min, self.max);
+        std::array::from_fn(|i| {
+            Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        })
+    }
+
+    pub fn contains(&self, p: Point3<f32>) -> bool {
+        (self.min.x..=self.max.x).contains(&p.x)
+            && (self.min.y..=self.max.y).contains(&p.y)
+            && (self.min.z..=self.max.z).contains(&p.z)
+    }
+
+    /// The box around this one after `matrix`, which is larger than the
+    /// original when it rotates
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        Self::from_points(self.corners().map(|corner| Point3::from_homogeneous(matrix * corner.to_homogeneous())))
+    }
+
+    pub fn transformed_by(&self, instance: &Instance) -> Self {
+        self.transform(&instance.to_matrix())
+    }
+}
+
+/// A sphere enclosing a mesh or model in its local space
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Default for BoundingSphere {
+    fn default() -> Self {
+        Self {
+            center: Point3::origin(),
+            radius: 0.0,
+        }
+    }
+}
+
+impl BoundingSphere {
+    /// Centered on the box around `points`, not the smallest possible sphere
+    /// but close enough for culling. Empty inputs give a zero sized sphere.
+    pub fn from_points(points: impl IntoIterator<Item = Point3<f32>> + Clone) -> Self {
+        let center = Aabb::from_points(points.clone()).center();
+        let radius = points
+            .into_iter()
+            .map(|p| p.distance2(center))
+            .fold(0.0, f32::max)
+            .sqrt();
+        Self { center, radius }
+    }
+
+    /// The sphere after `matrix`, scaled by its largest axis scale so it
+    /// still encloses everything under non-uniform scaling
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> Self {
+        let scale = [matrix.x, matrix.y, matrix.z]
+            .iter()
+            .map(|axis| axis.truncate().magnitude())
+            .fold(0.0, f32::max);
+        Self {
+            center: Point3::from_homogeneous(matrix * self.center.to_homogeneous()),
+            radius: self.radius * scale,
+        }
+    }
+
+    pub fn transformed_by(&self, instance: &Instance) -> Self {
+        self.transform(&instance.to_matrix())
+    }
+
+    pub fn contains(&self, p: Point3<f32>) -> bool {
+        p.distance2(self.center) <= self.radius * self.radius
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, Rotation3};
+
+    use super::*;
+
+    fn points() -> Vec<Point3<f32>> {
+        vec![
+            Point3::new(-1.0, 0.0, 2.0),
+            Point3::new(3.0, -2.0, 0.5),
+            Point3::new(0.0, 4.0, -1.0),
+        ]
+    }
+
+    fn instance() -> Instance {
+        Instance {
+            position: Vector3::new(5.0, -1.0, 2.0),
+            rotation: cgmath::Quaternion::from_axis_angle(Vector3::new(0.3, 1.0, -0.2).normalize(), Deg(70.0)),
+            scaling: Vector3::new(2.0, 0.5, 1.5),
+        }
+    }
+
+    #[test]
+    fn aabb_of_points() {
+        let aabb = Aabb::from_points(points());
+        assert_eq!(aabb.min, Point3::new(-1.0, -2.0, -1.0));
+        assert_eq!(aabb.max, Point3::new(3.0, 4.0, 2.0));
+        assert_eq!(aabb.center(), Point3::new(1.0, 1.0, 0.5));
+        assert_eq!(Aabb::from_points(Vec::new()), Aabb::default());
+    }
+
+    #[test]
+    fn sphere_contains_its_points() {
+        let sphere = BoundingSphere::from_points(points());
+        assert!(points().iter().all(|&p| sphere.contains(p)));
+    }
+
+    #[test]
+    fn transformed_volumes_contain_transformed_points() {
+        let instance = instance();
+        let matrix = instance.to_matrix();
+        let aabb = Aabb::from_points(points()).transformed_by(&instance);
+        // Pad the sphere for rounding errors
+        let mut sphere = BoundingSphere::from_points(points()).transformed_by(&instance);
+        sphere.radius += 1e-4;
+        for p in points() {
+            let p = Point3::from_homogeneous(matrix * p.to_homogeneous());
+            assert!(sphere.contains(p), "{:?} outside {:?}", p, sphere);
+            let epsilon = Vector3::new(1e-4, 1e-4, 1e-4);
+            let padded = Aabb {
+                min: aabb.min - epsilon,
+                max: aabb.max + epsilon,
+            };
+            assert!(padded.contains(p), "{:?} outside {:?}", p, aabb);
+        }
+    }
+}
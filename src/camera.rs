@@ -1,9 +1,5 @@
 use wgpu::util::DeviceExt;
-use winit::{
-    event::{Event, WindowEvent, KeyEvent, ElementState},
-    event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder, keyboard::{PhysicalKey, KeyCode, NativeKeyCode},
-};
+use winit::keyboard::KeyCode;
 
 use cgmath::*;
 use winit::event::*;
@@ -12,14 +8,18 @@ use winit::dpi::PhysicalPosition;
 
 
 use instant::Duration;
+use crate::bounds::BoundingSphere;
+use crate::texture::DepthMode;
 use std::f32::consts::FRAC_PI_2;
 
+/// Maps OpenGL's -1..1 clip space depth to wgpu's 0..1. `Matrix4::new`
+/// takes columns, so the 0.5 offset goes into the last one.
 #[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.5,
-    0.0, 0.0, 0.0, 1.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
 );
 
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
@@ -51,26 +51,61 @@ impl Camera {
     }
 
     pub fn calc_matrix(&self) -> Matrix4<f32> {
+        Matrix4::look_to_rh(self.position, self.forward(), Vector3::unit_y())
+    }
+
+    /// Unit vector the camera looks along
+    pub fn forward(&self) -> Vector3<f32> {
         let (sin_pitch, cos_pitch) = self.pitch.0.sin_cos();
         let (sin_yaw, cos_yaw) = self.yaw.0.sin_cos();
+        Vector3::new(cos_pitch * cos_yaw, sin_pitch, cos_pitch * sin_yaw).normalize()
+    }
 
-        Matrix4::look_to_rh(
-            self.position,
-            Vector3::new(
-                cos_pitch * cos_yaw,
-                sin_pitch,
-                cos_pitch * sin_yaw
-            ).normalize(),
-            Vector3::unit_y(),
-        )
+    /// Backs the camera away from `bounds` along its current view direction
+    /// until the sphere fills the view, and fits the depth range of
+    /// `projection` around it with some room to move
+    pub fn focus_on(&mut self, bounds: &BoundingSphere, projection: &mut Projection) {
+        // A single point still gets a sensible distance
+        let radius = if bounds.radius > 0.0 { bounds.radius } else { 1.0 };
+        let half_fovy = projection.fovy.0 / 2.0;
+        let half_fovx = (half_fovy.tan() * projection.aspect).atan();
+        let distance = radius / half_fovy.min(half_fovx).sin();
+
+        self.position = bounds.center - self.forward() * distance;
+        projection.znear = ((distance - radius) * 0.5).max(distance * 0.001);
+        projection.zfar = (distance + radius) * 4.0;
+        if let ProjectionKind::Orthographic { height } = &mut projection.kind {
+            *height = 2.0 * radius / projection.aspect.min(1.0);
+        }
     }
+
+    /// Looks at `target` from the direction given by `yaw` and `pitch`,
+    /// keeping the current distance to it
+    pub fn look_at_from<Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(&mut self, target: Point3<f32>, yaw: Y, pitch: P) {
+        let distance = self.position.distance(target);
+        self.yaw = yaw.into();
+        self.pitch = clamp_pitch(pitch.into());
+        self.position = target - self.forward() * distance;
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ProjectionKind {
+    Perspective,
+    /// Parallel projection showing `height` world units vertically
+    Orthographic { height: f32 },
 }
 
 pub struct Projection {
     aspect: f32,
+    /// Vertical field of view, kept while orthographic to switch back
     fovy: Rad<f32>,
     znear: f32,
+    /// Reverse-Z perspective projections reach to infinity, then it only
+    /// scales the depth debug views
     zfar: f32,
+    depth_mode: DepthMode,
+    pub kind: ProjectionKind,
 }
 
 impl Projection {
@@ -80,12 +115,17 @@ impl Projection {
         fovy: F,
         znear: f32,
         zfar: f32,
+        depth_mode: DepthMode,
     ) -> Self {
         Self {
             aspect: width as f32 / height as f32,
             fovy: fovy.into(),
             znear,
-            zfar,
+            // The orthographic matrices divide by the depth range, keep it
+            // positive however the planes were configured
+            zfar: zfar.max(znear + znear.abs().max(1.0) * 1e-3),
+            depth_mode,
+            kind: ProjectionKind::Perspective,
         }
     }
 
@@ -93,8 +133,95 @@ impl Projection {
         self.aspect = width as f32 / height as f32;
     }
 
+    pub fn fovy(&self) -> Rad<f32> {
+        self.fovy
+    }
+
+    pub fn aspect(&self) -> f32 {
+        self.aspect
+    }
+
+    pub fn znear(&self) -> f32 {
+        self.znear
+    }
+
+    pub fn zfar(&self) -> f32 {
+        self.zfar
+    }
+
+    /// Fixed at creation since the depth test of the pipelines depends on it
+    pub fn depth_mode(&self) -> DepthMode {
+        self.depth_mode
+    }
+
+    pub fn is_orthographic(&self) -> bool {
+        matches!(self.kind, ProjectionKind::Orthographic { .. })
+    }
+
+    /// Switches between perspective and orthographic. Things `distance` in
+    /// front of the camera keep their size on screen.
+    pub fn toggle_orthographic(&mut self, distance: f32) {
+        self.kind = match self.kind {
+            ProjectionKind::Perspective => ProjectionKind::Orthographic {
+                height: 2.0 * distance * (self.fovy / 2.0).tan(),
+            },
+            ProjectionKind::Orthographic { .. } => ProjectionKind::Perspective,
+        };
+    }
+
+    /// Zooms orthographic projections, returns false for perspective ones,
+    /// where moving the camera is the way to zoom
+    pub fn process_scroll(&mut self, delta: &MouseScrollDelta) -> bool {
+        match &mut self.kind {
+            ProjectionKind::Perspective => false,
+            ProjectionKind::Orthographic { height } => {
+                // Scrolling 100 pixels changes the height by about 10%
+                *height = (*height * (scroll_pixels(delta) * 0.001).exp()).max(1e-4);
+                true
+            }
+        }
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
-        OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
+        if self.depth_mode == DepthMode::ReverseZ {
+            return self.calc_reverse_z_matrix();
+        }
+        let projection = match self.kind {
+            ProjectionKind::Perspective => perspective(self.fovy, self.aspect, self.znear, self.zfar),
+            ProjectionKind::Orthographic { height } => {
+                let (half_width, half_height) = (height * self.aspect / 2.0, height / 2.0);
+                ortho(-half_width, half_width, -half_height, half_height, self.znear, self.zfar)
+            }
+        };
+        OPENGL_TO_WGPU_MATRIX * projection
+    }
+
+    /// Maps `znear` to a depth of 1 and infinity, or `zfar` when
+    /// orthographic, to 0. Built directly in wgpu's 0..1 depth range.
+    #[rustfmt::skip]
+    fn calc_reverse_z_matrix(&self) -> Matrix4<f32> {
+        match self.kind {
+            ProjectionKind::Perspective => {
+                let f = 1.0 / (self.fovy / 2.0).tan();
+                // Depth is znear / distance
+                Matrix4::new(
+                    f / self.aspect, 0.0, 0.0, 0.0,
+                    0.0, f, 0.0, 0.0,
+                    0.0, 0.0, 0.0, -1.0,
+                    0.0, 0.0, self.znear, 0.0,
+                )
+            }
+            ProjectionKind::Orthographic { height } => {
+                let (half_width, half_height) = (height * self.aspect / 2.0, height / 2.0);
+                let range = self.zfar - self.znear;
+                Matrix4::new(
+                    1.0 / half_width, 0.0, 0.0, 0.0,
+                    0.0, 1.0 / half_height, 0.0, 0.0,
+                    0.0, 0.0, 1.0 / range, 0.0,
+                    0.0, 0.0, self.zfar / range, 1.0,
+                )
+            }
+        }
     }
 }
 
@@ -110,6 +237,20 @@ pub struct CameraUniform {
     pub view_position: [f32; 4],
 
     pub view_proj: [[f32; 4]; 4],
+    /// World to view space, for passes working in view space like SSAO
+    pub view: [[f32; 4]; 4],
+    /// `znear`, `zfar`, then 1.0 for reverse-Z and 1.0 for orthographic, to
+    /// turn depth back into distance
+    pub depth_range: [f32; 4],
+    /// [`DebugView`](crate::depth_pass::DebugView) the main shader writes
+    pub debug_view: u32,
+    _padding: [u32; 3],
+}
+
+impl Default for CameraUniform {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CameraUniform {
@@ -117,12 +258,24 @@ impl CameraUniform {
         Self {
             view_position: [0.0; 4],
             view_proj: cgmath::Matrix4::identity().into(),
+            view: cgmath::Matrix4::identity().into(),
+            depth_range: [0.0; 4],
+            debug_view: 0,
+            _padding: [0; 3],
         }
     }
 
     pub fn update_view_proj(&mut self, camera: &Camera, projection: &Projection) {
         self.view_position = camera.position.to_homogeneous().into();
         self.view_proj = (projection.calc_matrix() * camera.calc_matrix()).into();
+        self.view = camera.calc_matrix().into();
+        let flag = |set: bool| if set { 1.0 } else { 0.0 };
+        self.depth_range = [
+            projection.znear,
+            projection.zfar,
+            flag(projection.depth_mode == DepthMode::ReverseZ),
+            flag(matches!(projection.kind, ProjectionKind::Orthographic { .. })),
+        ];
     }
 
     pub fn create_camera_buffer_bind_group(
@@ -168,6 +321,44 @@ impl CameraUniform {
     }
 }
 
+/// Input handling shared by the camera controllers, so they can be swapped
+/// at runtime. Mouse motion is passed on unconditionally, controllers track
+/// the buttons they care about themselves.
+pub trait CameraControl {
+    /// Returns whether the key was used
+    fn process_keyboard(&mut self, key: KeyCode, state: ElementState) -> bool;
+    /// Returns whether the button was used
+    fn process_mouse_button(&mut self, button: MouseButton, state: ElementState) -> bool;
+    fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64);
+    fn process_scroll(&mut self, delta: &MouseScrollDelta);
+    fn update_camera(&mut self, camera: &mut Camera, dt: Duration);
+    /// Called when the camera was moved to look at `target`, e.g. after focusing
+    fn set_target(&mut self, _target: Point3<f32>) {}
+    /// The point the controller moves around, if it has one
+    fn target(&self) -> Option<Point3<f32>> {
+        None
+    }
+}
+
+/// Scroll distance in pixels, positive when scrolling towards the user
+fn scroll_pixels(delta: &MouseScrollDelta) -> f32 {
+    -match delta {
+        // I'm assuming a line is about 100 pixels
+        MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
+        MouseScrollDelta::PixelDelta(PhysicalPosition {
+            y: scroll,
+            ..
+        }) => *scroll as f32,
+    }
+}
+
+fn clamp_pitch(pitch: Rad<f32>) -> Rad<f32> {
+    // Keep the camera's angle from going too high/low.
+    Rad(pitch.0.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2))
+}
+
+/// Fly-style controller: WASD/arrows move, space and shift go up and down,
+/// dragging with the left mouse button looks around
 #[derive(Debug)]
 pub struct CameraController {
     amount_left: f32,
@@ -181,6 +372,7 @@ pub struct CameraController {
     scroll: f32,
     speed: f32,
     sensitivity: f32,
+    mouse_pressed: bool,
 }
 
 impl CameraController {
@@ -197,10 +389,13 @@ impl CameraController {
             scroll: 0.0,
             speed,
             sensitivity,
+            mouse_pressed: false,
         }
     }
+}
 
-    pub fn process_keyboard(&mut self, key: KeyCode, state: ElementState) -> bool{
+impl CameraControl for CameraController {
+    fn process_keyboard(&mut self, key: KeyCode, state: ElementState) -> bool{
         let amount = if state == ElementState::Pressed { 1.0 } else { 0.0 };
         match key {
             KeyCode::KeyW | KeyCode::ArrowUp => {
@@ -231,23 +426,26 @@ impl CameraController {
         }
     }
 
-    pub fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
-        self.rotate_horizontal = mouse_dx as f32;
-        self.rotate_vertical = mouse_dy as f32;
+    fn process_mouse_button(&mut self, button: MouseButton, state: ElementState) -> bool {
+        if button != MouseButton::Left {
+            return false;
+        }
+        self.mouse_pressed = state == ElementState::Pressed;
+        true
     }
 
-    pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
-        self.scroll = -match delta {
-            // I'm assuming a line is about 100 pixels
-            MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
-            MouseScrollDelta::PixelDelta(PhysicalPosition {
-                y: scroll,
-                ..
-            }) => *scroll as f32,
-        };
+    fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        if self.mouse_pressed {
+            self.rotate_horizontal = mouse_dx as f32;
+            self.rotate_vertical = mouse_dy as f32;
+        }
+    }
+
+    fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+        self.scroll = scroll_pixels(delta);
     }
 
-    pub fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
+    fn update_camera(&mut self, camera: &mut Camera, dt: Duration) {
         let dt = dt.as_secs_f32();
 
         // Move forward/backward and left/right
@@ -280,11 +478,177 @@ impl CameraController {
         self.rotate_horizontal = 0.0;
         self.rotate_vertical = 0.0;
 
-        // Keep the camera's angle from going too high/low.
-        if camera.pitch < -Rad(SAFE_FRAC_PI_2) {
-            camera.pitch = -Rad(SAFE_FRAC_PI_2);
-        } else if camera.pitch > Rad(SAFE_FRAC_PI_2) {
-            camera.pitch = Rad(SAFE_FRAC_PI_2);
+        camera.pitch = clamp_pitch(camera.pitch);
+    }
+}
+
+/// Turntable-style controller that circles a target point: dragging with the
+/// left mouse button orbits, the middle button pans the target and the scroll
+/// wheel dollies towards or away from it
+#[derive(Debug)]
+pub struct OrbitController {
+    /// `None` until the first update, which picks a point in front of the camera
+    target: Option<Point3<f32>>,
+    /// Distance to the target used when none is set
+    default_distance: f32,
+    rotate_horizontal: f32,
+    rotate_vertical: f32,
+    pan_horizontal: f32,
+    pan_vertical: f32,
+    scroll: f32,
+    sensitivity: f32,
+    rotating: bool,
+    panning: bool,
+}
+
+impl OrbitController {
+    /// Orbits the point `default_distance` in front of the camera until
+    /// [`CameraControl::set_target`] is called
+    pub fn new(default_distance: f32, sensitivity: f32) -> Self {
+        Self {
+            target: None,
+            default_distance,
+            rotate_horizontal: 0.0,
+            rotate_vertical: 0.0,
+            pan_horizontal: 0.0,
+            pan_vertical: 0.0,
+            scroll: 0.0,
+            sensitivity,
+            rotating: false,
+            panning: false,
+        }
+    }
+}
+
+impl CameraControl for OrbitController {
+    fn process_keyboard(&mut self, _key: KeyCode, _state: ElementState) -> bool {
+        false
+    }
+
+    fn process_mouse_button(&mut self, button: MouseButton, state: ElementState) -> bool {
+        let pressed = state == ElementState::Pressed;
+        match button {
+            MouseButton::Left => self.rotating = pressed,
+            MouseButton::Middle => self.panning = pressed,
+            _ => return false,
+        }
+        true
+    }
+
+    fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64) {
+        if self.rotating {
+            self.rotate_horizontal += mouse_dx as f32;
+            self.rotate_vertical += mouse_dy as f32;
+        } else if self.panning {
+            self.pan_horizontal += mouse_dx as f32;
+            self.pan_vertical += mouse_dy as f32;
+        }
+    }
+
+    fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+        self.scroll += scroll_pixels(delta);
+    }
+
+    fn update_camera(&mut self, camera: &mut Camera, _dt: Duration) {
+        let target = *self
+            .target
+            .get_or_insert_with(|| camera.position + camera.forward() * self.default_distance);
+        let mut distance = (camera.position - target).magnitude().max(1e-3);
+
+        // Mouse deltas are already per frame, so unlike the fly controller
+        // they aren't scaled by dt
+        camera.yaw += Rad(self.rotate_horizontal) * self.sensitivity * 0.01;
+        camera.pitch = clamp_pitch(camera.pitch + Rad(-self.rotate_vertical) * self.sensitivity * 0.01);
+
+        // Pan proportionally to the distance, so the target follows the cursor
+        let forward = camera.forward();
+        let right = forward.cross(Vector3::unit_y()).normalize();
+        let up = right.cross(forward);
+        let pan = distance * self.sensitivity * 0.005;
+        let target = target + (-right * self.pan_horizontal + up * self.pan_vertical) * pan;
+
+        // Scrolling 100 pixels changes the distance by about 10%
+        distance *= (self.scroll * 0.001).exp();
+
+        camera.position = target - forward * distance;
+        self.target = Some(target);
+        self.rotate_horizontal = 0.0;
+        self.rotate_vertical = 0.0;
+        self.pan_horizontal = 0.0;
+        self.pan_vertical = 0.0;
+        self.scroll = 0.0;
+    }
+
+    fn set_target(&mut self, target: Point3<f32>) {
+        self.target = Some(target);
+    }
+
+    fn target(&self) -> Option<Point3<f32>> {
+        self.target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clip space depth and w of a point `distance` in front of the camera
+    fn depth(projection: &Projection, distance: f32) -> (f32, f32) {
+        let clip = projection.calc_matrix() * cgmath::Vector4::new(0.0, 0.0, -distance, 1.0);
+        (clip.z / clip.w, clip.w)
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn standard_depth_runs_from_near_to_far() {
+        let mut projection = Projection::new(4, 3, Deg(60.0), 0.5, 50.0, DepthMode::Standard);
+        for distance in [0.5, 50.0] {
+            // The remap must leave w alone, or the perspective divide is off
+            let (_, w) = depth(&projection, distance);
+            assert_close(w, distance);
+        }
+        assert_close(depth(&projection, 0.5).0, 0.0);
+        assert_close(depth(&projection, 50.0).0, 1.0);
+
+        projection.toggle_orthographic(10.0);
+        assert_close(depth(&projection, 0.5).0, 0.0);
+        assert_close(depth(&projection, 25.25).0, 0.5);
+        assert_close(depth(&projection, 50.0).0, 1.0);
+    }
+
+    #[test]
+    fn reverse_z_depth_runs_from_far_to_near() {
+        let mut projection = Projection::new(4, 3, Deg(60.0), 0.5, 50.0, DepthMode::ReverseZ);
+        assert_close(depth(&projection, 0.5).0, 1.0);
+        assert_close(depth(&projection, 5.0).0, 0.1);
+
+        projection.toggle_orthographic(10.0);
+        assert_close(depth(&projection, 0.5).0, 1.0);
+        assert_close(depth(&projection, 50.0).0, 0.0);
+    }
+
+    #[test]
+    fn far_plane_is_kept_beyond_the_near_plane() {
+        for depth_mode in [DepthMode::Standard, DepthMode::ReverseZ] {
+            for zfar in [2.0, 1.0, -3.0] {
+                let mut projection = Projection::new(4, 3, Deg(60.0), 2.0, zfar, depth_mode);
+                assert!(projection.zfar() > projection.znear(), "zfar {} in {:?}", projection.zfar(), depth_mode);
+
+                projection.toggle_orthographic(10.0);
+                let (near, _) = depth(&projection, 2.0);
+                let (far, _) = depth(&projection, projection.zfar());
+                let (beyond, _) = depth(&projection, 5.0);
+                assert!(near.is_finite() && far.is_finite() && beyond.is_finite());
+                let (expected_near, expected_far) = match depth_mode {
+                    DepthMode::Standard => (0.0, 1.0),
+                    DepthMode::ReverseZ => (1.0, 0.0),
+                };
+                assert!((near - expected_near).abs() < 1e-3, "{} != {} in {:?}", near, expected_near, depth_mode);
+                assert!((far - expected_far).abs() < 1e-3, "{} != {} in {:?}", far, expected_far, depth_mode);
+            }
         }
     }
 }
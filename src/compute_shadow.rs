@@ -1,4 +1,271 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Vector3};
+use std::ops::Range;
+use wgpu::util::DeviceExt;
 
+use crate::bounds::BoundingSphere;
+use crate::camera::OPENGL_TO_WGPU_MATRIX;
+use crate::instance::InstanceRaw;
+use crate::light::{Light, LightKind};
+use crate::model::{self, AlphaMode, Vertex};
+use crate::texture;
+
+pub const SHADOW_SIZE: u32 = 2048;
+/// Widest view of a point light, see [`ShadowUniform::from_light`]
+const MAX_POINT_FOVY: cgmath::Rad<f32> = cgmath::Rad(2.0 * std::f32::consts::FRAC_PI_3);
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ShadowUniform {
+    pub light_view_proj: [[f32; 4]; 4],
+}
+
+impl Default for ShadowUniform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShadowUniform {
+    pub fn new() -> Self {
+        Self {
+            light_view_proj: Matrix4::identity().into(),
+        }
+    }
+
+    /// View of `scene` as seen from the light, with the frustum fitted
+    /// around it so everything in it can cast and receive shadows.
+    ///
+    /// Directional and spot lights look along their direction. A point light
+    /// would need six views to shadow all around it, so it only gets one,
+    /// aimed at the center of `scene`: from outside the scene that covers
+    /// all of it, from inside only a 120 degree cone towards the center.
+    pub fn from_light(light: &Light, scene: &BoundingSphere) -> Self {
+        let radius = scene.radius.max(0.01);
+        let eye = Point3::from_vec(light.position);
+        let to_center = scene.center - eye;
+        let distance = to_center.magnitude();
+        // Close enough to keep the depth precision, but not past the scene
+        let near = (distance - radius).max(distance.max(radius) * 0.001);
+        let far = distance + radius;
+
+        let (eye, direction, proj) = match light.kind {
+            LightKind::Directional => {
+                // Back off from the scene along the light so all of it is in front
+                let direction = light.direction.normalize();
+                let eye = scene.center - direction * radius * 2.0;
+                let proj = cgmath::ortho(-radius, radius, -radius, radius, radius * 0.5, radius * 3.5);
+                (eye, direction, proj)
+            }
+            LightKind::Spot { outer_angle, .. } => {
+                let fovy = cgmath::Rad((outer_angle.0 * 2.0).min(3.0));
+                (eye, light.direction.normalize(), cgmath::perspective(fovy, 1.0, near, far))
+            }
+            LightKind::Point => {
+                let direction = if distance > 1e-4 {
+                    to_center / distance
+                } else {
+                    -Vector3::unit_y()
+                };
+                let fovy = if distance > radius {
+                    // Just wide enough for the scene, with a margin for the PCF kernel
+                    cgmath::Rad((2.0 * (radius / distance).asin() * 1.05).min(MAX_POINT_FOVY.0))
+                } else {
+                    MAX_POINT_FOVY
+                };
+                (eye, direction, cgmath::perspective(fovy, 1.0, near, far))
+            }
+        };
+        // look_to breaks down when looking straight up or down the y axis
+        let up = if direction.y.abs() > 0.99 {
+            Vector3::unit_z()
+        } else {
+            Vector3::unit_y()
+        };
+        let view = Matrix4::look_to_rh(eye, direction, up);
+        Self {
+            light_view_proj: (OPENGL_TO_WGPU_MATRIX * proj * view).into(),
+        }
+    }
+}
+
+/// Renders the scene depth from the shadow casting light into a shadow map,
+/// which the main pipeline samples together with `buffer` in group 3 of
+/// `shader.wgsl`.
 pub struct ShadowPass {
-    pipeline: wgpu::ComputePipeline,
+    pub texture: texture::Texture,
+    uniform: ShadowUniform,
+    /// The [`ShadowUniform`] of the light
+    pub buffer: wgpu::Buffer,
+    pass_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    /// Alpha tests masked materials, which need a fragment stage
+    masked_pipeline: wgpu::RenderPipeline,
+}
+
+impl ShadowPass {
+    /// `scene` encloses everything that casts or receives shadows
+    pub fn new(
+        device: &wgpu::Device,
+        material_layout: &wgpu::BindGroupLayout,
+        light: Option<&Light>,
+        scene: &BoundingSphere,
+    ) -> Self {
+        let texture = texture::Texture::create_depth_texture(
+            device,
+            SHADOW_SIZE,
+            SHADOW_SIZE,
+            texture::DepthMode::Standard,
+            "shadow_texture",
+        );
+
+        let uniform = light.map(|light| ShadowUniform::from_light(light, scene)).unwrap_or_default();
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Shadow Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let pass_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Shadow Pass Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+        });
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pass_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("shadow_pass.pass_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("shadow.wgsl"));
+        let create_pipeline = |label, bind_group_layouts: &[&wgpu::BindGroupLayout], fragment| {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Shadow Pass Pipeline Layout"),
+                bind_group_layouts,
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                },
+                fragment,
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    unclipped_depth: false,
+                    conservative: false,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: texture::Texture::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::LessEqual,
+                    stencil: wgpu::StencilState::default(),
+                    // Pushes the stored depth away from the light to avoid shadow acne
+                    bias: wgpu::DepthBiasState {
+                        constant: 2,
+                        slope_scale: 2.0,
+                        clamp: 0.0,
+                    },
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: 1,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+        // Depth only, so there is no fragment stage
+        let pipeline = create_pipeline("Shadow Pass Render Pipeline", &[&pass_layout], None);
+        let masked_pipeline = create_pipeline(
+            "Shadow Pass Masked Render Pipeline",
+            &[&pass_layout, material_layout],
+            Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_masked",
+                targets: &[],
+            }),
+        );
+
+        Self {
+            texture,
+            uniform,
+            buffer,
+            pass_bind_group,
+            pipeline,
+            masked_pipeline,
+        }
+    }
+
+    pub fn light_view_proj(&self) -> Matrix4<f32> {
+        self.uniform.light_view_proj.into()
+    }
+
+    /// Without a light the shadow map keeps its last view
+    pub fn update(&mut self, queue: &wgpu::Queue, light: Option<&Light>, scene: &BoundingSphere) {
+        let Some(light) = light else {
+            return;
+        };
+        self.uniform = ShadowUniform::from_light(light, scene);
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    /// Draws each `(model, instance buffer, instances)` into the shadow map.
+    /// Blended meshes cast no shadow, masked ones only where they are opaque.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        draws: &[(&model::Model, &wgpu::Buffer, Range<u32>)],
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow Render Pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.texture.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_bind_group(0, &self.pass_bind_group, &[]);
+        for (pipeline, mode) in [(&self.pipeline, AlphaMode::Opaque), (&self.masked_pipeline, AlphaMode::Mask)] {
+            render_pass.set_pipeline(pipeline);
+            for (model, instance_buffer, instances) in draws {
+                render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+                for mesh in &model.meshes {
+                    let material = &model.materials[mesh.material_idx];
+                    if material.uniform.alpha_mode() != mode {
+                        continue;
+                    }
+                    if mode == AlphaMode::Mask {
+                        render_pass.set_bind_group(1, &material.bind_group, &[]);
+                    }
+                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    render_pass.draw_indexed(0..mesh.num_elements, 0, instances.clone());
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,270 @@
+use wgpu::util::DeviceExt;
+
+use crate::instance::{InstanceBuffer, InstanceRaw};
+use crate::model::Model;
+
+const WORKGROUP_SIZE: u32 = 64;
+/// Offset of `instance_count` in `DrawIndexedIndirect`
+const INSTANCE_COUNT_OFFSET: wgpu::BufferAddress = 4;
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CullParams {
+    instance_count: u32,
+    instance_capacity: u32,
+    mesh_count: u32,
+    _padding: u32,
+}
+
+/// Whether `device` can run the culling compute shader, which WebGL can't
+pub fn is_supported(device: &wgpu::Device) -> bool {
+    let limits = device.limits();
+    limits.max_compute_workgroups_per_dimension > 0 && limits.max_storage_buffers_per_shader_stage >= 4
+}
+
+/// Whether the buffers culling `mesh_count` meshes of `instance_capacity`
+/// instances fit the device's storage binding limits. The visible buffer
+/// holds a full copy of the instances per mesh, so it outgrows them first.
+pub fn fits_limits(device: &wgpu::Device, mesh_count: usize, instance_capacity: usize) -> bool {
+    let limits = device.limits();
+    let max_binding = (limits.max_storage_buffer_binding_size as u64).min(limits.max_buffer_size);
+    let instances_size = (instance_capacity * std::mem::size_of::<InstanceRaw>()) as u64;
+    instances_size.saturating_mul(mesh_count.max(1) as u64) <= max_binding
+}
+
+/// Tests the bounding sphere of every mesh of every instance of a model
+/// against the camera frustum on the GPU. Visible instances are compacted
+/// per mesh into the buffer of a [`CulledInstances`], whose indirect
+/// arguments then draw just those.
+pub struct CullingPass {
+    layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+}
+
+impl CullingPass {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let uniform = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let storage = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Culling Layout"),
+            entries: &[
+                uniform(0),
+                uniform(1),
+                storage(2, true),
+                storage(3, true),
+                storage(4, false),
+                storage(5, false),
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Culling Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+        let shader = device.create_shader_module(wgpu::include_wgsl!("culling.wgsl"));
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Culling Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "cs_main",
+        });
+
+        Self { layout, pipeline }
+    }
+
+    /// Records the culling of `culled`'s instances into `encoder`
+    pub fn dispatch(&self, encoder: &mut wgpu::CommandEncoder, culled: &CulledInstances) {
+        encoder.clear_buffer(&culled.count_buffer, 0, None);
+        {
+            let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Culling Pass"),
+                timestamp_writes: None,
+            });
+            compute_pass.set_pipeline(&self.pipeline);
+            compute_pass.set_bind_group(0, &culled.bind_group, &[]);
+            compute_pass.dispatch_workgroups(culled.instance_count.div_ceil(WORKGROUP_SIZE), 1, 1);
+        }
+
+        let count_size = std::mem::size_of::<u32>() as wgpu::BufferAddress;
+        for mesh in 0..culled.mesh_count {
+            encoder.copy_buffer_to_buffer(
+                &culled.count_buffer,
+                mesh as wgpu::BufferAddress * count_size,
+                &culled.indirect_buffer,
+                (mesh * std::mem::size_of::<wgpu::util::DrawIndexedIndirect>()) as wgpu::BufferAddress
+                    + INSTANCE_COUNT_OFFSET,
+                count_size,
+            );
+        }
+    }
+}
+
+/// The culling output for one model: the visible instances and one set of
+/// indirect draw arguments per mesh. Has to be recreated when the model or
+/// the instance buffer is replaced.
+pub struct CulledInstances {
+    params_buffer: wgpu::Buffer,
+    /// Compacted visible instances, one block of `instance_capacity` per mesh
+    visible_buffer: wgpu::Buffer,
+    /// Number of visible instances per mesh
+    count_buffer: wgpu::Buffer,
+    /// `DrawIndexedIndirect` per mesh, in the order of `Model::meshes`
+    pub indirect_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    instance_count: u32,
+    instance_capacity: usize,
+    mesh_count: usize,
+}
+
+impl CulledInstances {
+    pub fn new(
+        device: &wgpu::Device,
+        pass: &CullingPass,
+        camera_buffer: &wgpu::Buffer,
+        model: &Model,
+        instances: &InstanceBuffer,
+    ) -> Self {
+        // Empty storage bindings aren't allowed, a model without meshes still gets one slot
+        let mesh_count = model.meshes.len();
+        let slots = mesh_count.max(1);
+        let instance_capacity = instances.capacity();
+
+        let params_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Culling Params Buffer"),
+            size: std::mem::size_of::<CullParams>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bounds = model
+            .meshes
+            .iter()
+            .map(|mesh| {
+                let sphere = &mesh.bounding_sphere;
+                [sphere.center.x, sphere.center.y, sphere.center.z, sphere.radius]
+            })
+            .chain(std::iter::repeat([0.0; 4]))
+            .take(slots)
+            .collect::<Vec<[f32; 4]>>();
+        let bounds_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Mesh Bounds Buffer"),
+            contents: bytemuck::cast_slice(&bounds),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let visible_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Visible Instance Buffer"),
+            size: (slots * instance_capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let count_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Visible Count Buffer"),
+            size: (slots * std::mem::size_of::<u32>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Only instance_count changes, it is copied over from count_buffer
+        let args = model
+            .meshes
+            .iter()
+            .flat_map(|mesh| {
+                wgpu::util::DrawIndexedIndirect {
+                    vertex_count: mesh.num_elements,
+                    instance_count: 0,
+                    base_index: 0,
+                    vertex_offset: 0,
+                    base_instance: 0,
+                }
+                .as_bytes()
+                .to_vec()
+            })
+            .collect::<Vec<_>>();
+        let indirect_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Culling Indirect Buffer"),
+            contents: &args,
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("culling_bind_group"),
+            layout: &pass.layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: instances.buffer().as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: bounds_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: visible_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: count_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        Self {
+            params_buffer,
+            visible_buffer,
+            count_buffer,
+            indirect_buffer,
+            bind_group,
+            instance_count: 0,
+            instance_capacity,
+            mesh_count,
+        }
+    }
+
+    /// Sets the number of instances to test
+    pub fn update(&mut self, queue: &wgpu::Queue, instance_count: u32) {
+        self.instance_count = instance_count;
+        let params = CullParams {
+            instance_count,
+            instance_capacity: self.instance_capacity as u32,
+            mesh_count: self.mesh_count as u32,
+            _padding: 0,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+    }
+
+    /// The visible instances of each mesh, in the order of `Model::meshes`,
+    /// to bind as vertex buffer 1 for the matching indirect draw
+    pub fn visible_instances(&self) -> Vec<wgpu::BufferSlice<'_>> {
+        let block = (self.instance_capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress;
+        (0..self.mesh_count as wgpu::BufferAddress)
+            .map(|mesh| self.visible_buffer.slice(mesh * block..(mesh + 1) * block))
+            .collect()
+    }
+}
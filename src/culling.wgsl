@@ -0,0 +1,81 @@
+// Frustum culling of instances, see culling.rs
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+}
+
+struct CullParams {
+    instance_count: u32,
+    // Instances each mesh has room for in `visible`
+    instance_capacity: u32,
+    mesh_count: u32,
+}
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+@group(0) @binding(1)
+var<uniform> params: CullParams;
+// InstanceRaw is 25 tightly packed floats, which no WGSL struct matches
+@group(0) @binding(2)
+var<storage, read> instances: array<f32>;
+// Bounding sphere of each mesh in model space, center in xyz and radius in w
+@group(0) @binding(3)
+var<storage, read> mesh_bounds: array<vec4<f32>>;
+// One block of `instance_capacity` instances per mesh
+@group(0) @binding(4)
+var<storage, read_write> visible: array<f32>;
+@group(0) @binding(5)
+var<storage, read_write> visible_counts: array<atomic<u32>>;
+
+const INSTANCE_STRIDE: u32 = 25u;
+
+fn load_column(base: u32) -> vec4<f32> {
+    return vec4<f32>(instances[base], instances[base + 1u], instances[base + 2u], instances[base + 3u]);
+}
+
+// Signed distance of a sphere to a plane (xyz normal, w offset), scaled by
+// the length of the unnormalized plane normal
+fn outside(plane: vec4<f32>, center: vec3<f32>, radius: f32) -> bool {
+    return dot(plane.xyz, center) + plane.w < -radius * length(plane.xyz);
+}
+
+@compute @workgroup_size(64)
+fn cs_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let index = id.x;
+    if index >= params.instance_count {
+        return;
+    }
+
+    let base = index * INSTANCE_STRIDE;
+    let model = mat4x4<f32>(
+        load_column(base),
+        load_column(base + 4u),
+        load_column(base + 8u),
+        load_column(base + 12u),
+    );
+    let scale = max(length(model[0].xyz), max(length(model[1].xyz), length(model[2].xyz)));
+
+    // Frustum planes from the rows of the view projection, with wgpu's 0..1
+    // depth. The depth planes just swap roles with reverse-Z, and an infinite
+    // far plane comes out as (0, 0, 0, znear), which never culls anything.
+    let m = transpose(camera.view_proj);
+    for (var mesh = 0u; mesh < params.mesh_count; mesh += 1u) {
+        let bounds = mesh_bounds[mesh];
+        let center = (model * vec4<f32>(bounds.xyz, 1.0)).xyz;
+        let radius = bounds.w * scale;
+        if outside(m[3] + m[0], center, radius)
+            || outside(m[3] - m[0], center, radius)
+            || outside(m[3] + m[1], center, radius)
+            || outside(m[3] - m[1], center, radius)
+            || outside(m[2], center, radius)
+            || outside(m[3] - m[2], center, radius) {
+            continue;
+        }
+
+        let slot = (mesh * params.instance_capacity + atomicAdd(&visible_counts[mesh], 1u)) * INSTANCE_STRIDE;
+        for (var i = 0u; i < INSTANCE_STRIDE; i += 1u) {
+            visible[slot + i] = instances[base + i];
+        }
+    }
+}
@@ -1,75 +1,96 @@
+use serde::Deserialize;
+
+use crate::quad::Quad;
 use crate::texture;
-use wgpu::util::DeviceExt;
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-struct Vertex {
-    position: [f32; 3],
-    tex_coords: [f32; 2],
+/// Written by the main shader next to the lit color, what it writes for each
+/// view has to match `shader.wgsl`
+pub const DEBUG_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8Unorm;
+
+/// What the debug view shows instead of the lit scene
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugView {
+    #[default]
+    Off,
+    /// Distance from the camera, black at `znear` and white at `zfar`
+    LinearDepth,
+    /// Like `LinearDepth` on a log scale, which keeps detail up close
+    LogDepth,
+    /// World space normals after normal mapping, mapped to 0..1
+    Normals,
+    /// Texture coordinates in red and green, wrapped to 0..1
+    Uvs,
+    /// Base color without lighting
+    Albedo,
+    /// Lighting of a white surface, without the base color
+    Lighting,
 }
 
-#[allow(dead_code)]
-impl Vertex {
-    fn desc() -> wgpu::VertexBufferLayout<'static> {
-        use std::mem;
-        wgpu::VertexBufferLayout {
-            array_stride: mem::size_of::<Vertex>() as wgpu::BufferAddress,
-            step_mode: wgpu::VertexStepMode::Vertex,
-            attributes: &[
-                wgpu::VertexAttribute {
-                    offset: 0,
-                    shader_location: 0,
-                    format: wgpu::VertexFormat::Float32x3,
-                },
-                wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
-                    shader_location: 1,
-                    format: wgpu::VertexFormat::Float32x2,
-                },
-            ],
+impl DebugView {
+    /// The next view, to cycle through them
+    pub fn next(self) -> Self {
+        match self {
+            DebugView::Off => DebugView::LinearDepth,
+            DebugView::LinearDepth => DebugView::LogDepth,
+            DebugView::LogDepth => DebugView::Normals,
+            DebugView::Normals => DebugView::Uvs,
+            DebugView::Uvs => DebugView::Albedo,
+            DebugView::Albedo => DebugView::Lighting,
+            DebugView::Lighting => DebugView::Off,
         }
     }
-}
 
-const DEPTH_VERTICES: &[Vertex] = &[
-    Vertex {
-        position: [0.0, 0.0, 0.0],
-        tex_coords: [0.0, 1.0],
-    },
-    Vertex {
-        position: [1.0, 0.0, 0.0],
-        tex_coords: [1.0, 1.0],
-    },
-    Vertex {
-        position: [1.0, 1.0, 0.0],
-        tex_coords: [1.0, 0.0],
-    },
-    Vertex {
-        position: [0.0, 1.0, 0.0],
-        tex_coords: [0.0, 0.0],
-    },
-];
-
-const DEPTH_INDICES: &[u16] = &[0, 1, 2, 0, 2, 3];
+    /// Where nothing is drawn, far away for the depth views
+    pub fn clear_color(self) -> wgpu::Color {
+        match self {
+            DebugView::LinearDepth | DebugView::LogDepth => wgpu::Color::WHITE,
+            _ => wgpu::Color::BLACK,
+        }
+    }
+}
 
+#[derive(Copy, Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DebugSettings {
+    pub view: DebugView,
+    /// Covers the whole frame instead of its top right quarter
+    pub fullscreen: bool,
+}
 
+/// Owns the depth buffers of the main pass and draws the debug view over the
+/// frame
 pub struct DepthPass {
+    /// Single sampled and sampleable. The main pass depth without MSAA, the
+    /// SSAO prepass draws into it either way.
     pub texture: texture::Texture,
+    /// The main pass depth with MSAA
+    multisampled: Option<texture::Texture>,
+    /// Second color target of the main pass, see [`DEBUG_FORMAT`]. With MSAA
+    /// `debug_multisampled` is resolved into it.
+    debug: texture::Texture,
+    debug_multisampled: Option<wgpu::TextureView>,
+    sample_count: u32,
     layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
-    vertex_buffer: wgpu::Buffer,
-    index_buffer: wgpu::Buffer,
-    num_depth_indices: u32,
+    /// The top right quarter of the screen
+    overlay_quad: Quad,
+    fullscreen_quad: Quad,
     render_pipeline: wgpu::RenderPipeline,
 }
 
 impl DepthPass {
-    pub fn new(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> Self {
-        let texture = texture::Texture::create_depth_texture_non_comparison_sampler(
-            device,
-            config,
-            "depth_texture",
-        );
+    /// `format` is that of the frames the debug view is drawn over and
+    /// `sample_count` that of the main render pass
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> Self {
+        let (texture, multisampled) = create_textures(device, width, height, sample_count);
+        let (debug, debug_multisampled) = create_debug_textures(device, width, height, sample_count);
 
         let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Depth Pass Layout"),
@@ -93,31 +114,7 @@ impl DepthPass {
             ],
         });
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
-                },
-            ],
-            label: Some("depth_pass.bind_group"),
-        });
-
-        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Depth Pass VB"),
-            contents: bytemuck::cast_slice(DEPTH_VERTICES),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Depth Pass IB"),
-            contents: bytemuck::cast_slice(DEPTH_INDICES),
-            usage: wgpu::BufferUsages::INDEX,
-        });
+        let bind_group = create_bind_group(device, &layout, &debug);
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Depth Pass Pipeline Layout"),
@@ -126,7 +123,9 @@ impl DepthPass {
         });
 
         let shader = device.create_shader_module(wgpu::include_wgsl!("depth_pass.wgsl"));
-        
+        // The debug texture holds what should end up on screen, sRGB frames
+        // would encode it again
+        let entry_point = if format.is_srgb() { "fs_srgb" } else { "fs_main" };
 
         let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
             label: Some("Depth Pass Render Pipeline"),
@@ -134,13 +133,13 @@ impl DepthPass {
             vertex: wgpu::VertexState {
                 module: &shader,
                 entry_point: "vs_main",
-                buffers: &[Vertex::desc()],
+                buffers: &[Quad::desc()],
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
-                entry_point: "fs_main",
+                entry_point,
                 targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
+                    format,
                     blend: Some(wgpu::BlendState {
                         color: wgpu::BlendComponent::REPLACE,
                         alpha: wgpu::BlendComponent::REPLACE,
@@ -174,42 +173,50 @@ impl DepthPass {
 
         Self {
             texture,
+            multisampled,
+            debug,
+            debug_multisampled,
+            sample_count,
             layout,
             bind_group,
-            vertex_buffer,
-            index_buffer,
-            num_depth_indices: DEPTH_INDICES.len() as u32,
+            overlay_quad: Quad::new(device, [0.0, 0.0], [1.0, 1.0]),
+            fullscreen_quad: Quad::fullscreen(device),
             render_pipeline,
         }
     }
 
-    pub fn resize(&mut self, device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) {
-        self.texture = texture::Texture::create_depth_texture_non_comparison_sampler(
-            device,
-            config,
-            "depth_texture",
-        );
-        self.bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &self.layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&self.texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&self.texture.sampler),
-                },
-            ],
-            label: Some("depth_pass.bind_group"),
-        });
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.texture, self.multisampled) = create_textures(device, width, height, self.sample_count);
+        (self.debug, self.debug_multisampled) = create_debug_textures(device, width, height, self.sample_count);
+        self.bind_group = create_bind_group(device, &self.layout, &self.debug);
+    }
+
+    /// Depth attachment of the main pass
+    pub fn attachment(&self) -> &wgpu::TextureView {
+        match &self.multisampled {
+            Some(multisampled) => &multisampled.view,
+            None => &self.texture.view,
+        }
+    }
+
+    /// The debug color target of the main pass and the texture to resolve
+    /// it into, if any
+    pub fn debug_attachment(&self) -> (&wgpu::TextureView, Option<&wgpu::TextureView>) {
+        match &self.debug_multisampled {
+            Some(multisampled) => (multisampled, Some(&self.debug.view)),
+            None => (&self.debug.view, None),
+        }
     }
 
-    pub fn render(&self, view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder) {
+    /// Draws the debug view over `view`, unless it is off
+    pub fn render(&self, view: &wgpu::TextureView, encoder: &mut wgpu::CommandEncoder, settings: &DebugSettings) {
+        if settings.view == DebugView::Off {
+            return;
+        }
         let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
             label: Some("Depth Visual Render Pass"),
             color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
+                view,
                 resolve_target: None,
                 ops: wgpu::Operations {
                     load: wgpu::LoadOp::Load,
@@ -222,8 +229,90 @@ impl DepthPass {
         });
         render_pass.set_pipeline(&self.render_pipeline);
         render_pass.set_bind_group(0, &self.bind_group, &[]);
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
-        render_pass.draw_indexed(0..self.num_depth_indices, 0, 0..1);
+        if settings.fullscreen {
+            self.fullscreen_quad.draw(&mut render_pass);
+        } else {
+            self.overlay_quad.draw(&mut render_pass);
+        }
     }
 }
+
+fn create_textures(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> (texture::Texture, Option<texture::Texture>) {
+    let texture =
+        texture::Texture::create_depth_texture_non_comparison_sampler(device, width, height, 1, "depth_texture");
+    let multisampled = (sample_count > 1).then(|| {
+        texture::Texture::create_depth_texture_non_comparison_sampler(
+            device,
+            width,
+            height,
+            sample_count,
+            "multisampled_depth_texture",
+        )
+    });
+    (texture, multisampled)
+}
+
+fn create_debug_textures(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> (texture::Texture, Option<wgpu::TextureView>) {
+    let create = |sample_count, usage, label| {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format: DEBUG_FORMAT,
+            usage,
+            view_formats: &[],
+        })
+    };
+    let texture = create(
+        1,
+        wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        "debug_texture",
+    );
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        label: Some("debug_sampler"),
+        ..Default::default()
+    });
+    let multisampled = (sample_count > 1).then(|| {
+        create(sample_count, wgpu::TextureUsages::RENDER_ATTACHMENT, "multisampled_debug_texture")
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    });
+    (texture::Texture { texture, view, sampler }, multisampled)
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    texture: &texture::Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&texture.sampler),
+            },
+        ],
+        label: Some("depth_pass.bind_group"),
+    })
+}
@@ -22,14 +22,25 @@ fn vs_main(
 
 // Fragment shader
 
+// Written by the main shader, already what should be seen on screen
 @group(0) @binding(0)
-var t_shadow: texture_2d<f32>;
+var t_debug: texture_2d<f32>;
 @group(0) @binding(1)
-var s_shadow: sampler;
+var s_debug: sampler;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let depth = textureSample(t_shadow, s_shadow, in.tex_coords).x;
-    let r = min((depth - 0.32)*10.0, 1.0);
-    return vec4<f32>(vec3<f32>(r), 1.0);
+    return vec4<f32>(textureSample(t_debug, s_debug, in.tex_coords).rgb, 1.0);
+}
+
+// For sRGB frames, which encode what is written again
+@fragment
+fn fs_srgb(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = textureSample(t_debug, s_debug, in.tex_coords).rgb;
+    let linear = select(
+        pow((color + 0.055) / 1.055, vec3<f32>(2.4)),
+        color / 12.92,
+        color <= vec3<f32>(0.04045),
+    );
+    return vec4<f32>(linear, 1.0);
 }
@@ -0,0 +1,558 @@
+//! The environment around the scene.
+//!
+//! A cube map is drawn as the skybox behind the opaque geometry and lights
+//! the scene as its ambient term. For that, three maps are baked on the GPU
+//! once it is loaded, following the split sum approximation:
+//!
+//! - the irradiance map, the cosine weighted environment for diffuse light
+//! - the prefiltered map, the environment blurred by GGX lobes of growing
+//!   roughness down its mip levels for specular reflections
+//! - the BRDF lookup table, the scale and bias of the Fresnel term per view
+//!   angle and roughness
+//!
+//! The main shader samples them through group 3, see
+//! [`Renderer`](crate::Renderer).
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use cgmath::{InnerSpace, Matrix3, Matrix4, SquareMatrix};
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+
+use crate::camera::{Camera, Projection, OPENGL_TO_WGPU_MATRIX};
+use crate::depth_pass::DEBUG_FORMAT;
+use crate::hdr::HDR_FORMAT;
+use crate::quad::Quad;
+use crate::texture::{self, DepthMode, Texture, CUBE_FORMAT};
+
+const IRRADIANCE_SIZE: u32 = 32;
+const PREFILTERED_SIZE: u32 = 128;
+/// Roughness 0 in the first and 1 in the last
+const PREFILTERED_MIPS: u32 = 5;
+const BRDF_SIZE: u32 = 128;
+/// Of the generated sky
+const GRADIENT_SIZE: u32 = 64;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnvironmentSettings {
+    /// Six images in the order +x, -x, +y, -y, +z, -z
+    pub faces: Option<Vec<PathBuf>>,
+    /// One latitude-longitude image, like an `.hdr` file. Without it or
+    /// `faces` a simple sky gradient is used.
+    pub equirectangular: Option<PathBuf>,
+    /// Face size the equirectangular image is resampled to
+    pub size: u32,
+    /// Scales the skybox and the light from the environment
+    pub intensity: f32,
+    /// Draws the environment behind the scene, the clear color shows
+    /// otherwise but the environment still lights the scene
+    pub skybox: bool,
+}
+
+impl Default for EnvironmentSettings {
+    fn default() -> Self {
+        Self {
+            faces: None,
+            equirectangular: None,
+            size: 512,
+            intensity: 1.0,
+            skybox: true,
+        }
+    }
+}
+
+impl EnvironmentSettings {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.faces.is_some() && self.equirectangular.is_some() {
+            bail!("set either faces or equirectangular, not both");
+        }
+        if let Some(faces) = &self.faces {
+            if faces.len() != 6 {
+                bail!("faces needs 6 images, got {}", faces.len());
+            }
+        }
+        for path in self.faces.iter().flatten().chain(&self.equirectangular) {
+            if !path.is_file() {
+                bail!("image {:?} not found", path);
+            }
+        }
+        if !self.intensity.is_finite() || self.intensity < 0.0 {
+            bail!("intensity must be a finite number of at least 0, got {}", self.intensity);
+        }
+        if self.size == 0 {
+            bail!("size must be positive");
+        }
+        Ok(())
+    }
+
+    /// Reads the images and uploads them as a cube map
+    fn load(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> anyhow::Result<Texture> {
+        let open = |path: &PathBuf| open_image(path).with_context(|| format!("Failed to load {:?}", path));
+        if let Some(faces) = &self.faces {
+            let faces = faces.iter().map(open).collect::<anyhow::Result<Vec<_>>>()?;
+            Texture::create_cube_from_faces(device, queue, &faces, "environment_texture")
+        } else if let Some(path) = &self.equirectangular {
+            Texture::create_cube_from_equirectangular(device, queue, &open(path)?, self.size, "environment_texture")
+        } else {
+            Ok(gradient_sky(device, queue))
+        }
+    }
+}
+
+/// Like [`image::open`], except that `.hdr` files keep their range instead
+/// of being converted to 8 bits
+fn open_image(path: &Path) -> anyhow::Result<image::DynamicImage> {
+    if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("hdr")) {
+        return Ok(image::open(path)?);
+    }
+    let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let decoder = image::codecs::hdr::HdrDecoder::new(reader)?;
+    let metadata = decoder.metadata();
+    let pixels = decoder.read_image_hdr()?.iter().flat_map(|p| p.0).collect();
+    let img = image::Rgb32FImage::from_raw(metadata.width, metadata.height, pixels)
+        .context("HDR image is smaller than its header says")?;
+    Ok(img.into())
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct EnvironmentUniform {
+    inv_view_proj: [[f32; 4]; 4],
+    intensity: f32,
+    max_lod: f32,
+    far_depth: f32,
+    _padding: f32,
+}
+
+/// Which face or mip level a bake pass draws
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct BakeUniform {
+    face: u32,
+    roughness: f32,
+    _padding: [u32; 2],
+}
+
+pub struct Environment {
+    pub intensity: f32,
+    pub skybox: bool,
+    uniform: EnvironmentUniform,
+    buffer: wgpu::Buffer,
+    // Kept alive for their views
+    _cube: Texture,
+    _irradiance: wgpu::Texture,
+    _prefiltered: wgpu::Texture,
+    _brdf: wgpu::Texture,
+    pub irradiance_view: wgpu::TextureView,
+    pub prefiltered_view: wgpu::TextureView,
+    pub brdf_view: wgpu::TextureView,
+    /// Linear and clamped, for all the maps
+    pub sampler: wgpu::Sampler,
+    quad: Quad,
+    sky_bind_group: wgpu::BindGroup,
+    sky_pipeline: wgpu::RenderPipeline,
+}
+
+impl Environment {
+    /// The skybox is drawn in the main pass, with its `sample_count` and
+    /// `depth_mode`
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        settings: &EnvironmentSettings,
+        depth_mode: DepthMode,
+        sample_count: u32,
+    ) -> anyhow::Result<Self> {
+        let cube = settings.load(device, queue)?;
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("environment_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let quad = Quad::fullscreen(device);
+
+        let cube_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Environment Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let uniform = EnvironmentUniform {
+            inv_view_proj: Matrix4::identity().into(),
+            intensity: settings.intensity,
+            max_lod: (PREFILTERED_MIPS - 1) as f32,
+            far_depth: depth_mode.clear_value(),
+            _padding: 0.0,
+        };
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let sky_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &cube_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&cube.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("environment.sky_bind_group"),
+        });
+
+        let (irradiance, prefiltered, brdf) = bake(device, queue, &cube_layout, &sky_bind_group, &quad);
+        let cube_view = |texture: &wgpu::Texture| {
+            texture.create_view(&wgpu::TextureViewDescriptor {
+                dimension: Some(wgpu::TextureViewDimension::Cube),
+                ..Default::default()
+            })
+        };
+
+        let sky_pipeline = {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Skybox Pipeline Layout"),
+                bind_group_layouts: &[&cube_layout],
+                push_constant_ranges: &[],
+            });
+            let shader = device.create_shader_module(wgpu::include_wgsl!("skybox.wgsl"));
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some("Skybox Pipeline"),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[Quad::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[
+                        Some(wgpu::ColorTargetState {
+                            format: HDR_FORMAT,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::ALL,
+                        }),
+                        Some(wgpu::ColorTargetState {
+                            format: DEBUG_FORMAT,
+                            blend: None,
+                            write_mask: wgpu::ColorWrites::empty(),
+                        }),
+                    ],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: Texture::DEPTH_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare: depth_mode.compare_equal(),
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: sample_count,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+
+        Ok(Self {
+            intensity: settings.intensity,
+            skybox: settings.skybox,
+            uniform,
+            buffer,
+            irradiance_view: cube_view(&irradiance),
+            prefiltered_view: cube_view(&prefiltered),
+            brdf_view: brdf.create_view(&wgpu::TextureViewDescriptor::default()),
+            _cube: cube,
+            _irradiance: irradiance,
+            _prefiltered: prefiltered,
+            _brdf: brdf,
+            sampler,
+            quad,
+            sky_bind_group,
+            sky_pipeline,
+        })
+    }
+
+    /// The uniform shared by the skybox and the main shader
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &Camera, projection: &Projection) {
+        // Only the rotation of the camera matters, and the sky keeps its
+        // perspective when the projection is orthographic
+        let view = camera.calc_matrix();
+        let rotation = Matrix4::from(Matrix3::from_cols(view.x.truncate(), view.y.truncate(), view.z.truncate()));
+        let proj = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(projection.fovy(), projection.aspect(), 0.1, 10.0);
+        self.uniform.inv_view_proj = (proj * rotation).invert().unwrap_or_else(Matrix4::identity).into();
+        self.uniform.intensity = self.intensity;
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    /// Draws the skybox where the depth buffer is still cleared, call after
+    /// the opaque geometry
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        if !self.skybox {
+            return;
+        }
+        render_pass.set_pipeline(&self.sky_pipeline);
+        render_pass.set_bind_group(0, &self.sky_bind_group, &[]);
+        self.quad.draw(render_pass);
+    }
+}
+
+/// Renders the irradiance map, the prefiltered map and the BRDF lookup table
+/// of the cube in `cube_bind_group`
+fn bake(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    cube_layout: &wgpu::BindGroupLayout,
+    cube_bind_group: &wgpu::BindGroup,
+    quad: &Quad,
+) -> (wgpu::Texture, wgpu::Texture, wgpu::Texture) {
+    let create_texture = |label, size, mip_level_count, layers, format| {
+        device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: layers,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        })
+    };
+    let irradiance = create_texture("irradiance_texture", IRRADIANCE_SIZE, 1, 6, CUBE_FORMAT);
+    let prefiltered = create_texture("prefiltered_texture", PREFILTERED_SIZE, PREFILTERED_MIPS, 6, CUBE_FORMAT);
+    let brdf = create_texture("brdf_texture", BRDF_SIZE, 1, 1, wgpu::TextureFormat::Rgba16Float);
+
+    // Every face and mip level gets its own slot of the uniform buffer
+    let stride = (device.limits().min_uniform_buffer_offset_alignment as usize).max(std::mem::size_of::<BakeUniform>());
+    let mut passes = Vec::new();
+    for face in 0..6 {
+        passes.push((&irradiance, "fs_irradiance", face, 0, 0.0));
+    }
+    for mip in 0..PREFILTERED_MIPS {
+        let roughness = mip as f32 / (PREFILTERED_MIPS - 1) as f32;
+        for face in 0..6 {
+            passes.push((&prefiltered, "fs_prefilter", face, mip, roughness));
+        }
+    }
+    let mut data = vec![0u8; stride * passes.len()];
+    for (i, &(_, _, face, _, roughness)) in passes.iter().enumerate() {
+        let uniform = BakeUniform {
+            face,
+            roughness,
+            _padding: [0; 2],
+        };
+        data[i * stride..][..std::mem::size_of::<BakeUniform>()].copy_from_slice(bytemuck::bytes_of(&uniform));
+    }
+    let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Bake Uniform Buffer"),
+        contents: &data,
+        usage: wgpu::BufferUsages::UNIFORM,
+    });
+    let bake_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Bake Layout"),
+        entries: &[wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: true,
+                min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<BakeUniform>() as u64),
+            },
+            count: None,
+        }],
+    });
+    let bake_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &bake_layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &buffer,
+                offset: 0,
+                size: wgpu::BufferSize::new(std::mem::size_of::<BakeUniform>() as u64),
+            }),
+        }],
+        label: Some("environment.bake_bind_group"),
+    });
+
+    let shader = device.create_shader_module(wgpu::include_wgsl!("ibl.wgsl"));
+    let create_pipeline = |entry_point, bind_group_layouts: &[&wgpu::BindGroupLayout]| {
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Bake Pipeline Layout"),
+            bind_group_layouts,
+            push_constant_ranges: &[],
+        });
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(entry_point),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[Quad::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format: CUBE_FORMAT,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    };
+    let irradiance_pipeline = create_pipeline("fs_irradiance", &[cube_layout, &bake_layout]);
+    let prefilter_pipeline = create_pipeline("fs_prefilter", &[cube_layout, &bake_layout]);
+    let brdf_pipeline = create_pipeline("fs_brdf", &[]);
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Bake Encoder"),
+    });
+    let draw = |encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView, pipeline, offset: Option<u32>| {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Bake Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        if let Some(offset) = offset {
+            render_pass.set_bind_group(0, cube_bind_group, &[]);
+            render_pass.set_bind_group(1, &bake_bind_group, &[offset]);
+        }
+        quad.draw(&mut render_pass);
+    };
+    for (i, &(texture, entry_point, face, mip, _)) in passes.iter().enumerate() {
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            base_mip_level: mip,
+            mip_level_count: Some(1),
+            base_array_layer: face,
+            array_layer_count: Some(1),
+            ..Default::default()
+        });
+        let pipeline = if entry_point == "fs_irradiance" { &irradiance_pipeline } else { &prefilter_pipeline };
+        draw(&mut encoder, &view, pipeline, Some((i * stride) as u32));
+    }
+    let brdf_view = brdf.create_view(&wgpu::TextureViewDescriptor::default());
+    draw(&mut encoder, &brdf_view, &brdf_pipeline, None);
+    queue.submit(std::iter::once(encoder.finish()));
+
+    (irradiance, prefiltered, brdf)
+}
+
+/// Blue above the horizon fading to white at it, and a dark ground below
+fn gradient_sky(device: &wgpu::Device, queue: &wgpu::Queue) -> Texture {
+    let zenith = cgmath::Vector3::new(0.15, 0.3, 0.65);
+    let horizon = cgmath::Vector3::new(0.7, 0.75, 0.8);
+    let ground = cgmath::Vector3::new(0.2, 0.18, 0.16);
+    let faces = (0..6)
+        .map(|face| {
+            (0..GRADIENT_SIZE * GRADIENT_SIZE)
+                .map(|i| {
+                    let u = ((i % GRADIENT_SIZE) as f32 + 0.5) / GRADIENT_SIZE as f32;
+                    let v = ((i / GRADIENT_SIZE) as f32 + 0.5) / GRADIENT_SIZE as f32;
+                    let y = texture::cube_face_direction(face, u, v).normalize().y;
+                    let color = if y >= 0.0 {
+                        horizon + (zenith - horizon) * y.sqrt()
+                    } else {
+                        horizon + (ground - horizon) * (-y * 8.0).min(1.0)
+                    };
+                    [color.x, color.y, color.z, 1.0]
+                })
+                .collect()
+        })
+        .collect::<Vec<_>>();
+    Texture::create_cube(device, queue, GRADIENT_SIZE, &faces, "environment_texture")
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use crate::texture::cube_face_direction;
+
+    #[test]
+    fn face_centers_point_along_the_axes() {
+        let axes = [
+            Vector3::unit_x(),
+            -Vector3::unit_x(),
+            Vector3::unit_y(),
+            -Vector3::unit_y(),
+            Vector3::unit_z(),
+            -Vector3::unit_z(),
+        ];
+        for (face, axis) in axes.into_iter().enumerate() {
+            assert_eq!(cube_face_direction(face, 0.5, 0.5), axis);
+        }
+    }
+
+    #[test]
+    fn faces_share_their_edges() {
+        // The top edge of +x meets the +x edge of +y
+        assert_eq!(cube_face_direction(0, 0.5, 0.0), Vector3::new(1.0, 1.0, 0.0));
+        assert_eq!(cube_face_direction(2, 1.0, 0.5), Vector3::new(1.0, 1.0, 0.0));
+    }
+}
@@ -0,0 +1,50 @@
+//! High dynamic range rendering.
+//!
+//! The scene is drawn into an [`HDR_FORMAT`] texture so lighting can go past
+//! 1.0. The tonemap effect of the post-processing chain, see hdr.wgsl, then
+//! maps it into the displayable range.
+
+use serde::Deserialize;
+
+pub const HDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Curve mapping HDR colors into the displayable 0..1 range
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tonemapper {
+    /// Narkowicz's fit of the ACES filmic curve, slightly saturated and contrasty
+    #[default]
+    Aces,
+    /// `c / (1 + c)`, keeps colors but looks flat
+    Reinhard,
+    /// John Hable's Uncharted 2 curve
+    Filmic,
+}
+
+impl Tonemapper {
+    /// The next operator, to cycle through them
+    pub fn next(self) -> Self {
+        match self {
+            Tonemapper::Aces => Tonemapper::Reinhard,
+            Tonemapper::Reinhard => Tonemapper::Filmic,
+            Tonemapper::Filmic => Tonemapper::Aces,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tonemapping {
+    pub operator: Tonemapper,
+    /// In stops, every +1 doubles the brightness before tonemapping
+    pub exposure: f32,
+}
+
+impl Default for Tonemapping {
+    fn default() -> Self {
+        Self {
+            operator: Tonemapper::default(),
+            exposure: 0.0,
+        }
+    }
+}
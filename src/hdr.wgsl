@@ -0,0 +1,82 @@
+// Tonemapping effect of the post-processing chain, see hdr.rs and post.rs
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = in.tex_coords;
+    out.clip_position = vec4<f32>(in.position, 1.0);
+    return out;
+}
+
+struct Tonemap {
+    // Linear factor, 2^exposure
+    exposure: f32,
+    // 0 = ACES, 1 = Reinhard, 2 = filmic
+    curve: f32,
+}
+
+@group(0) @binding(0)
+var t_hdr: texture_2d<f32>;
+@group(0) @binding(1)
+var s_hdr: sampler;
+@group(1) @binding(0)
+var<uniform> tonemap: Tonemap;
+
+// https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+fn aces(x: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((x * (a * x + b)) / (x * (c * x + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+fn reinhard(x: vec3<f32>) -> vec3<f32> {
+    return x / (1.0 + x);
+}
+
+fn hable(x: vec3<f32>) -> vec3<f32> {
+    let a = 0.15;
+    let b = 0.50;
+    let c = 0.10;
+    let d = 0.20;
+    let e = 0.02;
+    let f = 0.30;
+    return ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f;
+}
+
+fn filmic(x: vec3<f32>) -> vec3<f32> {
+    // Hable's curve needs its input doubled and only reaches 1.0 at 11.2
+    let white = 11.2;
+    return hable(2.0 * x) / hable(vec3<f32>(white));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let hdr = textureSample(t_hdr, s_hdr, in.tex_coords);
+    let color = max(hdr.rgb * tonemap.exposure, vec3<f32>(0.0));
+    var mapped: vec3<f32>;
+    switch u32(tonemap.curve) {
+        case 1u: {
+            mapped = reinhard(color);
+        }
+        case 2u: {
+            mapped = filmic(color);
+        }
+        default: {
+            mapped = aces(color);
+        }
+    }
+    return vec4<f32>(mapped, hdr.a);
+}
@@ -0,0 +1,163 @@
+// Bakes the image based lighting maps of environment.rs. Each pass draws one
+// face, or one mip level of a face, of a cube map.
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = in.tex_coords;
+    out.clip_position = vec4<f32>(in.position, 1.0);
+    return out;
+}
+
+const PI: f32 = 3.14159265359;
+const SAMPLE_COUNT: u32 = 64u;
+
+struct Bake {
+    face: u32,
+    roughness: f32,
+}
+
+@group(0) @binding(0)
+var t_environment: texture_cube<f32>;
+@group(0) @binding(1)
+var s_environment: sampler;
+@group(1) @binding(0)
+var<uniform> bake: Bake;
+
+// Same as cube_face_direction in texture.rs
+fn face_direction(face: u32, tex_coords: vec2<f32>) -> vec3<f32> {
+    let uv = tex_coords * 2.0 - 1.0;
+    switch face {
+        case 0u: {
+            return vec3<f32>(1.0, -uv.y, -uv.x);
+        }
+        case 1u: {
+            return vec3<f32>(-1.0, -uv.y, uv.x);
+        }
+        case 2u: {
+            return vec3<f32>(uv.x, 1.0, uv.y);
+        }
+        case 3u: {
+            return vec3<f32>(uv.x, -1.0, -uv.y);
+        }
+        case 4u: {
+            return vec3<f32>(uv.x, -uv.y, 1.0);
+        }
+        default: {
+            return vec3<f32>(-uv.x, -uv.y, -1.0);
+        }
+    }
+}
+
+// Turns a direction around +z into one around `normal`
+fn tangent_to_world(v: vec3<f32>, normal: vec3<f32>) -> vec3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(normal.y) > 0.999) {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let tangent = normalize(cross(up, normal));
+    let bitangent = cross(normal, tangent);
+    return tangent * v.x + bitangent * v.y + normal * v.z;
+}
+
+// Cosine weighted integral of the environment over the hemisphere
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = normalize(face_direction(bake.face, in.tex_coords));
+    let phi_steps = 64u;
+    let theta_steps = 16u;
+    var irradiance = vec3<f32>(0.0);
+    for (var i = 0u; i < phi_steps; i++) {
+        let phi = (f32(i) + 0.5) / f32(phi_steps) * 2.0 * PI;
+        for (var j = 0u; j < theta_steps; j++) {
+            let theta = (f32(j) + 0.5) / f32(theta_steps) * 0.5 * PI;
+            let v = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let color = textureSampleLevel(t_environment, s_environment, tangent_to_world(v, normal), 0.0).rgb;
+            irradiance += color * cos(theta) * sin(theta);
+        }
+    }
+    return vec4<f32>(PI * irradiance / f32(phi_steps * theta_steps), 1.0);
+}
+
+fn radical_inverse(bits_in: u32) -> f32 {
+    var bits = bits_in;
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 0x55555555u) << 1u) | ((bits & 0xAAAAAAAAu) >> 1u);
+    bits = ((bits & 0x33333333u) << 2u) | ((bits & 0xCCCCCCCCu) >> 2u);
+    bits = ((bits & 0x0F0F0F0Fu) << 4u) | ((bits & 0xF0F0F0F0u) >> 4u);
+    bits = ((bits & 0x00FF00FFu) << 8u) | ((bits & 0xFF00FF00u) >> 8u);
+    return f32(bits) * 2.3283064365386963e-10;
+}
+
+fn hammersley(i: u32, n: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(n), radical_inverse(i));
+}
+
+// Half vector around +z distributed like GGX with `roughness`
+fn importance_sample_ggx(xi: vec2<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+// The environment blurred by a GGX lobe, assuming the view along the normal
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = normalize(face_direction(bake.face, in.tex_coords));
+    var color = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < SAMPLE_COUNT; i++) {
+        let half_dir = tangent_to_world(importance_sample_ggx(hammersley(i, SAMPLE_COUNT), bake.roughness), normal);
+        let light_dir = normalize(2.0 * dot(normal, half_dir) * half_dir - normal);
+        let n_dot_l = dot(normal, light_dir);
+        if (n_dot_l > 0.0) {
+            color += textureSampleLevel(t_environment, s_environment, light_dir, 0.0).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    return vec4<f32>(color / max(weight, 1e-4), 1.0);
+}
+
+fn geometry_schlick_ggx(n_dot_v: f32, roughness: f32) -> f32 {
+    // k is remapped differently for image based lighting
+    let k = roughness * roughness / 2.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k);
+}
+
+// Scale and bias of f0 in the split sum approximation, x = n_dot_v and
+// y = roughness
+@fragment
+fn fs_brdf(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n_dot_v = max(in.tex_coords.x, 1e-3);
+    let roughness = in.tex_coords.y;
+    let view_dir = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < SAMPLE_COUNT; i++) {
+        let half_dir = importance_sample_ggx(hammersley(i, SAMPLE_COUNT), roughness);
+        let light_dir = normalize(2.0 * dot(view_dir, half_dir) * half_dir - view_dir);
+        let n_dot_l = max(light_dir.z, 0.0);
+        let n_dot_h = max(half_dir.z, 0.0);
+        let v_dot_h = max(dot(view_dir, half_dir), 0.0);
+        if (n_dot_l > 0.0) {
+            let g = geometry_schlick_ggx(n_dot_v, roughness) * geometry_schlick_ggx(n_dot_l, roughness);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fc) * g_vis;
+            bias += fc * g_vis;
+        }
+    }
+    return vec4<f32>(scale / f32(SAMPLE_COUNT), bias / f32(SAMPLE_COUNT), 0.0, 1.0);
+}
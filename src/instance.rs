@@ -1,41 +1,75 @@
-use wgpu::util::DeviceExt;
+use std::ops::Range;
 
+use cgmath::{Matrix, SquareMatrix};
 
+
+/// Position, rotation and scale of one copy of a model, relative to its
+/// parent node when used in a [`crate::scene_graph::SceneGraph`]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
     pub scaling: cgmath::Vector3<f32>,
 }
 
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            rotation: cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0),
+            scaling: cgmath::Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
 
 impl Instance {
-    pub fn to_raw(&self) -> InstanceRaw {
-        InstanceRaw {
-            model: (
-                cgmath::Matrix4::from_translation(self.position)
-                    * cgmath::Matrix4::from(self.rotation)
-                    * cgmath::Matrix4::from_nonuniform_scale(
-                        self.scaling.x,
-                        self.scaling.y,
-                        self.scaling.z
-                    )
-            ).into(),
-            normal: cgmath::Matrix3::from(self.rotation).into(),
+    /// Scales, then rotates, then translates
+    pub fn to_matrix(&self) -> cgmath::Matrix4<f32> {
+        cgmath::Matrix4::from_translation(self.position)
+            * cgmath::Matrix4::from(self.rotation)
+            * cgmath::Matrix4::from_nonuniform_scale(
+                self.scaling.x,
+                self.scaling.y,
+                self.scaling.z
+            )
+    }
 
-        }
+    pub fn normal_matrix(&self) -> cgmath::Matrix3<f32> {
+        normal_matrix(self.to_matrix())
+    }
+
+    pub fn to_raw(&self) -> InstanceRaw {
+        InstanceRaw::from_matrix(self.to_matrix())
     }
 }
 
 
 
+/// Inverse-transpose of the upper 3x3 of `model`, which keeps normals
+/// perpendicular to the surface under non-uniform scaling. The result isn't
+/// normalized, transformed normals have to be. Falls back to the upper 3x3
+/// when it can't be inverted, i.e. when a scale is zero.
+pub fn normal_matrix(model: cgmath::Matrix4<f32>) -> cgmath::Matrix3<f32> {
+    let linear = cgmath::Matrix3::from_cols(model.x.truncate(), model.y.truncate(), model.z.truncate());
+    linear.invert().map_or(linear, |inverse| inverse.transpose())
+}
+
 #[repr(C)]
-#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     pub model: [[f32; 4]; 4],
     normal: [[f32; 3]; 3],
 }
 
 impl InstanceRaw {
+    pub fn from_matrix(model: cgmath::Matrix4<f32>) -> Self {
+        Self {
+            model: model.into(),
+            normal: normal_matrix(model).into(),
+        }
+    }
+
     pub fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
         wgpu::VertexBufferLayout {
@@ -91,13 +125,391 @@ impl InstanceRaw {
 }
 
 
-pub fn create_instances_buffer(device: &wgpu::Device, instances_data: &Vec<InstanceRaw>) -> wgpu::Buffer {
-    let instances_buffer = device.create_buffer_init(
-        &wgpu::util::BufferInitDescriptor {
+/// A change to the instances of one model, see
+/// [`crate::scene_graph::SceneGraph::take_instance_changes`]
+#[derive(Copy, Clone, Debug)]
+pub enum InstanceChange {
+    Push(InstanceRaw),
+    Update(usize, InstanceRaw),
+    SwapRemove(usize),
+}
+
+/// The CPU copy of an [`InstanceBuffer`], which remembers the index ranges
+/// changed since they were last taken for uploading.
+#[derive(Default)]
+pub struct InstanceList {
+    instances: Vec<InstanceRaw>,
+    /// Instance index ranges written since the last `take_dirty`
+    dirty: Vec<Range<usize>>,
+}
+
+impl InstanceList {
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    pub fn instances(&self) -> &[InstanceRaw] {
+        &self.instances
+    }
+
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        match self.dirty.last_mut() {
+            // Consecutive writes, e.g. pushing many instances, share one range
+            Some(last) if last.start <= range.end && range.start <= last.end => {
+                last.start = last.start.min(range.start);
+                last.end = last.end.max(range.end);
+            }
+            _ => self.dirty.push(range),
+        }
+    }
+
+    /// Appends an instance and returns its index
+    pub fn push(&mut self, instance: InstanceRaw) -> usize {
+        let index = self.instances.len();
+        self.instances.push(instance);
+        self.mark_dirty(index..index + 1);
+        index
+    }
+
+    /// Replaces the instance at `index`
+    pub fn update(&mut self, index: usize, instance: InstanceRaw) {
+        self.instances[index] = instance;
+        self.mark_dirty(index..index + 1);
+    }
+
+    /// Removes the instance at `index` by moving the last one into its place,
+    /// so only a single instance has to be rewritten
+    pub fn swap_remove(&mut self, index: usize) -> InstanceRaw {
+        let removed = self.instances.swap_remove(index);
+        if index < self.instances.len() {
+            self.mark_dirty(index..index + 1);
+        }
+        removed
+    }
+
+    pub fn apply(&mut self, change: InstanceChange) {
+        match change {
+            InstanceChange::Push(instance) => {
+                self.push(instance);
+            }
+            InstanceChange::Update(index, instance) => self.update(index, instance),
+            InstanceChange::SwapRemove(index) => {
+                self.swap_remove(index);
+            }
+        }
+    }
+
+    /// The ranges changed since the last call, sorted and merged, clipped
+    /// to the instances that are left
+    pub fn take_dirty(&mut self) -> Vec<Range<usize>> {
+        let len = self.instances.len();
+        merge_ranges(std::mem::take(&mut self.dirty))
+            .into_iter()
+            .map(|range| range.start.min(len)..range.end.min(len))
+            .filter(|range| !range.is_empty())
+            .collect()
+    }
+}
+
+/// Instance data on the GPU together with a CPU copy, for instances that
+/// come and go or move every frame.
+///
+/// Changes only touch the [`InstanceList`] and mark the affected range
+/// dirty, [`InstanceBuffer::flush`] then writes the dirty ranges with
+/// `queue.write_buffer`, or reallocates the buffer when it has to grow.
+pub struct InstanceBuffer {
+    buffer: wgpu::Buffer,
+    /// Number of instances the buffer has room for
+    capacity: usize,
+    list: InstanceList,
+    /// Instances on the GPU as of the last flush
+    flushed_len: usize,
+}
+
+impl InstanceBuffer {
+    pub fn new(device: &wgpu::Device, capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        Self {
+            buffer: Self::create_buffer(device, capacity),
+            capacity,
+            list: InstanceList::default(),
+            flushed_len: 0,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(instances_data),
-            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            // Storage for the frustum culling pass to read from
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn instances(&self) -> &[InstanceRaw] {
+        self.list.instances()
+    }
+
+    /// The CPU copy, changes to it are uploaded by the next flush
+    pub fn list_mut(&mut self) -> &mut InstanceList {
+        &mut self.list
+    }
+
+    /// Instances to pass to `draw_model_instanced`, only covers what has
+    /// been flushed so it never reaches past the data on the GPU
+    pub fn draw_range(&self) -> Range<u32> {
+        0..self.flushed_len as u32
+    }
+
+    /// Uploads everything changed since the last flush, growing the buffer
+    /// to at least twice its size when the instances don't fit anymore.
+    /// Returns true when the buffer was replaced by a bigger one, so bind
+    /// groups using it need to be recreated.
+    pub fn flush(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        let len = self.list.len();
+        let grow = len > self.capacity;
+        let mut ranges = self.list.take_dirty();
+        if grow {
+            self.capacity = len.max(self.capacity * 2);
+            self.buffer = Self::create_buffer(device, self.capacity);
+            ranges.clear();
+            ranges.push(0..len);
+        }
+
+        for range in ranges {
+            let offset = (range.start * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress;
+            queue.write_buffer(&self.buffer, offset, bytemuck::cast_slice(&self.list.instances()[range]));
+        }
+        self.flushed_len = len;
+        grow
+    }
+}
+
+/// Sorts `ranges` and joins the ones that overlap or touch
+fn merge_ranges(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
         }
-    );
-    instances_buffer
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::{Deg, InnerSpace, Rotation3, Vector3};
+
+    use super::*;
+
+    fn instance(scaling: Vector3<f32>) -> Instance {
+        Instance {
+            position: Vector3::new(3.0, -1.0, 2.0),
+            rotation: cgmath::Quaternion::from_axis_angle(Vector3::new(1.0, 2.0, 0.5).normalize(), Deg(37.0)),
+            scaling,
+        }
+    }
+
+    /// Normals spread over the sphere, none of them axis aligned
+    fn normals() -> Vec<Vector3<f32>> {
+        (0..32)
+            .map(|i| {
+                let angle = i as f32 * 0.7;
+                Vector3::new(angle.cos(), (i as f32 * 0.31).sin() + 0.2, angle.sin()).normalize()
+            })
+            .collect()
+    }
+
+    /// Transforms a normal the long way round: two vectors in the surface go
+    /// through the model matrix and their cross product is the new normal
+    fn reference_normal(model: cgmath::Matrix4<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+        let helper = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+        let tangent = normal.cross(helper);
+        let bitangent = normal.cross(tangent);
+        let transform = |v: Vector3<f32>| (model * v.extend(0.0)).truncate();
+        let world = transform(tangent).cross(transform(bitangent)).normalize();
+        // Keep the side the surface faces, mirroring scales flip the cross product
+        let original_side = tangent.cross(bitangent).dot(normal);
+        if original_side * model.determinant() < 0.0 {
+            -world
+        } else {
+            world
+        }
+    }
+
+    fn shaded_normal(raw: &InstanceRaw, normal: Vector3<f32>) -> Vector3<f32> {
+        (cgmath::Matrix3::from(raw.normal) * normal).normalize()
+    }
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-4, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn non_uniform_scale_matches_reference_normals() {
+        for scaling in [
+            Vector3::new(2.0, 0.5, 3.0),
+            Vector3::new(0.1, 1.0, 1.0),
+            Vector3::new(-1.0, 2.0, 1.0),
+        ] {
+            let instance = instance(scaling);
+            let raw = instance.to_raw();
+            for normal in normals() {
+                assert_close(shaded_normal(&raw, normal), reference_normal(instance.to_matrix(), normal));
+            }
+        }
+    }
+
+    #[test]
+    fn lambert_term_matches_reference() {
+        let instance = instance(Vector3::new(4.0, 1.0, 0.25));
+        let raw = instance.to_raw();
+        let light_dir = Vector3::new(-0.3, 1.0, 0.6).normalize();
+        for normal in normals() {
+            let lit = shaded_normal(&raw, normal).dot(light_dir).max(0.0);
+            let expected = reference_normal(instance.to_matrix(), normal).dot(light_dir).max(0.0);
+            assert!((lit - expected).abs() < 1e-4, "{} != {}", lit, expected);
+        }
+    }
+
+    #[test]
+    fn uniform_scale_only_rotates_normals() {
+        let instance = instance(Vector3::new(2.5, 2.5, 2.5));
+        let raw = instance.to_raw();
+        for normal in normals() {
+            assert_close(shaded_normal(&raw, normal), instance.rotation * normal);
+        }
+    }
+
+    #[test]
+    fn raw_model_matches_the_matrix() {
+        let instance = instance(Vector3::new(1.0, 2.0, 3.0));
+        let raw = instance.to_raw();
+        let model: [[f32; 4]; 4] = instance.to_matrix().into();
+        assert_eq!(raw.model, model);
+        assert_eq!(std::mem::size_of::<InstanceRaw>(), 25 * 4);
+    }
+
+    #[test]
+    fn zero_scale_does_not_produce_nan() {
+        let raw = instance(Vector3::new(1.0, 0.0, 1.0)).to_raw();
+        assert!(raw.normal.iter().flatten().all(|v| v.is_finite()));
+    }
+
+    #[test]
+    fn dirty_ranges_are_merged() {
+        assert_eq!(merge_ranges(vec![5..6, 0..2, 1..3, 3..4, 8..10, 9..9]), vec![0..4, 5..6, 8..10]);
+        assert!(merge_ranges(Vec::new()).is_empty());
+    }
+
+    fn raw(x: f32) -> InstanceRaw {
+        Instance {
+            position: Vector3::new(x, 0.0, 0.0),
+            ..Instance::default()
+        }
+        .to_raw()
+    }
+
+    /// The x position of every instance, to tell them apart
+    fn positions(list: &InstanceList) -> Vec<f32> {
+        list.instances().iter().map(|raw| raw.model[3][0]).collect()
+    }
+
+    fn list_of(count: usize) -> InstanceList {
+        let mut list = InstanceList::default();
+        for i in 0..count {
+            list.push(raw(i as f32));
+        }
+        list.take_dirty();
+        list
+    }
+
+    #[test]
+    fn push_marks_the_new_instances() {
+        let mut list = list_of(2);
+        assert_eq!(list.push(raw(2.0)), 2);
+        assert_eq!(list.push(raw(3.0)), 3);
+        assert_eq!(list.take_dirty(), vec![2..4]);
+        assert!(list.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn update_marks_only_the_updated_instances() {
+        let mut list = list_of(8);
+        list.update(5, raw(50.0));
+        list.update(1, raw(10.0));
+        list.update(2, raw(20.0));
+        assert_eq!(list.take_dirty(), vec![1..3, 5..6]);
+        assert_eq!(positions(&list), vec![0.0, 10.0, 20.0, 3.0, 4.0, 50.0, 6.0, 7.0]);
+    }
+
+    #[test]
+    fn swap_remove_marks_the_filled_slot() {
+        let mut list = list_of(4);
+        assert_eq!(list.swap_remove(1).model[3][0], 1.0);
+        assert_eq!(positions(&list), vec![0.0, 3.0, 2.0]);
+        assert_eq!(list.take_dirty(), vec![1..2]);
+    }
+
+    #[test]
+    fn swap_remove_of_the_last_instance_marks_nothing() {
+        let mut list = list_of(4);
+        list.swap_remove(3);
+        assert_eq!(positions(&list), vec![0.0, 1.0, 2.0]);
+        assert!(list.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn take_dirty_clips_ranges_after_a_shrink() {
+        let mut list = list_of(6);
+        list.update(1, raw(10.0));
+        list.update(4, raw(40.0));
+        list.update(5, raw(50.0));
+        for _ in 0..3 {
+            list.swap_remove(list.len() - 1);
+        }
+        assert_eq!(list.take_dirty(), vec![1..2]);
+
+        list.update(2, raw(20.0));
+        list.swap_remove(2);
+        assert!(list.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn apply_handles_every_change() {
+        let mut list = list_of(3);
+        list.apply(InstanceChange::Push(raw(3.0)));
+        assert_eq!(list.take_dirty(), vec![3..4]);
+        list.apply(InstanceChange::Update(1, raw(10.0)));
+        assert_eq!(list.take_dirty(), vec![1..2]);
+        list.apply(InstanceChange::SwapRemove(0));
+        assert_eq!(list.take_dirty(), vec![0..1]);
+        assert_eq!(positions(&list), vec![3.0, 10.0, 2.0]);
+    }
 }
@@ -1,39 +1,58 @@
-use bytemuck::bytes_of;
-use cgmath::{SquareMatrix, Rotation3, Zero, Angle};
-use depth_pass::DepthPass;
-use light::LightUniform;
-use texture::Texture;
-use tracing::info;
 use winit::{
-    event::{Event, WindowEvent, KeyEvent, ElementState, MouseButton, DeviceEvent},
+    event::{Event, WindowEvent, KeyEvent, ElementState, DeviceEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder, keyboard::{PhysicalKey, KeyCode},
 };
-use wgpu::util::DeviceExt;
-
-mod texture;
-mod camera;
-mod instance;
-mod model;
-mod light;
-mod depth_pass;
-mod compute_shadow;
+
+pub mod texture;
+pub mod camera;
+pub mod instance;
+pub mod model;
+pub mod light;
+pub mod depth_pass;
+pub mod quad;
+pub mod hdr;
+pub mod post;
+pub mod ssao;
+pub mod environment;
+pub mod compute_shadow;
+pub mod culling;
+pub mod mipmap;
+pub mod asset_loader;
+pub mod bounds;
+pub mod scene;
+pub mod scene_graph;
+mod renderer;
 // lib.rs
 use winit::window::Window;
+use scene::SceneDescription;
 
-use camera::*;
-use instance::*;
-use model::{DrawModel, Vertex};
+pub use renderer::{msaa_features, supported_sample_count, Renderer};
 
+#[allow(clippy::too_many_arguments)]
 fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
+    color_formats: &[wgpu::TextureFormat],
     depth_format: Option<wgpu::TextureFormat>,
+    depth_mode: texture::DepthMode,
+    depth_write: bool,
+    sample_count: u32,
+    blend: wgpu::BlendState,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     shader: wgpu::ShaderModuleDescriptor,
 ) -> wgpu::RenderPipeline {
     let shader = device.create_shader_module(shader);
+    let targets = color_formats
+        .iter()
+        .map(|&format| {
+            Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            })
+        })
+        .collect::<Vec<_>>();
 
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
         label: Some("Render Pipeline"),
@@ -46,14 +65,7 @@ fn create_render_pipeline(
         fragment: Some(wgpu::FragmentState {
             module: &shader,
             entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
+            targets: &targets,
         }),
         primitive: wgpu::PrimitiveState {
             topology: wgpu::PrimitiveTopology::TriangleList,
@@ -69,13 +81,13 @@ fn create_render_pipeline(
         },
         depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
             format,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
+            depth_write_enabled: depth_write,
+            depth_compare: depth_mode.compare(),
             stencil: wgpu::StencilState::default(),
             bias: wgpu::DepthBiasState::default(),
         }),
         multisample: wgpu::MultisampleState {
-            count: 1,
+            count: sample_count,
             mask: !0,
             alpha_to_coverage_enabled: false,
         },
@@ -86,47 +98,23 @@ fn create_render_pipeline(
 
 struct State {
     surface: wgpu::Surface,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
     size: winit::dpi::PhysicalSize<u32>,
     // The window must be declared after the surface so
     // it gets dropped after it as the surface contains
     // unsafe references to the window's resources.
     window: Window,
-    render_pipeline: wgpu::RenderPipeline,
-    obj_model: model::Model,
-
-    depth_texture: Texture,
-    
-
-    camera: camera::Camera, // UPDATED!
-    projection: camera::Projection, // NEW!
-    camera_controller: camera::CameraController, // UPDATED!
-    // ...
-    // NEW!
-    mouse_pressed: bool,
-
-    camera_uniform: CameraUniform,
-    camera_buffer: wgpu::Buffer,
-    camera_bind_group: wgpu::BindGroup,
-
-    light_uniform: LightUniform,
-    light_buffer: wgpu::Buffer,
-    light_bind_group: wgpu::BindGroup,
-    light_render_pipeline: wgpu::RenderPipeline,
-
-    instances: Vec<Instance>,
-    instance_buffer: wgpu::Buffer,
-
-    
-    depth_pass: DepthPass,
+    renderer: Renderer,
 
+    camera_controller: Box<dyn camera::CameraControl>,
+    /// Whether `camera_controller` is the orbit controller, Tab switches
+    orbiting: bool,
+    title: String,
 }
 
 impl State {
     // Creating some of the wgpu types requires async code
-    async fn new(window: Window) -> Self {
+    async fn new(window: Window, scene: &SceneDescription) -> Self {
         let size = window.inner_size();
 
         // The instance is a handle to our GPU
@@ -152,11 +140,12 @@ impl State {
 
         let (device, queue) = adapter.request_device(
             &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
-                // WebGL doesn't support all of wgpu's features, so if
-                // we're building for the web, we'll have to disable some.
+                features: renderer::msaa_features(&adapter),
+                // The web build runs on browser WebGPU, which may not reach
+                // the default limits. The WebGL2 limits are no option since
+                // the light list is a storage buffer, which WebGL2 lacks.
                 limits: if cfg!(target_arch = "wasm32") {
-                    wgpu::Limits::downlevel_webgl2_defaults()
+                    wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits())
                 } else {
                     wgpu::Limits::default()
                 },
@@ -172,8 +161,7 @@ impl State {
         // sRGB surfaces, you'll need to account for that when drawing to the frame.
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .filter(|f| f.is_srgb())
-            .next()
+            .find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
@@ -185,154 +173,204 @@ impl State {
             view_formats: vec![],
         };
         surface.configure(&device, &config);
-        
 
-        let texture_bind_group_layout = texture::create_texture_bind_group_layout(&device);
-        let depth_texture = texture::Texture::create_depth_texture(&device, &config, "depth_texture");
-        
-
-        info!("Load model");
-        let obj_model = model::load_obj("/Users/zhang/Downloads/GF2_Cheeta/CheetaDefault.obj", &device, &queue, &texture_bind_group_layout).unwrap();
-
-
-
-        let camera = camera::Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-20.0));
-        let projection = camera::Projection::new(config.width, config.height, cgmath::Deg(45.0), 0.1, 100.0);
-        let camera_controller = camera::CameraController::new(4.0, 0.4);
-
-
-
-        let mut camera_uniform = CameraUniform::new();
-        camera_uniform.update_view_proj(&camera, &projection); // UPDATED!
-        let (camera_buffer, camera_bind_group_layout, camera_bind_group) = camera_uniform.create_camera_buffer_bind_group(&device);
-        
-
-        let light_uniform = light::LightUniform {
-            position: [2.0, 2.0, 2.0, 1.0],
-            color: [1.0, 1.0, 1.0, 1.0],
-        };
-        let (light_buffer, light_bind_group_layout, light_bind_group) =
-            light::create_light_buffer_and_bind_group(&device, &light_uniform);
-
-
-        //shader file & render pipeline
-        let render_pipeline_layout =
-            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[
-                    &texture_bind_group_layout,
-                    &camera_bind_group_layout,
-                    &light_bind_group_layout,
-                ],
-                push_constant_ranges: &[],
-            });
-        
-        let render_pipeline = {
-            let shader = wgpu::include_wgsl!("shader.wgsl");
-
-            create_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc(), InstanceRaw::desc()],
-                shader,
-            )
-        };
-
-  
-        
-        let instances = vec![
-            Instance {
-                position: cgmath::Vector3::zero(),
-                rotation: cgmath::Quaternion::from_angle_y(cgmath::Deg(0.0)),
-                scaling: cgmath::Vector3::new(1.0, 1.0, 1.0),
-            },
-        ];
-        let instance_data = instances.iter().map(Instance::to_raw).collect::<Vec<_>>();
-        let instance_buffer = instance::create_instances_buffer(&device, &instance_data);
-
-        // lib.rs
-        let light_render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Light Pipeline Layout"),
-                bind_group_layouts: &[
-                    &camera_bind_group_layout,
-                    &light_bind_group_layout
-                ],
-                push_constant_ranges: &[],
-            });
-            let shader = wgpu::include_wgsl!("light.wgsl");
-
-            create_render_pipeline(
-                &device,
-                &layout,
-                config.format,
-                Some(texture::Texture::DEPTH_FORMAT),
-                &[model::ModelVertex::desc()],
-                shader,
-            )
-        };
+        let sample_count = renderer::supported_sample_count(&adapter, scene.msaa);
+        let renderer = Renderer::new(
+            device,
+            queue,
+            config.format,
+            config.width,
+            config.height,
+            sample_count,
+            scene,
+        ).unwrap();
 
+        let camera_controller = Box::new(camera::CameraController::new(4.0, 0.4));
+        let title = window.title();
 
-        let depth_pass = DepthPass::new(&device, &config);
-        
         Self {
             window,
             surface,
-            device,
-            queue,
             config,
             size,
-            render_pipeline,
-        
+            renderer,
 
-            obj_model,
-            
-            depth_texture,
-            
-            camera,
-            projection,
             camera_controller,
-            mouse_pressed: false,
-            
-            camera_uniform,
-            camera_buffer,
-            camera_bind_group,
-
-            light_uniform,
-            light_buffer,
-            light_bind_group,
-            light_render_pipeline,
-           
-
-            instances,
-            instance_buffer,
-
-            depth_pass,
+            orbiting: false,
+            title,
         }
 
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
-    }
-
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
             self.config.width = new_size.width;
             self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
-            self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
-            self.projection.resize(new_size.width, new_size.height);
-            self.depth_pass.resize(&self.device, &self.config);
-            
+            self.surface.configure(self.renderer.device(), &self.config);
+            self.renderer.resize(new_size.width, new_size.height);
         }
     }
 
     fn input(&mut self, event: &WindowEvent) -> bool {
         match event {
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(KeyCode::KeyF),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                if let Some(bounds) = self.renderer.focus(None) {
+                    self.camera_controller.set_target(bounds.center);
+                }
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(KeyCode::Tab),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                self.switch_camera_controller();
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(KeyCode::KeyP),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                let (_, distance) = self.view_target();
+                self.renderer.projection.toggle_orthographic(distance);
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(key @ (KeyCode::Digit1 | KeyCode::Digit3 | KeyCode::Digit7)),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                // Front, right side and top views like in most modelling tools
+                let (yaw, pitch) = match key {
+                    KeyCode::Digit1 => (-90.0, 0.0),
+                    KeyCode::Digit3 => (180.0, 0.0),
+                    _ => (-90.0, -90.0),
+                };
+                let (target, _) = self.view_target();
+                self.renderer.camera.look_at_from(target, cgmath::Deg(yaw), cgmath::Deg(pitch));
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(KeyCode::KeyT),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                if let Some(tonemapping) = self.renderer.post.tonemapping_mut() {
+                    tonemapping.operator = tonemapping.operator.next();
+                    tracing::info!("Tonemapping with {:?}", tonemapping.operator);
+                }
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(key @ (KeyCode::Minus | KeyCode::Equal)),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                // Half a stop per press
+                let step = if *key == KeyCode::Minus { -0.5 } else { 0.5 };
+                if let Some(tonemapping) = self.renderer.post.tonemapping_mut() {
+                    tonemapping.exposure += step;
+                    tracing::info!("Exposure {:+.1} EV", tonemapping.exposure);
+                }
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key:
+                        PhysicalKey::Code(
+                            key @ (KeyCode::KeyB | KeyCode::KeyX | KeyCode::KeyV | KeyCode::KeyG | KeyCode::KeyC),
+                        ),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                let name = match key {
+                    KeyCode::KeyB => "bloom",
+                    KeyCode::KeyX => "fxaa",
+                    KeyCode::KeyV => "vignette",
+                    KeyCode::KeyG => "color_grading",
+                    _ => "chromatic_aberration",
+                };
+                if let Some(effect) = self.renderer.post.find_mut(name) {
+                    effect.enabled = !effect.enabled;
+                    tracing::info!("{} {}", name, if effect.enabled { "on" } else { "off" });
+                }
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(KeyCode::KeyO),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                let ssao = &mut self.renderer.ssao.settings;
+                ssao.enabled = !ssao.enabled;
+                tracing::info!("SSAO {}", if ssao.enabled { "on" } else { "off" });
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(KeyCode::KeyZ),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                let settings = &mut self.renderer.debug;
+                settings.view = settings.view.next();
+                tracing::info!("Debug view {:?}", settings.view);
+                true
+            }
+            WindowEvent::KeyboardInput {
+                event:
+                KeyEvent {
+                    physical_key: PhysicalKey::Code(KeyCode::KeyM),
+                    state: ElementState::Pressed,
+                    ..
+                },
+                ..
+            } => {
+                let settings = &mut self.renderer.debug;
+                settings.fullscreen = !settings.fullscreen;
+                tracing::info!("Debug view {}", if settings.fullscreen { "fullscreen" } else { "as overlay" });
+                true
+            }
             WindowEvent::KeyboardInput {
                 event:
                 KeyEvent {
@@ -343,98 +381,70 @@ impl State {
                 ..
             } => self.camera_controller.process_keyboard(*key, *state),
             WindowEvent::MouseWheel { delta, .. } => {
-                self.camera_controller.process_scroll(delta);
+                // Moving doesn't zoom an orthographic view
+                if !self.renderer.projection.process_scroll(delta) {
+                    self.camera_controller.process_scroll(delta);
+                }
                 true
             }
             WindowEvent::MouseInput {
-                button: MouseButton::Left,
+                button,
                 state,
                 ..
-            } => {
-                self.mouse_pressed = *state == ElementState::Pressed;
-                true
-            }
+            } => self.camera_controller.process_mouse_button(*button, *state),
             _ => false,
         }
 
 
     }
 
-    fn update(&mut self, dt: instant::Duration) {
-        self.camera_controller.update_camera(&mut self.camera, dt);
-        self.camera_uniform.update_view_proj(&self.camera, &self.projection);
-        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
-
-        // Update the light
-        let old_position = cgmath::Vector4::from(self.light_uniform.position);
-        // self.light_uniform.position =
-        //     (cgmath::Matrix4::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(1.0))
-        //      * old_position)
-        //     .into();
+    /// The point the camera is looking at and how far ahead it is: the
+    /// target of the controller, else the center of the scene when it is in
+    /// front of the camera, else a point a few units ahead
+    fn view_target(&mut self) -> (cgmath::Point3<f32>, f32) {
+        use cgmath::InnerSpace;
+
+        let center = self.renderer.world_bounds(None).map(|bounds| bounds.center());
+        let camera = &self.renderer.camera;
+        let forward = camera.forward();
+        self.camera_controller
+            .target()
+            .or(center)
+            .map(|target| (target, (target - camera.position).dot(forward)))
+            .filter(|&(_, distance)| distance > 0.0)
+            .unwrap_or((camera.position + forward * 5.0, 5.0))
+    }
 
-        self.light_uniform.position =
-            (cgmath::Matrix4::from_axis_angle((0.0, 1.0, 0.0).into(), cgmath::Deg(60.0 * dt.as_secs_f32()))
-             * old_position).into(); // UPDATED!
+    /// Swaps between the fly and the orbit controller. The orbit controller
+    /// circles the point the camera is looking at.
+    fn switch_camera_controller(&mut self) {
+        self.orbiting = !self.orbiting;
+        self.camera_controller = if self.orbiting {
+            let (_, distance) = self.view_target();
+            Box::new(camera::OrbitController::new(distance, 0.4))
+        } else {
+            Box::new(camera::CameraController::new(4.0, 0.4))
+        };
+        tracing::info!("Orbit camera: {}", self.orbiting);
+    }
 
-        self.queue.write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.light_uniform]));
+    fn update(&mut self, dt: instant::Duration) {
+        self.camera_controller.update_camera(&mut self.renderer.camera, dt);
+        self.renderer.update(dt);
 
+        let title = match self.renderer.loading_progress() {
+            Some(progress) => format!("{} - loading {:.0}%", self.title, progress * 100.0),
+            None => self.title.clone(),
+        };
+        if self.window.title() != title {
+            self.window.set_title(&title);
+        }
     }
 
     fn render(&mut self, color: wgpu::Color) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
         let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
-            label: Some("Render Encoder"),
-        });
-        
-        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Render Pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
-                resolve_target: None,
-                ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(color),
-                    store: wgpu::StoreOp::Store,
-                },
-            })],
-            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                //                view: &self.depth_texture.view,
-                view: &self.depth_pass.texture.view,
-                depth_ops: Some(wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(1.0),
-                    store: wgpu::StoreOp::Store,
-                }),
-                stencil_ops: None,
-            }),
-            occlusion_query_set: None,
-            timestamp_writes: None,
-        });
-        // NEW!
-        render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-
-        info!("start light render pipeline");
-        use crate::model::DrawLight; // NEW!
-        render_pass.set_pipeline(&self.light_render_pipeline); // NEW!
-        render_pass.draw_light_model(
-            &self.obj_model,
-            &self.camera_bind_group,
-            &self.light_bind_group,
-        ); // NEW!
-
-        info!("start model render pipeline");
-        render_pass.set_pipeline(&self.render_pipeline); // 2.
-        render_pass.draw_model_instanced(
-            &self.obj_model,
-            0..self.instances.len() as u32,
-            &self.camera_bind_group,
-            &self.light_bind_group,
-        );
-
-        drop(render_pass);
-        self.depth_pass.render(&view, &mut encoder);
-
-        // submit will accept anything that implements IntoIter
-        self.queue.submit(std::iter::once(encoder.finish()));
+        self.renderer.render(&view, color);
         output.present();
 
         Ok(())
@@ -443,8 +453,8 @@ impl State {
 }
 
 
-pub async fn run() {
-    
+pub async fn run(scene: SceneDescription) {
+    let clear_color = scene.clear_color();
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
@@ -452,7 +462,7 @@ pub async fn run() {
     // dispatched any events. This is ideal for games and similar applications.
     event_loop.set_control_flow(ControlFlow::Poll);
 
-    let mut state = State::new(window).await;
+    let mut state = State::new(window, &scene).await;
     let mut last_render_time = instant::Instant::now();  // NEW!
 
 
@@ -472,7 +482,7 @@ pub async fn run() {
             Event::DeviceEvent {
                 event: DeviceEvent::MouseMotion{ delta, },
                 .. // We're not using device_id currently
-            } => if state.mouse_pressed {
+            } => {
                 state.camera_controller.process_mouse(delta.0, delta.1)
             },
             Event::WindowEvent {
@@ -506,7 +516,7 @@ pub async fn run() {
                     let dt = now - last_render_time;
                     last_render_time = now;
                     state.update(dt);
-                    match state.render(wgpu::Color::BLACK) {
+                    match state.render(clear_color) {
                         Ok(_) => {}
                         // Reconfigure the surface if lost
                         Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
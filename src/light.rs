@@ -1,50 +1,253 @@
-use wgpu::util::DeviceExt;
-
-pub fn create_light_buffer_and_bind_group(
-    device: &wgpu::Device,
-    light_uniform: &LightUniform,
-) -> (wgpu::Buffer, wgpu::BindGroupLayout, wgpu::BindGroup) {
-    // We'll want to update our lights position, so we use COPY_DST
-    let light_buffer = device.create_buffer_init(
-        &wgpu::util::BufferInitDescriptor {
-            label: Some("Light VB"),
-            contents: bytemuck::cast_slice(&[*light_uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+use cgmath::{InnerSpace, Rad, Vector3};
+
+/// Upper bound is arbitrary, it only keeps a runaway scene from eating VRAM.
+pub const MAX_LIGHTS: usize = 1024;
+
+pub const LIGHT_KIND_POINT: u32 = 0;
+pub const LIGHT_KIND_DIRECTIONAL: u32 = 1;
+pub const LIGHT_KIND_SPOT: u32 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightKind {
+    Point,
+    Directional,
+    Spot {
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+    },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: Vector3<f32>,
+    /// Direction the light travels in, ignored by point lights
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    /// Distance at which the light fades out completely, `0.0` means unbounded
+    pub range: f32,
+    /// Only the first light with this set gets the shadow map
+    pub casts_shadow: bool,
+}
+
+impl Light {
+    pub fn point(position: Vector3<f32>, color: Vector3<f32>, intensity: f32, range: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            position,
+            direction: -Vector3::unit_y(),
+            color,
+            intensity,
+            range,
+            casts_shadow: true,
+        }
+    }
+
+    pub fn directional(direction: Vector3<f32>, color: Vector3<f32>, intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Directional,
+            position: -direction.normalize() * 10.0,
+            direction: direction.normalize(),
+            color,
+            intensity,
+            range: 0.0,
+            casts_shadow: true,
+        }
+    }
+
+    pub fn spot<A: Into<Rad<f32>>>(
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        color: Vector3<f32>,
+        intensity: f32,
+        range: f32,
+        inner_angle: A,
+        outer_angle: A,
+    ) -> Self {
+        Self {
+            kind: LightKind::Spot {
+                inner_angle: inner_angle.into(),
+                outer_angle: outer_angle.into(),
+            },
+            position,
+            direction: direction.normalize(),
+            color,
+            intensity,
+            range,
+            casts_shadow: true,
         }
-    );
+    }
+
+    pub fn to_raw(&self) -> LightRaw {
+        let (kind, inner_cone_cos, outer_cone_cos) = match self.kind {
+            LightKind::Point => (LIGHT_KIND_POINT, -1.0, -1.0),
+            LightKind::Directional => (LIGHT_KIND_DIRECTIONAL, -1.0, -1.0),
+            LightKind::Spot { inner_angle, outer_angle } => {
+                (LIGHT_KIND_SPOT, inner_angle.0.cos(), outer_angle.0.cos())
+            }
+        };
+        LightRaw {
+            position: self.position.into(),
+            kind,
+            direction: self.direction.into(),
+            range: self.range,
+            color: self.color.into(),
+            intensity: self.intensity,
+            inner_cone_cos,
+            outer_cone_cos,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+// Layout has to match `struct Light` in shader.wgsl and light.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightRaw {
+    pub position: [f32; 3],
+    pub kind: u32,
+    pub direction: [f32; 3],
+    pub range: f32,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub inner_cone_cos: f32,
+    pub outer_cone_cos: f32,
+    _padding: [f32; 2],
+}
+
+/// Header in front of the light array, `count` is the number of valid entries
+/// and `shadow_index` the light the shadow map belongs to, `u32::MAX` if none
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightsHeader {
+    count: u32,
+    shadow_index: u32,
+    _padding: [u32; 2],
+}
+
+/// The scene's lights, stored as one `LightsHeader` followed by a `LightRaw`
+/// array in a storage buffer that grows when lights are added.
+pub struct LightList {
+    pub lights: Vec<Light>,
+    capacity: usize,
+    /// Lights in the buffer as of the last write, at most `MAX_LIGHTS`
+    uploaded: usize,
+    buffer: wgpu::Buffer,
+    pub layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+}
 
-    let light_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+impl LightList {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, lights: Vec<Light>) -> Self {
+        let capacity = lights.len().max(1);
+        let buffer = Self::create_buffer(device, capacity);
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             entries: &[wgpu::BindGroupLayoutEntry {
                 binding: 0,
                 visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
                     has_dynamic_offset: false,
                     min_binding_size: None,
                 },
                 count: None,
             }],
-            label: None,
+            label: Some("light_bind_group_layout"),
         });
-    
-    let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-        layout: &light_bind_group_layout,
-        entries: &[wgpu::BindGroupEntry {
-            binding: 0,
-            resource: light_buffer.as_entire_binding(),
-        }],
-        label: None,
-    });
-    (light_buffer, light_bind_group_layout, light_bind_group)
-}
+        let bind_group = Self::create_bind_group(device, &layout, &buffer);
 
+        let mut list = Self {
+            lights,
+            capacity,
+            uploaded: 0,
+            buffer,
+            layout,
+            bind_group,
+        };
+        list.write(device, queue);
+        list
+    }
 
-// lib.rs
-#[repr(C)]
-#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct LightUniform {
-    pub position: [f32; 4],
-    pub color: [f32; 4],
-}
+    fn create_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Light Storage Buffer"),
+            size: (std::mem::size_of::<LightsHeader>() + capacity * std::mem::size_of::<LightRaw>())
+                as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffer: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            }],
+            label: Some("light_bind_group"),
+        })
+    }
 
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    /// Number of lights the shaders can see, lights past `MAX_LIGHTS` or
+    /// added since the last [`LightList::write`] are not uploaded
+    pub fn uploaded_len(&self) -> usize {
+        self.uploaded
+    }
+
+    /// Index of the light that owns the shadow map, the first that casts shadows
+    pub fn shadow_caster(&self) -> Option<usize> {
+        self.lights[..self.lights.len().min(MAX_LIGHTS)]
+            .iter()
+            .position(|light| light.casts_shadow)
+    }
+
+    pub fn push(&mut self, light: Light) -> usize {
+        self.lights.push(light);
+        self.lights.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Light {
+        self.lights.remove(index)
+    }
+
+    /// Uploads the lights, reallocating the buffer (and bind group) if it is too small.
+    pub fn write(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let count = self.lights.len().min(MAX_LIGHTS);
+        if count > self.capacity {
+            self.capacity = count.next_power_of_two().min(MAX_LIGHTS);
+            self.buffer = Self::create_buffer(device, self.capacity);
+            self.bind_group = Self::create_bind_group(device, &self.layout, &self.buffer);
+        }
+
+        let header = LightsHeader {
+            count: count as u32,
+            shadow_index: self.shadow_caster().map_or(u32::MAX, |index| index as u32),
+            _padding: [0; 2],
+        };
+        let raw = self.lights[..count].iter().map(Light::to_raw).collect::<Vec<_>>();
+        queue.write_buffer(&self.buffer, 0, bytemuck::bytes_of(&header));
+        if !raw.is_empty() {
+            queue.write_buffer(
+                &self.buffer,
+                std::mem::size_of::<LightsHeader>() as wgpu::BufferAddress,
+                bytemuck::cast_slice(&raw),
+            );
+        }
+        self.uploaded = count;
+    }
+}
@@ -8,12 +8,26 @@ struct Camera {
 @group(0) @binding(0)
 var<uniform> camera: Camera;
 
+const LIGHT_KIND_DIRECTIONAL: u32 = 1u;
+
 struct Light {
-    position: vec4<f32>,
-    color: vec4<f32>,
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    inner_cone_cos: f32,
+    outer_cone_cos: f32,
+}
+struct Lights {
+    count: u32,
+    // Light the shadow map belongs to, 0xffffffffu if none
+    shadow_index: u32,
+    data: array<Light>,
 }
 @group(1) @binding(0)
-var<uniform> light: Light;
+var<storage, read> lights: Lights;
 
 struct VertexInput {
     @location(0) position: vec3<f32>,
@@ -27,17 +41,33 @@ struct VertexOutput {
 @vertex
 fn vs_main(
     model: VertexInput,
+    @builtin(instance_index) light_index: u32,
 ) -> VertexOutput {
+    let light = lights.data[light_index];
     let scale = 0.25;
     var out: VertexOutput;
-    out.clip_position = camera.view_proj * vec4<f32>(model.position * scale + light.position.xyz, 1.0);
-    out.color = light.color.xyz;
+    out.clip_position = camera.view_proj * vec4<f32>(model.position * scale + light.position, 1.0);
+    // Directional lights have no position worth showing, collapse their
+    // cube into a single point outside the clip volume so nothing is drawn
+    if (light.kind == LIGHT_KIND_DIRECTIONAL) {
+        out.clip_position = vec4<f32>(0.0, 0.0, 2.0, 1.0);
+    }
+    out.color = light.color;
     return out;
 }
 
 // Fragment shader
 
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // The debug view shows the gizmos in their light color
+    @location(1) debug: vec4<f32>,
+}
+
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return vec4<f32>(in.color, 1.0);
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    var out: FragmentOutput;
+    out.color = vec4<f32>(in.color, 1.0);
+    out.debug = vec4<f32>(in.color, 1.0);
+    return out;
 }
@@ -1,9 +1,78 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
 use rs_wgpu::run;
-mod model;
-mod texture;
+use rs_wgpu::scene::{ModelDescription, SceneDescription};
+
+const USAGE: &str = "\
+Usage: rs-wgpu [SCENE] [--model PATH]... [--msaa SAMPLES]
+
+Arguments:
+  SCENE          TOML scene file, defaults to scenes/example.toml
+
+Options:
+  --model PATH   Show the model at PATH (OBJ or glTF) at the origin, can be
+                 repeated. Without SCENE only these models are shown
+  --msaa SAMPLES Multisample anti-aliasing with 1, 2, 4 or 8 samples,
+                 overrides the scene
+  -h, --help     Print this help";
+
+fn parse_args() -> anyhow::Result<SceneDescription> {
+    let mut scene_path = None;
+    let mut models = Vec::new();
+    let mut msaa = None;
+
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-h") | Some("--help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            Some("--model") => {
+                let path = args.next().ok_or_else(|| anyhow::anyhow!("--model needs a path"))?;
+                models.push(ModelDescription::new(path));
+            }
+            Some("--msaa") => {
+                let samples = args.next().ok_or_else(|| anyhow::anyhow!("--msaa needs a sample count"))?;
+                let samples = samples.to_string_lossy();
+                msaa = Some(samples.parse().map_err(|_| anyhow::anyhow!("invalid sample count {}", samples))?);
+            }
+            Some(flag) if flag.starts_with('-') => anyhow::bail!("unknown option {}", flag),
+            _ if scene_path.is_none() => scene_path = Some(PathBuf::from(arg)),
+            _ => anyhow::bail!("more than one scene file given"),
+        }
+    }
+
+    if scene_path.is_none() && models.is_empty() {
+        scene_path = Some(PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/scenes/example.toml")));
+    }
+    let mut scene = match &scene_path {
+        Some(path) => SceneDescription::load(path)?,
+        None => SceneDescription::default(),
+    };
+    scene.models.extend(models);
+    if let Some(msaa) = msaa {
+        scene.msaa = msaa;
+    }
+    // Validated once the command line has had its say
+    scene.validate().with_context(|| match &scene_path {
+        Some(path) => format!("Invalid scene file {:?}", path),
+        None => "Invalid scene".to_string(),
+    })?;
+    Ok(scene)
+}
 
 fn main() {
     tracing_subscriber::fmt::init();
 
-    pollster::block_on(run());
+    let scene = match parse_args() {
+        Ok(scene) => scene,
+        Err(e) => {
+            eprintln!("error: {:#}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    pollster::block_on(run(scene));
 }
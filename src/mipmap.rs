@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+
+/// Number of levels in a full mip chain down to 1x1
+pub fn mip_level_count(width: u32, height: u32) -> u32 {
+    32 - width.max(height).max(1).leading_zeros()
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let c = value as f32 / 255.0;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let c = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round().clamp(0.0, 255.0) as u8
+}
+
+/// Halves `img` with a 2x2 box filter. Color channels of sRGB images are
+/// averaged in linear space, alpha always is.
+pub fn downsample(img: &image::RgbaImage, is_srgb: bool) -> image::RgbaImage {
+    let (width, height) = img.dimensions();
+    let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+
+    image::RgbaImage::from_fn(next_width, next_height, |x, y| {
+        let mut sum = [0.0f32; 4];
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            // Clamping repeats the edge when a dimension is already 1
+            let pixel = img.get_pixel((x * 2 + dx).min(width - 1), (y * 2 + dy).min(height - 1));
+            for (c, sum) in sum.iter_mut().enumerate() {
+                *sum += if is_srgb && c < 3 {
+                    srgb_to_linear(pixel[c])
+                } else {
+                    pixel[c] as f32 / 255.0
+                };
+            }
+        }
+        image::Rgba(std::array::from_fn(|c| {
+            let average = sum[c] / 4.0;
+            if is_srgb && c < 3 {
+                linear_to_srgb(average)
+            } else {
+                (average * 255.0).round() as u8
+            }
+        }))
+    })
+}
+
+/// Every level below `img` in its mip chain, largest first
+pub fn generate_mip_chain(img: &image::RgbaImage, is_srgb: bool) -> Vec<image::RgbaImage> {
+    let levels = mip_level_count(img.width(), img.height());
+    let mut chain: Vec<image::RgbaImage> = Vec::with_capacity(levels.saturating_sub(1) as usize);
+    for _ in 1..levels {
+        let next = downsample(chain.last().unwrap_or(img), is_srgb);
+        chain.push(next);
+    }
+    chain
+}
+
+/// Whether `format` can be rendered to and filtered, which the blit needs
+pub fn supports_gpu_generation(device: &wgpu::Device, format: wgpu::TextureFormat) -> bool {
+    let features = format.guaranteed_format_features(device.features());
+    features.allowed_usages.contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+        && features.flags.contains(wgpu::TextureFormatFeatureFlags::FILTERABLE)
+}
+
+/// Fills the mip levels of textures by repeatedly blitting each level into
+/// the next with a linear sampler. Pipelines are created per texture format
+/// on first use and cached, so one generator should serve every texture.
+pub struct MipmapGenerator {
+    shader: wgpu::ShaderModule,
+    layout: wgpu::BindGroupLayout,
+    pipeline_layout: wgpu::PipelineLayout,
+    sampler: wgpu::Sampler,
+    pipelines: HashMap<wgpu::TextureFormat, wgpu::RenderPipeline>,
+}
+
+impl MipmapGenerator {
+    pub fn new(device: &wgpu::Device) -> Self {
+        let shader = device.create_shader_module(wgpu::include_wgsl!("mipmap.wgsl"));
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Mipmap Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Mipmap Pipeline Layout"),
+            bind_group_layouts: &[&layout],
+            push_constant_ranges: &[],
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("mipmap_sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            shader,
+            layout,
+            pipeline_layout,
+            sampler,
+            pipelines: HashMap::new(),
+        }
+    }
+
+    fn create_pipeline(&self, device: &wgpu::Device, format: wgpu::TextureFormat) -> wgpu::RenderPipeline {
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Mipmap Pipeline"),
+            layout: Some(&self.pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &self.shader,
+                entry_point: "vs_main",
+                buffers: &[],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &self.shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: None,
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        })
+    }
+
+    /// Generates levels `1..mip_level_count` of `texture` from level 0. The
+    /// texture needs `COPY_SRC` and `COPY_DST` usage.
+    ///
+    /// Levels are drawn into single-level scratch textures and copied over,
+    /// since the GL backend ignores the mip range of a view when sampling it.
+    pub fn generate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, texture: &wgpu::Texture) {
+        if texture.mip_level_count() < 2 {
+            return;
+        }
+
+        let format = texture.format();
+        if !self.pipelines.contains_key(&format) {
+            let pipeline = self.create_pipeline(device, format);
+            self.pipelines.insert(format, pipeline);
+        }
+        let pipeline = &self.pipelines[&format];
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Mipmap Encoder"),
+        });
+
+        let create_scratch = |size| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("mip_scratch"),
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+                    | wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_SRC
+                    | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            })
+        };
+        let mip_copy = |level| wgpu::ImageCopyTexture {
+            texture,
+            mip_level: level,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        };
+
+        // Dropping a texture before the commands using it are submitted destroys it
+        let mut scratch_textures = vec![create_scratch(texture.size())];
+        encoder.copy_texture_to_texture(mip_copy(0), scratch_textures[0].as_image_copy(), texture.size());
+
+        for level in 1..texture.mip_level_count() {
+            let size = texture.size().mip_level_size(level, texture.dimension());
+            let scratch = create_scratch(size);
+            let source = scratch_textures[scratch_textures.len() - 1].create_view(&wgpu::TextureViewDescriptor::default());
+            let target = scratch.create_view(&wgpu::TextureViewDescriptor::default());
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &self.layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&source),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&self.sampler),
+                    },
+                ],
+                label: None,
+            });
+
+            {
+                let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: Some("Mipmap Pass"),
+                    color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                        view: &target,
+                        resolve_target: None,
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                            store: wgpu::StoreOp::Store,
+                        },
+                    })],
+                    depth_stencil_attachment: None,
+                    occlusion_query_set: None,
+                    timestamp_writes: None,
+                });
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_bind_group(0, &bind_group, &[]);
+                render_pass.draw(0..3, 0..1);
+            }
+
+            encoder.copy_texture_to_texture(scratch.as_image_copy(), mip_copy(level), size);
+            scratch_textures.push(scratch);
+        }
+
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn level_count_covers_the_largest_dimension() {
+        assert_eq!(mip_level_count(1, 1), 1);
+        assert_eq!(mip_level_count(2, 2), 2);
+        assert_eq!(mip_level_count(256, 128), 9);
+        assert_eq!(mip_level_count(5, 3), 3);
+        assert_eq!(mip_level_count(1, 1000), 10);
+    }
+
+    #[test]
+    fn chain_halves_down_to_one_texel() {
+        let img = image::RgbaImage::new(8, 3);
+        let dimensions = generate_mip_chain(&img, false)
+            .iter()
+            .map(|level| level.dimensions())
+            .collect::<Vec<_>>();
+        assert_eq!(dimensions, vec![(4, 1), (2, 1), (1, 1)]);
+    }
+
+    #[test]
+    fn single_texel_has_no_extra_levels() {
+        let img = image::RgbaImage::new(1, 1);
+        assert!(generate_mip_chain(&img, true).is_empty());
+    }
+
+    #[test]
+    fn linear_downsample_averages_texels() {
+        let img = image::RgbaImage::from_fn(2, 2, |x, y| {
+            let v = if (x + y) % 2 == 0 { 0 } else { 200 };
+            image::Rgba([v, v, 255 - v, v])
+        });
+        let level = downsample(&img, false);
+        assert_eq!(level.dimensions(), (1, 1));
+        assert_eq!(level.get_pixel(0, 0).0, [100, 100, 155, 100]);
+    }
+
+    #[test]
+    fn srgb_downsample_averages_in_linear_space() {
+        let img = image::RgbaImage::from_fn(2, 2, |x, _| {
+            if x == 0 {
+                image::Rgba([0, 0, 0, 0])
+            } else {
+                image::Rgba([255, 255, 255, 255])
+            }
+        });
+        let pixel = downsample(&img, true).get_pixel(0, 0).0;
+        // Half intensity is 0.5 linear, which is 188 in sRGB; alpha stays linear
+        assert_eq!(pixel, [188, 188, 188, 128]);
+    }
+
+    #[test]
+    fn uniform_color_is_preserved() {
+        let img = image::RgbaImage::from_pixel(16, 16, image::Rgba([10, 120, 240, 77]));
+        for level in generate_mip_chain(&img, true) {
+            assert!(level.pixels().all(|p| p.0 == [10, 120, 240, 77]));
+        }
+    }
+}
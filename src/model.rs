@@ -1,7 +1,18 @@
-use std::{path::{self, Path}, ops::Range};
+use std::{
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use anyhow::Context;
+use cgmath::{InnerSpace, Matrix, SquareMatrix};
 use wgpu::util::DeviceExt;
 use tracing::{info, warn};
 
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::mipmap::MipmapGenerator;
 use crate::texture;
 
 // model.rs
@@ -64,114 +75,990 @@ impl Vertex for ModelVertex {
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    /// Encloses all meshes, in model space
+    pub aabb: Aabb,
+    /// Encloses all meshes, in model space
+    pub bounding_sphere: BoundingSphere,
 }
 
+impl Model {
+    /// Whether `mesh` belongs in the sorted transparent pass
+    pub fn is_blended(&self, mesh: &Mesh) -> bool {
+        self.materials[mesh.material_idx].uniform.alpha_mode() == AlphaMode::Blend
+    }
+}
+
+/// How the alpha of the base color is used, following glTF's `alphaMode`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Alpha is ignored
+    #[default]
+    Opaque,
+    /// Fully transparent below `alpha_cutoff`, opaque otherwise
+    Mask,
+    /// Blended over what is behind, drawn back to front after everything
+    /// opaque
+    Blend,
+}
+
+impl AlphaMode {
+    /// Guesses the mode from the alpha channel of a base color texture:
+    /// mostly fully transparent or fully opaque texels, like foliage, are
+    /// masked, partially transparent ones, like glass, are blended.
+    pub fn from_texture(img: &image::DynamicImage) -> Self {
+        if !img.color().has_alpha() {
+            return AlphaMode::Opaque;
+        }
+        // Within 2% of either end counts as fully transparent or opaque
+        let (mut cut_out, mut partial) = (0usize, 0usize);
+        for pixel in img.to_rgba8().pixels() {
+            match pixel[3] {
+                250..=255 => {}
+                0..=5 => cut_out += 1,
+                _ => partial += 1,
+            }
+        }
+        if cut_out + partial == 0 {
+            AlphaMode::Opaque
+        } else if partial * 4 < cut_out + partial {
+            AlphaMode::Mask
+        } else {
+            AlphaMode::Blend
+        }
+    }
+}
+
+/// Scalar factors of a metallic-roughness material, multiplied with the
+/// corresponding texture samples in `shader.wgsl`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    pub base_color_factor: [f32; 4],
+    pub emissive_factor: [f32; 3],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub occlusion_strength: f32,
+    /// Alpha below which masked materials are discarded
+    pub alpha_cutoff: f32,
+    /// An [`AlphaMode`], see [`MaterialUniform::alpha_mode`]
+    alpha_mode: u32,
+}
+
+impl Default for MaterialUniform {
+    fn default() -> Self {
+        Self {
+            base_color_factor: [1.0; 4],
+            emissive_factor: [0.0; 3],
+            metallic_factor: 0.0,
+            roughness_factor: 1.0,
+            occlusion_strength: 1.0,
+            alpha_cutoff: 0.5,
+            alpha_mode: AlphaMode::Opaque as u32,
+        }
+    }
+}
+
+impl MaterialUniform {
+    pub fn alpha_mode(&self) -> AlphaMode {
+        match self.alpha_mode {
+            1 => AlphaMode::Mask,
+            2 => AlphaMode::Blend,
+            _ => AlphaMode::Opaque,
+        }
+    }
+
+    pub fn set_alpha_mode(&mut self, mode: AlphaMode) {
+        self.alpha_mode = mode as u32;
+    }
+}
+
+/// Physically based metallic-roughness material, following the glTF 2.0 model.
 pub struct Material {
     pub name: String,
+    /// Base color
     pub diffuse_texture: texture::Texture,
+    pub normal_texture: texture::Texture,
+    /// Roughness in the green channel, metalness in the blue channel
+    pub metallic_roughness_texture: texture::Texture,
+    /// Ambient occlusion in the red channel
+    pub occlusion_texture: texture::Texture,
+    pub emissive_texture: texture::Texture,
+    pub uniform: MaterialUniform,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
 }
 
+pub struct MaterialTextures {
+    pub diffuse: texture::Texture,
+    pub normal: texture::Texture,
+    pub metallic_roughness: texture::Texture,
+    pub occlusion: texture::Texture,
+    pub emissive: texture::Texture,
+}
+
+impl Material {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        name: String,
+        textures: MaterialTextures,
+        uniform: MaterialUniform,
+    ) -> Self {
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let MaterialTextures {
+            diffuse,
+            normal,
+            metallic_roughness,
+            occlusion,
+            emissive,
+        } = textures;
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&diffuse.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&diffuse.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&normal.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&metallic_roughness.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&metallic_roughness.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::TextureView(&occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::Sampler(&occlusion.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::TextureView(&emissive.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::Sampler(&emissive.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some(&name),
+        });
+
+        Self {
+            name,
+            diffuse_texture: diffuse,
+            normal_texture: normal,
+            metallic_roughness_texture: metallic_roughness,
+            occlusion_texture: occlusion,
+            emissive_texture: emissive,
+            uniform,
+            uniform_buffer,
+            bind_group,
+        }
+    }
+
+    /// Uploads changes made to `uniform`
+    pub fn write_uniform(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+}
+
 pub struct Mesh {
     pub name: String,
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material_idx: usize,
+    /// Bounds of the vertices in model space
+    pub aabb: Aabb,
+    pub bounding_sphere: BoundingSphere,
+}
+
+/// The texture slots of a `Material`, which decide color space and fallback
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum TextureSlot {
+    BaseColor,
+    Normal,
+    MetallicRoughness,
+    Occlusion,
+    Emissive,
+}
+
+impl TextureSlot {
+    fn is_linear(self) -> bool {
+        !matches!(self, TextureSlot::BaseColor | TextureSlot::Emissive)
+    }
+
+    /// Texel that leaves the material factors unchanged
+    fn default_pixel(self) -> image::Rgba<u8> {
+        match self {
+            TextureSlot::Normal => image::Rgba([128, 128, 255, 255]),
+            _ => image::Rgba([255, 255, 255, 255]),
+        }
+    }
+}
+
+fn default_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mipmaps: &mut MipmapGenerator,
+    label: &str,
+    slot: TextureSlot,
+) -> texture::Texture {
+    let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(1, 1, slot.default_pixel()));
+    texture::Texture::from_image(device, queue, mipmaps, &img, Some(label), slot.is_linear()).unwrap()
+}
+
+/// Uploads `img`, falling back to a 1x1 texture of the slot's neutral value
+/// when there is none or it can't be uploaded.
+fn upload_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mipmaps: &mut MipmapGenerator,
+    img: Option<&image::DynamicImage>,
+    label: &str,
+    slot: TextureSlot,
+) -> texture::Texture {
+    match img.map(|img| texture::Texture::from_image(device, queue, mipmaps, img, Some(label), slot.is_linear())) {
+        Some(Ok(v)) => v,
+        Some(Err(e)) => {
+            warn!("Error while uploading texture: {:?}, use default texture instead", e);
+            default_texture(device, queue, mipmaps, label, slot)
+        }
+        None => default_texture(device, queue, mipmaps, label, slot),
+    }
+}
+
+/// A mesh in CPU memory, as produced by the parsers before anything touches the GPU
+pub struct MeshData {
+    pub name: String,
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+    pub material_idx: usize,
+}
+
+impl MeshData {
+    pub fn upload(&self, device: &wgpu::Device) -> Mesh {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Vertex Buffer", self.name)),
+            contents: bytemuck::cast_slice(&self.vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Index Buffer", self.name)),
+            contents: bytemuck::cast_slice(&self.indices),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        Mesh {
+            name: self.name.clone(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: self.indices.len() as u32,
+            material_idx: self.material_idx,
+            aabb: Aabb::from_points(self.positions()),
+            bounding_sphere: BoundingSphere::from_points(self.positions()),
+        }
+    }
+
+    fn positions(&self) -> impl Iterator<Item = cgmath::Point3<f32>> + Clone + '_ {
+        self.vertices.iter().map(|v| cgmath::Point3::from(v.position))
+    }
+}
+
+/// A material with its decoded images, `None` images use the slot's default
+#[derive(Default)]
+pub struct MaterialData {
+    pub name: String,
+    pub diffuse: Option<image::DynamicImage>,
+    pub normal: Option<image::DynamicImage>,
+    /// Roughness in the green channel, metalness in the blue channel
+    pub metallic_roughness: Option<image::DynamicImage>,
+    pub occlusion: Option<image::DynamicImage>,
+    pub emissive: Option<image::DynamicImage>,
+    pub uniform: MaterialUniform,
+}
+
+impl MaterialData {
+    fn default_material() -> Self {
+        Self {
+            name: "default".to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmaps: &mut MipmapGenerator,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Material {
+        let name = &self.name;
+        let textures = MaterialTextures {
+            diffuse: upload_texture(device, queue, mipmaps, self.diffuse.as_ref(), name, TextureSlot::BaseColor),
+            normal: upload_texture(device, queue, mipmaps, self.normal.as_ref(), name, TextureSlot::Normal),
+            metallic_roughness: upload_texture(
+                device,
+                queue,
+                mipmaps,
+                self.metallic_roughness.as_ref(),
+                name,
+                TextureSlot::MetallicRoughness,
+            ),
+            occlusion: upload_texture(device, queue, mipmaps, self.occlusion.as_ref(), name, TextureSlot::Occlusion),
+            emissive: upload_texture(device, queue, mipmaps, self.emissive.as_ref(), name, TextureSlot::Emissive),
+        };
+        Material::new(device, layout, name.clone(), textures, self.uniform)
+    }
+}
+
+/// A fully parsed model whose images are decoded, ready for [`ModelData::upload`].
+///
+/// Parsing only needs the CPU, so it can happen on worker threads (see
+/// `asset_loader`) while the GPU upload stays on the render thread.
+#[derive(Default)]
+pub struct ModelData {
+    pub meshes: Vec<MeshData>,
+    pub materials: Vec<MaterialData>,
+}
+
+impl ModelData {
+    pub fn upload(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmaps: &mut MipmapGenerator,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Model {
+        Model {
+            meshes: self.meshes.iter().map(|m| m.upload(device)).collect(),
+            materials: self.materials.iter().map(|m| m.upload(device, queue, mipmaps, layout)).collect(),
+            aabb: self.aabb(),
+            bounding_sphere: self.bounding_sphere(),
+        }
+    }
+
+    pub fn aabb(&self) -> Aabb {
+        Aabb::from_points(self.meshes.iter().flat_map(MeshData::positions))
+    }
+
+    pub fn bounding_sphere(&self) -> BoundingSphere {
+        BoundingSphere::from_points(self.meshes.iter().flat_map(MeshData::positions))
+    }
+
+    /// Unit cube with the default material, shown while the real model loads
+    pub fn cube() -> Self {
+        // (normal, tangent) per face, the bitangent is their cross product
+        let faces = [
+            ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0]),
+            ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0]),
+            ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0]),
+            ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0]),
+        ];
+        let mut vertices = Vec::with_capacity(24);
+        let mut indices = Vec::with_capacity(36);
+        for (normal, tangent) in faces {
+            let n = cgmath::Vector3::from(normal);
+            let t = cgmath::Vector3::from(tangent);
+            let b = n.cross(t);
+            let base = vertices.len() as u32;
+            for (u, v) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+                let position = (n + t * (u * 2.0 - 1.0) + b * (v * 2.0 - 1.0)) * 0.5;
+                vertices.push(ModelVertex {
+                    position: position.into(),
+                    tex_coords: [u, 1.0 - v],
+                    normal,
+                    tangent,
+                    bitangent: b.into(),
+                });
+            }
+            indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+        }
+
+        Self {
+            meshes: vec![MeshData {
+                name: "placeholder".to_string(),
+                vertices,
+                indices,
+                material_idx: 0,
+            }],
+            materials: vec![MaterialData::default_material()],
+        }
+    }
+}
+
+/// Decodes `paths` on up to one thread per core, calling `on_progress` with
+/// the number of finished images. Broken images are logged and left out.
+fn decode_images(
+    paths: &[PathBuf],
+    on_progress: &(dyn Fn(usize, usize) + Sync),
+) -> Vec<Option<image::DynamicImage>> {
+    let next = AtomicUsize::new(0);
+    let done = AtomicUsize::new(0);
+    let results = Mutex::new((0..paths.len()).map(|_| None).collect::<Vec<_>>());
+    let workers = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(paths.len());
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = paths.get(i) else {
+                    break;
+                };
+                info!("load texture from {:?}", path);
+                let img = match image::open(path) {
+                    Ok(img) => Some(img),
+                    Err(e) => {
+                        warn!("Error while loading texture {:?}: {:?}, use default texture instead", path, e);
+                        None
+                    }
+                };
+                results.lock().unwrap()[i] = img;
+                on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, paths.len());
+            });
+        }
+    });
+    results.into_inner().unwrap()
+}
+
+/// MTL keeps roughness (`map_Pr`) and metalness (`map_Pm`) in separate
+/// grayscale maps, which are packed into the glTF layout here.
+fn pack_metallic_roughness(
+    roughness: Option<&image::DynamicImage>,
+    metallic: Option<&image::DynamicImage>,
+) -> Option<image::DynamicImage> {
+    if roughness.is_none() && metallic.is_none() {
+        return None;
+    }
+
+    let (width, height) = [roughness, metallic]
+        .into_iter()
+        .flatten()
+        .fold((1, 1), |(w, h), img| (w.max(img.width()), h.max(img.height())));
+    let fit = |img: Option<&image::DynamicImage>| {
+        img.map(|img| {
+            let img = img.to_luma8();
+            if img.dimensions() == (width, height) {
+                img
+            } else {
+                image::imageops::resize(&img, width, height, image::imageops::FilterType::Triangle)
+            }
+        })
+    };
+    let roughness = fit(roughness);
+    let metallic = fit(metallic);
+    let packed = image::RgbaImage::from_fn(width, height, |x, y| {
+        let r = roughness.as_ref().map_or(255, |img| img.get_pixel(x, y)[0]);
+        let m = metallic.as_ref().map_or(255, |img| img.get_pixel(x, y)[0]);
+        image::Rgba([0, r, m, 255])
+    });
+    Some(image::DynamicImage::ImageRgba8(packed))
+}
+
+/// Fills the PBR factors from the MTL extensions (`Pr`, `Pm`, `Ke`), deriving
+/// roughness from the Blinn-Phong exponent `Ns` when `Pr` is absent.
+fn obj_material_uniform(m: &tobj::Material) -> MaterialUniform {
+    let param = |key: &str| m.unknown_param.get(key);
+    let parse_float = |key: &str| param(key).and_then(|v| v.trim().parse::<f32>().ok());
+    let parse_float3 = |key: &str| {
+        let values = param(key)?
+            .split_whitespace()
+            .map(|v| v.parse::<f32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        match values[..] {
+            [r, g, b] => Some([r, g, b]),
+            [v] => Some([v; 3]),
+            _ => None,
+        }
+    };
+
+    let diffuse = m.diffuse.unwrap_or([1.0; 3]);
+    let roughness = parse_float("Pr")
+        .or_else(|| m.shininess.map(|ns| (2.0 / (ns.max(0.0) + 2.0)).sqrt()))
+        .unwrap_or(1.0);
+    // `Tr` is the transparency some exporters write instead of `d`
+    let opacity = m
+        .dissolve
+        .or_else(|| parse_float("Tr").map(|tr| 1.0 - tr))
+        .unwrap_or(1.0)
+        .clamp(0.0, 1.0);
+    let mut uniform = MaterialUniform {
+        base_color_factor: [diffuse[0], diffuse[1], diffuse[2], opacity],
+        emissive_factor: parse_float3("Ke").unwrap_or([0.0; 3]),
+        metallic_factor: parse_float("Pm").unwrap_or(0.0),
+        roughness_factor: roughness.clamp(0.0, 1.0),
+        ..Default::default()
+    };
+    if opacity < 1.0 {
+        uniform.set_alpha_mode(AlphaMode::Blend);
+    }
+    uniform
+}
+
+/// Fills in per-vertex tangents and bitangents from the triangle positions and UVs.
+///
+/// Each triangle's tangent frame is accumulated on its vertices, then the result
+/// is averaged and made orthogonal to the vertex normal.
+pub fn compute_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    use cgmath::{Vector2, Vector3, Zero};
+
+    let mut tangents = vec![Vector3::<f32>::zero(); vertices.len()];
+    let mut bitangents = vec![Vector3::<f32>::zero(); vertices.len()];
+
+    for c in indices.chunks_exact(3) {
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
+
+        let pos0: Vector3<f32> = v0.position.into();
+        let pos1: Vector3<f32> = v1.position.into();
+        let pos2: Vector3<f32> = v2.position.into();
+
+        let uv0: Vector2<f32> = v0.tex_coords.into();
+        let uv1: Vector2<f32> = v1.tex_coords.into();
+        let uv2: Vector2<f32> = v2.tex_coords.into();
+
+        let delta_pos1 = pos1 - pos0;
+        let delta_pos2 = pos2 - pos0;
+        let delta_uv1 = uv1 - uv0;
+        let delta_uv2 = uv2 - uv0;
+
+        // Degenerate UVs can't define a tangent frame
+        let det = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
+
+        for &i in c {
+            tangents[i as usize] += tangent;
+            bitangents[i as usize] += bitangent;
+        }
+    }
+
+    for (i, v) in vertices.iter_mut().enumerate() {
+        let n: Vector3<f32> = v.normal.into();
+        let n = if n.magnitude2() > 0.0 { n.normalize() } else { Vector3::unit_y() };
+
+        // Gram-Schmidt, keeping the handedness of the UV mapping
+        let mut t = tangents[i] - n * n.dot(tangents[i]);
+        if t.magnitude2() < f32::EPSILON {
+            // No usable UVs, any vector perpendicular to the normal will do
+            let axis = if n.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+            t = axis - n * n.dot(axis);
+        }
+        let t = t.normalize();
+        let mut b = n.cross(t);
+        if b.dot(bitangents[i]) < 0.0 {
+            b = -b;
+        }
+
+        v.tangent = t.into();
+        v.bitangent = b.into();
+    }
+}
+
+/// Picks the importer from the file extension: `.gltf`/`.glb` or Wavefront OBJ.
+pub fn load_model(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mipmaps: &mut MipmapGenerator,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<Model> {
+    Ok(parse_model(file_name, &|_, _| {})?.upload(device, queue, mipmaps, layout))
 }
 
 pub fn load_obj(
     file_name: &str,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+    mipmaps: &mut MipmapGenerator,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<Model> {
+    Ok(parse_obj(file_name, &|_, _| {})?.upload(device, queue, mipmaps, layout))
+}
+
+/// Loads a `.gltf` (with external or embedded buffers and images) or `.glb` file.
+///
+/// Node transforms of the default scene are baked into the vertices, and every
+/// triangle primitive becomes its own `Mesh`.
+pub fn load_gltf(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mipmaps: &mut MipmapGenerator,
     layout: &wgpu::BindGroupLayout,
 ) -> anyhow::Result<Model> {
+    Ok(parse_gltf(file_name, &|_, _| {})?.upload(device, queue, mipmaps, layout))
+}
+
+/// CPU half of [`load_model`]. `on_progress(done, total)` is called from
+/// worker threads as images finish decoding.
+pub fn parse_model(file_name: &str, on_progress: &(dyn Fn(usize, usize) + Sync)) -> anyhow::Result<ModelData> {
+    let extension = Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("gltf") | Some("glb") => parse_gltf(file_name, on_progress),
+        _ => parse_obj(file_name, on_progress),
+    }
+}
+
+pub fn parse_obj(file_name: &str, on_progress: &(dyn Fn(usize, usize) + Sync)) -> anyhow::Result<ModelData> {
     let path = Path::new(file_name);
     
-    let obj = tobj::load_obj(&path, &tobj::GPU_LOAD_OPTIONS);
-    let (obj_models, obj_materials) = obj.expect("Failed to load OBJ file");
-    let obj_materials = obj_materials.expect("Failed to load MTL file");
-    
-    let mut materials: Vec<Material> = Vec::new();
-    obj_materials.into_iter().for_each(|m| {
-        let full_path = path.parent().unwrap().join(&m.diffuse_texture.unwrap());
-        info!("load material from {:?}", full_path);
-        let diffuse_texture = match texture::Texture::from_path(&full_path, device, queue, Some(&m.name)) {
-            Ok(v) => v,
-            Err(e) => {
-                warn!("Error while loading diffuse texture: {:?}, use default texture instead", e);
-                let img = image::DynamicImage::new_rgba8(1, 1);
-                texture::Texture::from_image(device, queue, &img, Some(&m.name)).unwrap()
+    let (obj_models, obj_materials) = tobj::load_obj(path, &tobj::GPU_LOAD_OPTIONS)
+        .with_context(|| format!("Failed to load OBJ file {:?}", path))?;
+    let obj_materials = obj_materials.unwrap_or_else(|e| {
+        warn!("Failed to load MTL file for {:?}: {}, use default material instead", path, e);
+        Vec::new()
+    });
+
+    // Every referenced image is decoded once, in parallel
+    let texture_keys = ["map_Pr", "map_Pm", "map_Ke"];
+    let mut image_paths: Vec<PathBuf> = Vec::new();
+    let mut image_index = |file_name: Option<&str>| {
+        let full_path = path.parent().unwrap_or(Path::new("")).join(file_name?);
+        Some(match image_paths.iter().position(|p| *p == full_path) {
+            Some(i) => i,
+            None => {
+                image_paths.push(full_path);
+                image_paths.len() - 1
             }
-        };
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
+        })
+    };
+    let material_images = obj_materials
+        .iter()
+        .map(|m| {
+            let [roughness, metallic, emissive] =
+                texture_keys.map(|key| image_index(m.unknown_param.get(key).map(String::as_str)));
+            [
+                image_index(m.diffuse_texture.as_deref()),
+                image_index(m.normal_texture.as_deref()),
+                roughness,
+                metallic,
+                emissive,
+            ]
+        })
+        .collect::<Vec<_>>();
+    let images = decode_images(&image_paths, on_progress);
+    let image = |i: Option<usize>| i.and_then(|i| images[i].clone());
+
+    let mut materials = obj_materials
+        .into_iter()
+        .zip(material_images)
+        .map(|(m, [diffuse, normal, roughness, metallic, emissive])| {
+            let mut uniform = obj_material_uniform(&m);
+            let diffuse = image(diffuse);
+            // MTL files can't flag alpha tested materials, so the texture decides
+            if let (AlphaMode::Opaque, Some(img)) = (uniform.alpha_mode(), &diffuse) {
+                uniform.set_alpha_mode(AlphaMode::from_texture(img));
+            }
+            MaterialData {
+                diffuse,
+                normal: image(normal),
+                metallic_roughness: pack_metallic_roughness(
+                    roughness.and_then(|i| images[i].as_ref()),
+                    metallic.and_then(|i| images[i].as_ref()),
+                ),
+                occlusion: None,
+                emissive: image(emissive),
+                uniform,
+                name: m.name,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Meshes without a (valid) material share a default one appended at the end
+    let mut default_material_idx = None;
+    let mut meshes = Vec::with_capacity(obj_models.len());
+    for m in obj_models {
+        let mut vertices = (0..m.mesh.positions.len() / 3)
+            .map(|i| ModelVertex {
+                position: [
+                    m.mesh.positions[i * 3],
+                    m.mesh.positions[i * 3 + 1],
+                    m.mesh.positions[i * 3 + 2],
+                ],
+                tex_coords: if m.mesh.texcoords.is_empty() {
+                    [0.0; 2]
+                } else {
+                    [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]]
                 },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
+                normal: if m.mesh.normals.is_empty() {
+                    [0.0, 1.0, 0.0]
+                } else {
+                    [
+                        m.mesh.normals[i * 3],
+                        m.mesh.normals[i * 3 + 1],
+                        m.mesh.normals[i * 3 + 2],
+                    ]
                 },
-            ],
-            label: None,
-        });
-        
-        materials.push(Material {
+                tangent: [0.0; 3],
+                bitangent: [0.0; 3],
+            })
+            .collect::<Vec<_>>();
+        compute_tangents(&mut vertices, &m.mesh.indices);
+
+        let material_idx = match m.mesh.material_id {
+            Some(id) if id < materials.len() => id,
+            _ => *default_material_idx.get_or_insert(materials.len()),
+        };
+        meshes.push(MeshData {
             name: m.name,
-            diffuse_texture,
-            bind_group,
+            vertices,
+            indices: m.mesh.indices,
+            material_idx,
         });
-    });
+    }
+    if default_material_idx.is_some() {
+        materials.push(MaterialData::default_material());
+    }
 
-    let meshes = obj_models
-        .into_iter()
+    Ok(ModelData { meshes, materials })
+
+}
+
+pub fn parse_gltf(file_name: &str, on_progress: &(dyn Fn(usize, usize) + Sync)) -> anyhow::Result<ModelData> {
+    // gltf::import decodes every image itself, so there is only one step to report
+    on_progress(0, 1);
+    let (document, buffers, images) = gltf::import(file_name)
+        .with_context(|| format!("Failed to load glTF file {:?}", file_name))?;
+    on_progress(1, 1);
+
+    let gltf_image = |texture: Option<gltf::Texture>| {
+        let texture = texture?;
+        match gltf_image_to_dynamic(&images[texture.source().index()]) {
+            Ok(img) => Some(img),
+            Err(e) => {
+                warn!("Error while loading texture: {:?}, use default texture instead", e);
+                None
+            }
+        }
+    };
+
+    let mut materials = document
+        .materials()
         .map(|m| {
-            let vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                    tangent: [0.0; 3],
-                    bitangent: [0.0; 3],
+            let pbr = m.pbr_metallic_roughness();
+            let mut uniform = MaterialUniform {
+                base_color_factor: pbr.base_color_factor(),
+                emissive_factor: m.emissive_factor(),
+                metallic_factor: pbr.metallic_factor(),
+                roughness_factor: pbr.roughness_factor(),
+                occlusion_strength: m.occlusion_texture().map_or(1.0, |t| t.strength()),
+                alpha_cutoff: m.alpha_cutoff().unwrap_or(0.5),
+                ..Default::default()
+            };
+            uniform.set_alpha_mode(match m.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+                gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+            });
+            MaterialData {
+                name: m.name().unwrap_or("material").to_string(),
+                diffuse: gltf_image(pbr.base_color_texture().map(|t| t.texture())),
+                normal: gltf_image(m.normal_texture().map(|t| t.texture())),
+                metallic_roughness: gltf_image(pbr.metallic_roughness_texture().map(|t| t.texture())),
+                occlusion: gltf_image(m.occlusion_texture().map(|t| t.texture())),
+                emissive: gltf_image(m.emissive_texture().map(|t| t.texture())),
+                uniform,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let scene = document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .ok_or_else(|| anyhow::anyhow!("glTF file {:?} contains no scene", file_name))?;
+
+    let mut default_material_idx = None;
+    let mut meshes = Vec::new();
+    let mut stack = scene
+        .nodes()
+        .map(|node| (node, cgmath::Matrix4::<f32>::identity()))
+        .collect::<Vec<_>>();
+    while let Some((node, parent_transform)) = stack.pop() {
+        let transform = parent_transform * cgmath::Matrix4::from(node.transform().matrix());
+        stack.extend(node.children().map(|child| (child, transform)));
+
+        let Some(mesh) = node.mesh() else {
+            continue;
+        };
+        let normal_matrix = {
+            let m = cgmath::Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate());
+            m.invert().map(|inv| inv.transpose()).unwrap_or(m)
+        };
+        // Mirroring transforms flip the winding order
+        let flip_winding = transform.determinant() < 0.0;
+
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                warn!("Skipping non-triangle primitive {:?} in {:?}", primitive.mode(), file_name);
+                continue;
+            }
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+            let positions = reader
+                .read_positions()
+                .ok_or_else(|| anyhow::anyhow!("Primitive without positions in {:?}", file_name))?
+                .collect::<Vec<_>>();
+            let normals = reader.read_normals().map(|n| n.collect::<Vec<_>>());
+            let tex_coords = reader
+                .read_tex_coords(gltf_tex_coord_set(&primitive.material(), file_name))
+                .map(|t| t.into_f32().collect::<Vec<_>>());
+            let tangents = reader.read_tangents().map(|t| t.collect::<Vec<_>>());
+            let mut indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<_>>(),
+                None => (0..positions.len() as u32).collect(),
+            };
+            if flip_winding {
+                for triangle in indices.chunks_exact_mut(3) {
+                    triangle.swap(1, 2);
+                }
+            }
+
+            let mut vertices = positions
+                .iter()
+                .enumerate()
+                .map(|(i, position)| {
+                    let position = transform * cgmath::Vector3::from(*position).extend(1.0);
+                    let normal = normals
+                        .as_ref()
+                        .map(|n| (normal_matrix * cgmath::Vector3::from(n[i])).normalize())
+                        .unwrap_or(cgmath::Vector3::unit_y());
+                    ModelVertex {
+                        position: position.truncate().into(),
+                        tex_coords: tex_coords.as_ref().map(|t| t[i]).unwrap_or([0.0; 2]),
+                        normal: normal.into(),
+                        tangent: [0.0; 3],
+                        bitangent: [0.0; 3],
+                    }
                 })
                 .collect::<Vec<_>>();
 
- 
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
+            match &tangents {
+                // glTF tangents carry the bitangent sign in w, which mirroring flips
+                Some(tangents) => {
+                    let handedness = if flip_winding { -1.0 } else { 1.0 };
+                    for (v, t) in vertices.iter_mut().zip(tangents) {
+                        let n = cgmath::Vector3::from(v.normal);
+                        let tangent = (transform * cgmath::Vector4::new(t[0], t[1], t[2], 0.0)).truncate().normalize();
+                        v.tangent = tangent.into();
+                        v.bitangent = (n.cross(tangent) * t[3] * handedness).into();
+                    }
+                }
+                None => compute_tangents(&mut vertices, &indices),
+            }
+
+            let material_idx = match primitive.material().index() {
+                Some(id) => id,
+                None => *default_material_idx.get_or_insert(materials.len()),
+            };
+            meshes.push(MeshData {
+                name: mesh.name().unwrap_or(file_name).to_string(),
+                vertices,
+                indices,
+                material_idx,
             });
+        }
+    }
+    if default_material_idx.is_some() {
+        materials.push(MaterialData::default_material());
+    }
 
-            Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material_idx: m.mesh.material_id.unwrap_or(0),
-            }
-        })
-        .collect::<Vec<_>>();
+    Ok(ModelData { meshes, materials })
+}
 
+/// The UV set the textures of `material` read from. Meshes only carry one, so
+/// textures that ask for another set end up sampling the base color's.
+fn gltf_tex_coord_set(material: &gltf::Material, file_name: &str) -> u32 {
+    let pbr = material.pbr_metallic_roughness();
+    let sets = [
+        pbr.base_color_texture().map(|t| t.tex_coord()),
+        material.normal_texture().map(|t| t.tex_coord()),
+        pbr.metallic_roughness_texture().map(|t| t.tex_coord()),
+        material.occlusion_texture().map(|t| t.tex_coord()),
+        material.emissive_texture().map(|t| t.tex_coord()),
+    ];
+    let mut sets = sets.into_iter().flatten();
+    let Some(set) = sets.next() else {
+        return 0;
+    };
+    if sets.any(|other| other != set) {
+        warn!(
+            "Material {:?} in {:?} uses several UV sets, all of its textures use set {}",
+            material.name().unwrap_or("material"),
+            file_name,
+            set
+        );
+    }
+    set
+}
 
-    Ok(Model { meshes, materials })
+fn gltf_image_to_dynamic(data: &gltf::image::Data) -> anyhow::Result<image::DynamicImage> {
+    use gltf::image::Format;
+    use image::{DynamicImage, ImageBuffer};
 
+    let (width, height) = (data.width, data.height);
+    let to_u16 = || {
+        data.pixels
+            .chunks_exact(2)
+            .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+            .collect::<Vec<_>>()
+    };
+    let to_f32 = || {
+        data.pixels
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>()
+    };
+    let img = match data.format {
+        Format::R8 => ImageBuffer::from_raw(width, height, data.pixels.clone()).map(DynamicImage::ImageLuma8),
+        Format::R8G8 => ImageBuffer::from_raw(width, height, data.pixels.clone()).map(DynamicImage::ImageLumaA8),
+        Format::R8G8B8 => ImageBuffer::from_raw(width, height, data.pixels.clone()).map(DynamicImage::ImageRgb8),
+        Format::R8G8B8A8 => ImageBuffer::from_raw(width, height, data.pixels.clone()).map(DynamicImage::ImageRgba8),
+        Format::R16 => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageLuma16),
+        Format::R16G16 => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageLumaA16),
+        Format::R16G16B16 => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageRgb16),
+        Format::R16G16B16A16 => ImageBuffer::from_raw(width, height, to_u16()).map(DynamicImage::ImageRgba16),
+        Format::R32G32B32FLOAT => ImageBuffer::from_raw(width, height, to_f32()).map(DynamicImage::ImageRgb32F),
+        Format::R32G32B32A32FLOAT => ImageBuffer::from_raw(width, height, to_f32()).map(DynamicImage::ImageRgba32F),
+    };
+    img.ok_or_else(|| anyhow::anyhow!("glTF image data does not match its {}x{} size", width, height))
 }
 
 pub trait DrawModel<'a> {
@@ -197,6 +1084,7 @@ pub trait DrawModel<'a> {
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
+    /// Skips blended meshes, those are sorted and drawn separately
     fn draw_model_instanced(
         &mut self,
         model: &'a Model,
@@ -204,6 +1092,17 @@ pub trait DrawModel<'a> {
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
+    /// Draws mesh `i` with the `DrawIndexedIndirect` arguments at index `i`
+    /// of `indirect_buffer` and the instances in `instances[i]`, skipping
+    /// blended meshes
+    fn draw_model_indirect(
+        &mut self,
+        model: &'a Model,
+        instances: &[wgpu::BufferSlice<'a>],
+        indirect_buffer: &'a wgpu::Buffer,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
 }
 
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
@@ -251,11 +1150,32 @@ where
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     ) {
-        for mesh in &model.meshes {
+        for mesh in model.meshes.iter().filter(|mesh| !model.is_blended(mesh)) {
             let material = &model.materials[mesh.material_idx];
             self.draw_mesh_instanced(mesh, material, instances.clone(), camera_bind_group, light_bind_group);
         }
     }
+
+    fn draw_model_indirect(
+        &mut self,
+        model: &'b Model,
+        instances: &[wgpu::BufferSlice<'b>],
+        indirect_buffer: &'b wgpu::Buffer,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    ) {
+        for (i, mesh) in model.meshes.iter().enumerate().filter(|(_, mesh)| !model.is_blended(mesh)) {
+            let material = &model.materials[mesh.material_idx];
+            self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            self.set_vertex_buffer(1, instances[i]);
+            self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            self.set_bind_group(0, &material.bind_group, &[]);
+            self.set_bind_group(1, camera_bind_group, &[]);
+            self.set_bind_group(2, light_bind_group, &[]);
+            let offset = i * std::mem::size_of::<wgpu::util::DrawIndexedIndirect>();
+            self.draw_indexed_indirect(indirect_buffer, offset as wgpu::BufferAddress);
+        }
+    }
 }
 
 
@@ -340,3 +1260,35 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_alpha(alpha: impl Fn(u32) -> u8) -> image::DynamicImage {
+        image::RgbaImage::from_fn(16, 16, |x, y| image::Rgba([255, 255, 255, alpha(x + y * 16)])).into()
+    }
+
+    #[test]
+    fn alpha_mode_follows_the_texture() {
+        let rgb = image::RgbImage::new(4, 4).into();
+        assert_eq!(AlphaMode::from_texture(&rgb), AlphaMode::Opaque);
+        assert_eq!(AlphaMode::from_texture(&with_alpha(|_| 255)), AlphaMode::Opaque);
+        // Cut out with a few filtered texels along the edges
+        let cutout = with_alpha(|i| match i % 16 {
+            0..=7 => 255,
+            8 => 128,
+            _ => 0,
+        });
+        assert_eq!(AlphaMode::from_texture(&cutout), AlphaMode::Mask);
+        assert_eq!(AlphaMode::from_texture(&with_alpha(|_| 90)), AlphaMode::Blend);
+    }
+
+    #[test]
+    fn faint_glass_is_blended() {
+        assert_eq!(AlphaMode::from_texture(&with_alpha(|_| 240)), AlphaMode::Blend);
+        // Barely varying glass, none of it close enough to opaque
+        assert_eq!(AlphaMode::from_texture(&with_alpha(|i| 235 + (i % 8) as u8)), AlphaMode::Blend);
+        assert_eq!(AlphaMode::from_texture(&with_alpha(|i| 250 + (i % 6) as u8)), AlphaMode::Opaque);
+    }
+}
@@ -0,0 +1,897 @@
+//! Post-processing of the rendered frame.
+//!
+//! The scene is drawn into the HDR texture of [`PostProcessing::view`], then
+//! every enabled effect of [`PostProcessing::effects`] runs in order as a
+//! fullscreen pass reading the result of the one before. Intermediate results
+//! ping-pong between two textures and the last pass writes the frame. Effects
+//! can be added, removed, reordered and tweaked between frames.
+//!
+//! All passes share their bind group layouts: group 0 holds the input texture
+//! and a linear sampler, group 1 the settings of the effect as a `vec4<f32>`
+//! and group 2 extra inputs like the blurred bloom texture or a LUT.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+use serde::Deserialize;
+use tracing::error;
+
+use crate::hdr::{Tonemapping, HDR_FORMAT};
+use crate::quad::Quad;
+
+/// Blurs what is brighter than `threshold` over its surroundings, belongs
+/// before tonemapping
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bloom {
+    pub threshold: f32,
+    pub intensity: f32,
+}
+
+impl Default for Bloom {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            intensity: 0.5,
+        }
+    }
+}
+
+/// Darkens the corners
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Vignette {
+    /// 1 turns the corners black
+    pub intensity: f32,
+    /// Where the darkening starts, 0 in the center and 1 in the corners
+    pub radius: f32,
+}
+
+impl Default for Vignette {
+    fn default() -> Self {
+        Self {
+            intensity: 0.5,
+            radius: 0.5,
+        }
+    }
+}
+
+/// Remaps colors with a lookup table
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColorGrading {
+    /// Image with N slices of N x N texels side by side, red grows to the
+    /// right, green down and blue from slice to slice. Both the colors it is
+    /// looked up with and the ones it holds are sRGB encoded, like in a
+    /// screenshot. The identity LUT when left out.
+    pub lut: Option<PathBuf>,
+    /// 0 keeps the original colors, 1 uses the LUT colors
+    pub strength: f32,
+}
+
+impl Default for ColorGrading {
+    fn default() -> Self {
+        Self {
+            lut: None,
+            strength: 1.0,
+        }
+    }
+}
+
+/// Splits the color channels towards the edges like a cheap lens
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChromaticAberration {
+    /// Offset of the red and blue channels in the corners, as a fraction of
+    /// the frame size
+    pub strength: f32,
+}
+
+impl Default for ChromaticAberration {
+    fn default() -> Self {
+        Self { strength: 0.01 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Effect {
+    Bloom(Bloom),
+    Tonemap(Tonemapping),
+    /// Fast approximate anti-aliasing, belongs after tonemapping
+    Fxaa,
+    Vignette(Vignette),
+    ColorGrading(ColorGrading),
+    ChromaticAberration(ChromaticAberration),
+}
+
+impl Effect {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Bloom(_) => "bloom",
+            Effect::Tonemap(_) => "tonemap",
+            Effect::Fxaa => "fxaa",
+            Effect::Vignette(_) => "vignette",
+            Effect::ColorGrading(_) => "color_grading",
+            Effect::ChromaticAberration(_) => "chromatic_aberration",
+        }
+    }
+
+    /// The `params` uniform of post.wgsl and hdr.wgsl
+    fn params(&self) -> [f32; 4] {
+        match self {
+            Effect::Bloom(bloom) => [bloom.threshold, bloom.intensity, 0.0, 0.0],
+            Effect::Tonemap(tonemapping) => [
+                tonemapping.exposure.exp2(),
+                tonemapping.operator as u32 as f32,
+                0.0,
+                0.0,
+            ],
+            Effect::Fxaa => [0.0; 4],
+            Effect::Vignette(vignette) => [vignette.intensity, vignette.radius, 0.0, 0.0],
+            Effect::ColorGrading(grading) => [grading.strength, 0.0, 0.0, 0.0],
+            Effect::ChromaticAberration(aberration) => [aberration.strength, 0.0, 0.0, 0.0],
+        }
+    }
+
+    /// Checks the settings, naming the broken one in the error
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let params = self.params();
+        if params.iter().any(|p| !p.is_finite()) {
+            bail!("{} has a setting that is not a finite number: {:?}", self.name(), self);
+        }
+        if let Effect::Bloom(bloom) = self {
+            if bloom.threshold <= 0.0 {
+                bail!("bloom threshold must be positive, got {}", bloom.threshold);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An entry of the post-processing chain
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct PostEffect {
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub effect: Effect,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl PostEffect {
+    pub fn new(effect: Effect) -> Self {
+        Self { enabled: true, effect }
+    }
+
+    pub fn disabled(effect: Effect) -> Self {
+        Self { enabled: false, effect }
+    }
+}
+
+/// Every effect in a sensible order, only tonemapping is enabled
+pub fn default_effects() -> Vec<PostEffect> {
+    vec![
+        PostEffect::disabled(Effect::Bloom(Bloom::default())),
+        PostEffect::new(Effect::Tonemap(Tonemapping::default())),
+        PostEffect::disabled(Effect::ColorGrading(ColorGrading::default())),
+        PostEffect::disabled(Effect::Fxaa),
+        PostEffect::disabled(Effect::ChromaticAberration(ChromaticAberration::default())),
+        PostEffect::disabled(Effect::Vignette(Vignette::default())),
+    ]
+}
+
+/// One fullscreen pass, effects are made of one or more of them
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Stage {
+    /// Copies the input clamped to 0..1, for when no effect is enabled.
+    /// The HDR scene then shows without tonemapping.
+    Copy,
+    BloomExtract,
+    BlurHorizontal,
+    BlurVertical,
+    BloomComposite,
+    Tonemap,
+    Fxaa,
+    Vignette,
+    ColorGrading,
+    ChromaticAberration,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum ExtraInput {
+    None,
+    Bloom,
+    Lut,
+}
+
+impl Stage {
+    const ALL: [Stage; 10] = [
+        Stage::Copy,
+        Stage::BloomExtract,
+        Stage::BlurHorizontal,
+        Stage::BlurVertical,
+        Stage::BloomComposite,
+        Stage::Tonemap,
+        Stage::Fxaa,
+        Stage::Vignette,
+        Stage::ColorGrading,
+        Stage::ChromaticAberration,
+    ];
+
+    fn entry_point(self) -> &'static str {
+        match self {
+            Stage::Copy => "fs_copy",
+            Stage::BloomExtract => "fs_bloom_extract",
+            Stage::BlurHorizontal => "fs_blur_horizontal",
+            Stage::BlurVertical => "fs_blur_vertical",
+            Stage::BloomComposite => "fs_bloom_composite",
+            Stage::Tonemap => "fs_main",
+            Stage::Fxaa => "fs_fxaa",
+            Stage::Vignette => "fs_vignette",
+            Stage::ColorGrading => "fs_color_grading",
+            Stage::ChromaticAberration => "fs_chromatic_aberration",
+        }
+    }
+
+    fn extra_input(self) -> ExtraInput {
+        match self {
+            Stage::BloomComposite => ExtraInput::Bloom,
+            Stage::ColorGrading => ExtraInput::Lut,
+            _ => ExtraInput::None,
+        }
+    }
+
+    /// Whether the stage can be the last one, writing the frame
+    fn can_be_last(self) -> bool {
+        !matches!(self, Stage::BloomExtract | Stage::BlurHorizontal | Stage::BlurVertical)
+    }
+}
+
+struct StagePipelines {
+    /// Renders into another HDR texture
+    intermediate: wgpu::RenderPipeline,
+    /// Renders into the frame
+    output: Option<wgpu::RenderPipeline>,
+}
+
+/// A texture passes can render into and read from
+struct Target {
+    // Kept alive for the view
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    /// Group 0 with this texture as the input
+    bind_group: wgpu::BindGroup,
+}
+
+/// Where the output of the previous pass is
+#[derive(Copy, Clone)]
+enum Input {
+    Scene,
+    Ping(usize),
+}
+
+pub struct PostProcessing {
+    /// Applied in order, the first one reads the scene. Without an enabled
+    /// tonemapping effect the HDR colors are only clamped, not tonemapped.
+    pub effects: Vec<PostEffect>,
+
+    input_layout: wgpu::BindGroupLayout,
+    params_layout: wgpu::BindGroupLayout,
+    bloom_layout: wgpu::BindGroupLayout,
+    lut_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    quad: Quad,
+    pipelines: HashMap<Stage, StagePipelines>,
+
+    scene: Target,
+    ping: [Target; 2],
+    /// Half resolution, the brightest parts end up blurred in the first one
+    bloom: [Target; 2],
+    /// Group 2 of the bloom composite
+    bloom_bind_group: wgpu::BindGroup,
+
+    /// The params of every effect, `params_stride` bytes apart
+    params_buffer: wgpu::Buffer,
+    params_bind_group: wgpu::BindGroup,
+    params_stride: u64,
+    /// Group 2 of color grading per LUT file, `None` is the identity LUT
+    luts: HashMap<Option<PathBuf>, wgpu::BindGroup>,
+}
+
+impl PostProcessing {
+    /// `output_format` is the format of the frames passed to `process`
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        output_format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        effects: Vec<PostEffect>,
+    ) -> Self {
+        let texture_entry = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let input_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post Input Layout"),
+            entries: &[
+                texture_entry(0, wgpu::TextureViewDimension::D2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post Params Layout"),
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(PARAMS_SIZE),
+                },
+                count: None,
+            }],
+        });
+        let bloom_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post Bloom Layout"),
+            entries: &[texture_entry(0, wgpu::TextureViewDimension::D2)],
+        });
+        let lut_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Post Lut Layout"),
+            entries: &[texture_entry(1, wgpu::TextureViewDimension::D3)],
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Post Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let post_shader = device.create_shader_module(wgpu::include_wgsl!("post.wgsl"));
+        let tonemap_shader = device.create_shader_module(wgpu::include_wgsl!("hdr.wgsl"));
+        let pipeline_layout = |label, extra: Option<&wgpu::BindGroupLayout>| {
+            let mut bind_group_layouts = vec![&input_layout, &params_layout];
+            bind_group_layouts.extend(extra);
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &bind_group_layouts,
+                push_constant_ranges: &[],
+            })
+        };
+        let plain_layout = pipeline_layout("Post Pipeline Layout", None);
+        let bloom_pipeline_layout = pipeline_layout("Post Bloom Pipeline Layout", Some(&bloom_layout));
+        let lut_pipeline_layout = pipeline_layout("Post Lut Pipeline Layout", Some(&lut_layout));
+
+        let pipelines = Stage::ALL
+            .into_iter()
+            .map(|stage| {
+                let layout = match stage.extra_input() {
+                    ExtraInput::None => &plain_layout,
+                    ExtraInput::Bloom => &bloom_pipeline_layout,
+                    ExtraInput::Lut => &lut_pipeline_layout,
+                };
+                let shader = if stage == Stage::Tonemap { &tonemap_shader } else { &post_shader };
+                let create = |format| create_pipeline(device, layout, shader, stage, format);
+                let pipelines = StagePipelines {
+                    intermediate: create(HDR_FORMAT),
+                    output: stage.can_be_last().then(|| create(output_format)),
+                };
+                (stage, pipelines)
+            })
+            .collect();
+
+        let scene = create_target(device, &input_layout, &sampler, width, height, "Scene Target");
+        let ping = create_ping_targets(device, &input_layout, &sampler, width, height);
+        let bloom = create_bloom_targets(device, &input_layout, &sampler, width, height);
+        let bloom_bind_group = create_bloom_bind_group(device, &bloom_layout, &bloom[0]);
+
+        let params_stride = (device.limits().min_uniform_buffer_offset_alignment as u64).max(PARAMS_SIZE);
+        let (params_buffer, params_bind_group) = create_params(device, &params_layout, params_stride, effects.len());
+
+        let mut luts = HashMap::new();
+        luts.insert(None, create_lut_bind_group(device, queue, &lut_layout, IDENTITY_LUT_SIZE, &identity_lut(IDENTITY_LUT_SIZE)));
+
+        let mut post = Self {
+            effects,
+            input_layout,
+            params_layout,
+            bloom_layout,
+            lut_layout,
+            sampler,
+            quad: Quad::fullscreen(device),
+            pipelines,
+            scene,
+            ping,
+            bloom,
+            bloom_bind_group,
+            params_buffer,
+            params_bind_group,
+            params_stride,
+            luts,
+        };
+        post.update(device, queue);
+        post
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        self.scene = create_target(device, &self.input_layout, &self.sampler, width, height, "Scene Target");
+        self.ping = create_ping_targets(device, &self.input_layout, &self.sampler, width, height);
+        self.bloom = create_bloom_targets(device, &self.input_layout, &self.sampler, width, height);
+        self.bloom_bind_group = create_bloom_bind_group(device, &self.bloom_layout, &self.bloom[0]);
+    }
+
+    /// The HDR texture to draw the scene into
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.scene.view
+    }
+
+    /// The first effect called `name`
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut PostEffect> {
+        self.effects.iter_mut().find(|effect| effect.effect.name() == name)
+    }
+
+    /// Settings of the first tonemap effect
+    pub fn tonemapping_mut(&mut self) -> Option<&mut Tonemapping> {
+        self.effects.iter_mut().find_map(|effect| match &mut effect.effect {
+            Effect::Tonemap(tonemapping) => Some(tonemapping),
+            _ => None,
+        })
+    }
+
+    /// Uploads the settings of the effects and loads LUTs they refer to
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let capacity = (self.params_buffer.size() / self.params_stride) as usize;
+        if self.effects.len() > capacity {
+            (self.params_buffer, self.params_bind_group) =
+                create_params(device, &self.params_layout, self.params_stride, self.effects.len());
+        }
+        for (i, effect) in self.effects.iter().enumerate() {
+            queue.write_buffer(
+                &self.params_buffer,
+                i as u64 * self.params_stride,
+                bytemuck::cast_slice(&effect.effect.params()),
+            );
+        }
+
+        for effect in &self.effects {
+            let Effect::ColorGrading(ColorGrading { lut: Some(path), .. }) = &effect.effect else {
+                continue;
+            };
+            if self.luts.contains_key(&Some(path.clone())) {
+                continue;
+            }
+            // Failed LUTs fall back to the identity and are not retried
+            let (size, data) = load_lut(path).unwrap_or_else(|e| {
+                error!("Failed to load LUT {:?}: {:#}", path, e);
+                (IDENTITY_LUT_SIZE, identity_lut(IDENTITY_LUT_SIZE))
+            });
+            let bind_group = create_lut_bind_group(device, queue, &self.lut_layout, size, &data);
+            self.luts.insert(Some(path.clone()), bind_group);
+        }
+    }
+
+    /// Runs the enabled effects on the scene texture and writes the result
+    /// into `output`, overwriting all of it
+    pub fn process(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        let enabled = self
+            .effects
+            .iter()
+            .enumerate()
+            .filter(|(_, effect)| effect.enabled)
+            .collect::<Vec<_>>();
+        if enabled.is_empty() {
+            self.run(encoder, Stage::Copy, Input::Scene, 0, None, output, true);
+            return;
+        }
+
+        let mut input = Input::Scene;
+        for (n, &(slot, effect)) in enabled.iter().enumerate() {
+            let last = n + 1 == enabled.len();
+            let ping = n % 2;
+            let target = if last { output } else { &self.ping[ping].view };
+            match &effect.effect {
+                Effect::Bloom(_) => {
+                    let [first, second] = &self.bloom;
+                    self.run(encoder, Stage::BloomExtract, input, slot, None, &first.view, false);
+                    self.run_on(encoder, Stage::BlurHorizontal, &first.bind_group, slot, None, &second.view, false);
+                    self.run_on(encoder, Stage::BlurVertical, &second.bind_group, slot, None, &first.view, false);
+                    let extra = Some(&self.bloom_bind_group);
+                    self.run(encoder, Stage::BloomComposite, input, slot, extra, target, last);
+                }
+                Effect::Tonemap(_) => self.run(encoder, Stage::Tonemap, input, slot, None, target, last),
+                Effect::Fxaa => self.run(encoder, Stage::Fxaa, input, slot, None, target, last),
+                Effect::Vignette(_) => self.run(encoder, Stage::Vignette, input, slot, None, target, last),
+                Effect::ColorGrading(grading) => {
+                    let lut = self.luts.get(&grading.lut).unwrap_or(&self.luts[&None]);
+                    self.run(encoder, Stage::ColorGrading, input, slot, Some(lut), target, last);
+                }
+                Effect::ChromaticAberration(_) => {
+                    self.run(encoder, Stage::ChromaticAberration, input, slot, None, target, last)
+                }
+            }
+            input = Input::Ping(ping);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn run(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        stage: Stage,
+        input: Input,
+        slot: usize,
+        extra: Option<&wgpu::BindGroup>,
+        target: &wgpu::TextureView,
+        last: bool,
+    ) {
+        let input = match input {
+            Input::Scene => &self.scene.bind_group,
+            Input::Ping(i) => &self.ping[i].bind_group,
+        };
+        self.run_on(encoder, stage, input, slot, extra, target, last);
+    }
+
+    /// Draws one `stage` reading the texture of `input` and the params in
+    /// `slot` into `target`, which is the frame when `last` is set
+    #[allow(clippy::too_many_arguments)]
+    fn run_on(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        stage: Stage,
+        input: &wgpu::BindGroup,
+        slot: usize,
+        extra: Option<&wgpu::BindGroup>,
+        target: &wgpu::TextureView,
+        last: bool,
+    ) {
+        let pipelines = &self.pipelines[&stage];
+        let pipeline = match &pipelines.output {
+            Some(output) if last => output,
+            _ => &pipelines.intermediate,
+        };
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(stage.entry_point()),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, input, &[]);
+        let offset = (slot as u64 * self.params_stride) as wgpu::DynamicOffset;
+        render_pass.set_bind_group(1, &self.params_bind_group, &[offset]);
+        if let Some(extra) = extra {
+            render_pass.set_bind_group(2, extra, &[]);
+        }
+        self.quad.draw(&mut render_pass);
+    }
+}
+
+/// A `vec4<f32>`
+const PARAMS_SIZE: u64 = 16;
+const IDENTITY_LUT_SIZE: u32 = 16;
+
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    stage: Stage,
+    format: wgpu::TextureFormat,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(stage.entry_point()),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[Quad::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: stage.entry_point(),
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: None,
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
+
+fn create_target(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    width: u32,
+    height: u32,
+    label: &str,
+) -> Target {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: HDR_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some(label),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+    });
+    Target {
+        _texture: texture,
+        view,
+        bind_group,
+    }
+}
+
+fn create_ping_targets(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    width: u32,
+    height: u32,
+) -> [Target; 2] {
+    ["Post Target 0", "Post Target 1"].map(|label| create_target(device, layout, sampler, width, height, label))
+}
+
+fn create_bloom_targets(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    width: u32,
+    height: u32,
+) -> [Target; 2] {
+    let (width, height) = (width.div_ceil(2), height.div_ceil(2));
+    ["Bloom Target 0", "Bloom Target 1"].map(|label| create_target(device, layout, sampler, width, height, label))
+}
+
+fn create_bloom_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, bloom: &Target) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("post.bloom_bind_group"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::TextureView(&bloom.view),
+        }],
+    })
+}
+
+fn create_params(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    stride: u64,
+    count: usize,
+) -> (wgpu::Buffer, wgpu::BindGroup) {
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Post Params Buffer"),
+        size: stride * count.max(1) as u64,
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("post.params_bind_group"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer: &buffer,
+                offset: 0,
+                size: wgpu::BufferSize::new(PARAMS_SIZE),
+            }),
+        }],
+    });
+    (buffer, bind_group)
+}
+
+/// RGBA texels of a `size`³ LUT that maps every color onto itself
+fn identity_lut(size: u32) -> Vec<u8> {
+    let level = |i: u32| (i * 255 / (size - 1)) as u8;
+    let mut data = Vec::with_capacity((size * size * size * 4) as usize);
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                data.extend_from_slice(&[level(r), level(g), level(b), 255]);
+            }
+        }
+    }
+    data
+}
+
+/// Rearranges a LUT strip, see [`ColorGrading::lut`], into the texels of a
+/// 3D texture and returns them with the size of the LUT
+fn lut_from_strip(strip: &image::RgbaImage) -> anyhow::Result<(u32, Vec<u8>)> {
+    let size = strip.height();
+    if size < 2 || strip.width() != size * size {
+        bail!(
+            "expected a LUT strip of N slices of N x N texels, got {}x{}",
+            strip.width(),
+            strip.height()
+        );
+    }
+    let mut data = Vec::with_capacity((size * size * size * 4) as usize);
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                data.extend_from_slice(&strip.get_pixel(b * size + r, g).0);
+            }
+        }
+    }
+    Ok((size, data))
+}
+
+fn load_lut(path: &Path) -> anyhow::Result<(u32, Vec<u8>)> {
+    lut_from_strip(&image::open(path)?.to_rgba8())
+}
+
+fn create_lut_bind_group(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    size: u32,
+    data: &[u8],
+) -> wgpu::BindGroup {
+    let extent = wgpu::Extent3d {
+        width: size,
+        height: size,
+        depth_or_array_layers: size,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Lut Texture"),
+        size: extent,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            aspect: wgpu::TextureAspect::All,
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+        },
+        data,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * size),
+            rows_per_image: Some(size),
+        },
+        extent,
+    );
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("post.lut_bind_group"),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 1,
+            resource: wgpu::BindingResource::TextureView(&view),
+        }],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_strip_matches_identity_lut() {
+        let size = 4;
+        let level = |i: u32| (i * 255 / (size - 1)) as u8;
+        let strip = image::RgbaImage::from_fn(size * size, size, |x, y| {
+            image::Rgba([level(x % size), level(y), level(x / size), 255])
+        });
+        let (lut_size, data) = lut_from_strip(&strip).unwrap();
+        assert_eq!(lut_size, size);
+        assert_eq!(data, identity_lut(size));
+    }
+
+    #[test]
+    fn non_identity_strip_keeps_its_axes() {
+        // Inverts red and copies blue into green, so every axis shows up
+        let size = 4;
+        let level = |i: u32| (i * 255 / (size - 1)) as u8;
+        let strip = image::RgbaImage::from_fn(size * size, size, |x, _| {
+            let (r, b) = (x % size, x / size);
+            image::Rgba([255 - level(r), level(b), level(b), 255])
+        });
+        let (lut_size, data) = lut_from_strip(&strip).unwrap();
+        assert_eq!(lut_size, size);
+        let texel = |r: u32, g: u32, b: u32| {
+            let i = (((b * size + g) * size + r) * 4) as usize;
+            &data[i..i + 4]
+        };
+        assert_eq!(texel(0, 0, 0), [255, 0, 0, 255]);
+        assert_eq!(texel(3, 2, 0), [0, 0, 0, 255]);
+        assert_eq!(texel(1, 3, 2), [170, 170, 170, 255]);
+        assert_eq!(texel(0, 1, 3), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn effects_parse_from_toml() {
+        #[derive(Deserialize)]
+        struct Chain {
+            effect: Vec<PostEffect>,
+        }
+        let chain: Chain = toml::from_str(
+            r#"
+            [[effect]]
+            kind = "bloom"
+            threshold = 2.0
+            [[effect]]
+            kind = "tonemap"
+            operator = "filmic"
+            [[effect]]
+            kind = "fxaa"
+            enabled = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            chain.effect,
+            [
+                PostEffect::new(Effect::Bloom(Bloom {
+                    threshold: 2.0,
+                    ..Bloom::default()
+                })),
+                PostEffect::new(Effect::Tonemap(Tonemapping {
+                    operator: crate::hdr::Tonemapper::Filmic,
+                    ..Tonemapping::default()
+                })),
+                PostEffect::disabled(Effect::Fxaa),
+            ]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+// Post-processing effects, see post.rs. Every fragment entry point is one
+// fullscreen pass reading the result of the previous pass from t_input.
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = in.tex_coords;
+    out.clip_position = vec4<f32>(in.position, 1.0);
+    return out;
+}
+
+@group(0) @binding(0)
+var t_input: texture_2d<f32>;
+@group(0) @binding(1)
+var s_input: sampler;
+// The settings of the effect, what each component means depends on it
+@group(1) @binding(0)
+var<uniform> params: vec4<f32>;
+// Only bound for the bloom composite
+@group(2) @binding(0)
+var t_bloom: texture_2d<f32>;
+// Only bound for color grading
+@group(2) @binding(1)
+var t_lut: texture_3d<f32>;
+
+fn input(uv: vec2<f32>) -> vec3<f32> {
+    return textureSample(t_input, s_input, uv).rgb;
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let c = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+    return select(1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3<f32>(0.0031308));
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    return select(pow((color + 0.055) / 1.055, vec3<f32>(2.4)), color / 12.92, color <= vec3<f32>(0.04045));
+}
+
+// Only runs without any enabled effect, the clamp stands in for the missing
+// tonemapping on float surfaces
+@fragment
+fn fs_copy(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(clamp(input(in.tex_coords), vec3<f32>(0.0), vec3<f32>(1.0)), 1.0);
+}
+
+// x = threshold. Keeps what is brighter than the threshold, with a soft
+// knee so the cut off doesn't show.
+@fragment
+fn fs_bloom_extract(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = input(in.tex_coords);
+    let brightness = max(color.r, max(color.g, color.b));
+    let threshold = params.x;
+    let knee = threshold * 0.5;
+    let soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+    let contribution = max(soft * soft / (4.0 * knee + 1e-4), brightness - threshold);
+    return vec4<f32>(color * contribution / max(brightness, 1e-4), 1.0);
+}
+
+// 9 tap gaussian, folded into 5 taps with bilinear filtering
+fn blur(uv: vec2<f32>, direction: vec2<f32>) -> vec4<f32> {
+    let texel = direction / vec2<f32>(textureDimensions(t_input));
+    let near = texel * 1.3846153846;
+    let far = texel * 3.2307692308;
+    var color = input(uv) * 0.2270270270;
+    color += (input(uv + near) + input(uv - near)) * 0.3162162162;
+    color += (input(uv + far) + input(uv - far)) * 0.0702702703;
+    return vec4<f32>(color, 1.0);
+}
+
+@fragment
+fn fs_blur_horizontal(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.tex_coords, vec2<f32>(1.0, 0.0));
+}
+
+@fragment
+fn fs_blur_vertical(in: VertexOutput) -> @location(0) vec4<f32> {
+    return blur(in.tex_coords, vec2<f32>(0.0, 1.0));
+}
+
+// y = intensity
+@fragment
+fn fs_bloom_composite(in: VertexOutput) -> @location(0) vec4<f32> {
+    let bloom = textureSample(t_bloom, s_input, in.tex_coords).rgb;
+    return vec4<f32>(input(in.tex_coords) + bloom * params.y, 1.0);
+}
+
+// Roughly perceptual luma of a linear color
+fn luma(color: vec3<f32>) -> f32 {
+    return dot(sqrt(max(color, vec3<f32>(0.0))), vec3<f32>(0.299, 0.587, 0.114));
+}
+
+// The compact FXAA from Timothy Lottes' original paper: blurs along the edge
+// direction found from the luma of the diagonal neighbours
+@fragment
+fn fs_fxaa(in: VertexOutput) -> @location(0) vec4<f32> {
+    let reduce_min = 1.0 / 128.0;
+    let reduce_mul = 1.0 / 8.0;
+    let span_max = 8.0;
+
+    let uv = in.tex_coords;
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_input));
+    let luma_nw = luma(input(uv + vec2<f32>(-1.0, -1.0) * texel));
+    let luma_ne = luma(input(uv + vec2<f32>(1.0, -1.0) * texel));
+    let luma_sw = luma(input(uv + vec2<f32>(-1.0, 1.0) * texel));
+    let luma_se = luma(input(uv + vec2<f32>(1.0, 1.0) * texel));
+    let luma_m = luma(input(uv));
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    var direction = vec2<f32>(
+        (luma_sw + luma_se) - (luma_nw + luma_ne),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * reduce_mul, reduce_min);
+    let scale = 1.0 / (min(abs(direction.x), abs(direction.y)) + reduce);
+    direction = clamp(direction * scale, vec2<f32>(-span_max), vec2<f32>(span_max)) * texel;
+
+    let a = 0.5 * (input(uv + direction * (1.0 / 3.0 - 0.5)) + input(uv + direction * (2.0 / 3.0 - 0.5)));
+    let b = a * 0.5 + 0.25 * (input(uv - direction * 0.5) + input(uv + direction * 0.5));
+    let luma_b = luma(b);
+    // The wider blur is only safe when it didn't pick up a different edge
+    let color = select(b, a, luma_b < luma_min || luma_b > luma_max);
+    return vec4<f32>(color, 1.0);
+}
+
+// x = intensity, y = radius where darkening starts, 1 reaches the corners
+@fragment
+fn fs_vignette(in: VertexOutput) -> @location(0) vec4<f32> {
+    let distance = length(in.tex_coords - vec2<f32>(0.5)) * sqrt(2.0);
+    let darkening = params.x * smoothstep(params.y, 1.0, distance);
+    return vec4<f32>(input(in.tex_coords) * (1.0 - darkening), 1.0);
+}
+
+// x = strength. LUTs are authored for gamma encoded colors.
+@fragment
+fn fs_color_grading(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = input(in.tex_coords);
+    // LUTs are made on sRGB images, so they are looked up and interpolated
+    // in sRGB
+    let encoded = linear_to_srgb(color);
+    // Sample the centers of the outer texels at 0 and 1
+    let size = f32(textureDimensions(t_lut).x);
+    let coords = (encoded * (size - 1.0) + 0.5) / size;
+    let graded = srgb_to_linear(textureSample(t_lut, s_input, coords).rgb);
+    return vec4<f32>(mix(color, graded, params.x), 1.0);
+}
+
+// x = strength, how far the red and blue channels move apart at the edges
+@fragment
+fn fs_chromatic_aberration(in: VertexOutput) -> @location(0) vec4<f32> {
+    let offset = (in.tex_coords - vec2<f32>(0.5)) * params.x;
+    let r = input(in.tex_coords + offset).r;
+    let g = input(in.tex_coords).g;
+    let b = input(in.tex_coords - offset).b;
+    return vec4<f32>(r, g, b, 1.0);
+}
@@ -0,0 +1,94 @@
+//! Screen aligned quads for passes that draw a texture over the frame, like
+//! the depth overlay and the HDR tonemapping.
+//!
+//! Shaders take the position at location 0 and the texture coordinates at
+//! location 1, the position is already in clip space.
+
+use wgpu::util::DeviceExt;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct Vertex {
+    position: [f32; 3],
+    tex_coords: [f32; 2],
+}
+
+const INDICES: &[u16] = &[0, 1, 2, 0, 2, 3];
+
+pub struct Quad {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    num_indices: u32,
+}
+
+impl Quad {
+    /// Covers `min` to `max` in normalized device coordinates, the texture
+    /// coordinates span the whole texture
+    pub fn new(device: &wgpu::Device, min: [f32; 2], max: [f32; 2]) -> Self {
+        let vertices = [
+            Vertex {
+                position: [min[0], min[1], 0.0],
+                tex_coords: [0.0, 1.0],
+            },
+            Vertex {
+                position: [max[0], min[1], 0.0],
+                tex_coords: [1.0, 1.0],
+            },
+            Vertex {
+                position: [max[0], max[1], 0.0],
+                tex_coords: [1.0, 0.0],
+            },
+            Vertex {
+                position: [min[0], max[1], 0.0],
+                tex_coords: [0.0, 0.0],
+            },
+        ];
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Quad VB"),
+            contents: bytemuck::cast_slice(&vertices),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Quad IB"),
+            contents: bytemuck::cast_slice(INDICES),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        Self {
+            vertex_buffer,
+            index_buffer,
+            num_indices: INDICES.len() as u32,
+        }
+    }
+
+    pub fn fullscreen(device: &wgpu::Device) -> Self {
+        Self::new(device, [-1.0, -1.0], [1.0, 1.0])
+    }
+
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+            ],
+        }
+    }
+
+    /// Draws the quad with whatever pipeline and bind groups are set
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+        render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
+    }
+}
@@ -0,0 +1,1010 @@
+use std::ops::Range;
+
+use cgmath::{EuclideanSpace, InnerSpace};
+use tracing::{error, info, warn};
+
+use crate::asset_loader::{AssetHandle, AssetLoader};
+use crate::bounds::{Aabb, BoundingSphere};
+use crate::camera::{self, CameraUniform};
+use crate::compute_shadow::ShadowPass;
+use crate::culling::{self, CulledInstances, CullingPass};
+use crate::depth_pass::{DebugSettings, DepthPass, DEBUG_FORMAT};
+use crate::environment::Environment;
+use crate::hdr::HDR_FORMAT;
+use crate::instance::{InstanceBuffer, InstanceRaw};
+use crate::light::LightList;
+use crate::mipmap::MipmapGenerator;
+use crate::model::{self, DrawLight, DrawModel, Vertex};
+use crate::post::PostProcessing;
+use crate::scene::SceneDescription;
+use crate::scene_graph::{ModelId, SceneGraph};
+use crate::ssao::SsaoPass;
+use crate::texture;
+use crate::create_render_pipeline;
+
+/// Everything needed to draw a frame, independent of where the frame ends up.
+///
+/// `State` drives it with the swapchain texture of a window, while
+/// [`Renderer::headless`] + [`Renderer::render_to_image`] render into an
+/// offscreen texture without needing a display.
+pub struct Renderer {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    /// Samples per pixel of the main pass, 1 without MSAA
+    sample_count: u32,
+    /// Multisampled color target resolved into the HDR texture, `None`
+    /// without MSAA
+    msaa_view: Option<wgpu::TextureView>,
+    /// The scene is drawn into its HDR texture, then its effects turn that
+    /// into the frame
+    pub post: PostProcessing,
+
+    render_pipeline: wgpu::RenderPipeline,
+    /// Draws blended meshes back to front after everything opaque
+    transparent_pipeline: wgpu::RenderPipeline,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    models: Vec<SceneModel>,
+    /// Where the models are drawn, nodes refer to `models` by index
+    pub scene_graph: SceneGraph,
+    asset_loader: AssetLoader,
+    /// Shared by every texture upload so its blit pipelines are reused
+    mipmaps: MipmapGenerator,
+    /// Drawn once per light by `light_render_pipeline`
+    light_model: model::Model,
+
+    pub camera: camera::Camera,
+    pub projection: camera::Projection,
+    /// Frame the scene once every model has loaded
+    focus_when_loaded: bool,
+    camera_uniform: CameraUniform,
+    camera_buffer: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+
+    pub lights: LightList,
+    /// How fast the lights orbit the y axis
+    pub light_rotation: cgmath::Deg<f32>,
+    light_render_pipeline: wgpu::RenderPipeline,
+
+    shadow_pass: ShadowPass,
+    pub ssao: SsaoPass,
+    /// Skybox and image based lighting
+    pub environment: Environment,
+    /// Group 3 of the main pipeline, everything lighting samples besides the
+    /// lights themselves
+    lighting_layout: wgpu::BindGroupLayout,
+    lighting_bind_group: wgpu::BindGroup,
+    /// What the debug view shows and where
+    pub debug: DebugSettings,
+    depth_pass: DepthPass,
+    /// `None` where compute shaders aren't available
+    culling_pass: Option<CullingPass>,
+    /// Skip instances outside the view frustum, ignored without `culling_pass`
+    pub frustum_culling: bool,
+}
+
+/// One model of the scene with the world matrices of the nodes showing it
+struct SceneModel {
+    /// Placeholder cube until `handle` finishes loading
+    model: model::Model,
+    handle: Option<AssetHandle>,
+    instances: InstanceBuffer,
+    /// Created on demand, dropped when `model` or the instance buffer changes
+    culled: Option<CulledInstances>,
+    /// Set when the culling buffers would exceed the device limits, the
+    /// model is then drawn without culling
+    too_large_to_cull: bool,
+}
+
+impl SceneModel {
+    fn new(device: &wgpu::Device, model: model::Model, handle: AssetHandle) -> Self {
+        Self {
+            model,
+            handle: Some(handle),
+            instances: InstanceBuffer::new(device, 1),
+            culled: None,
+            too_large_to_cull: false,
+        }
+    }
+
+    /// Drops the culling output after `model` or the instance buffer changed
+    fn reset_culling(&mut self) {
+        self.culled = None;
+        self.too_large_to_cull = false;
+    }
+}
+
+impl Renderer {
+    /// Starts loading the models of `scene` in the background, they show up
+    /// as cubes until they are ready.
+    pub fn new(
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+        scene: &SceneDescription,
+    ) -> anyhow::Result<Self> {
+        let texture_bind_group_layout = texture::create_texture_bind_group_layout(&device);
+
+        let scene_graph = scene.build_graph()?;
+        let mut asset_loader = AssetLoader::new();
+        let mut mipmaps = MipmapGenerator::new(&device);
+        let models = scene
+            .models
+            .iter()
+            .map(|description| {
+                let placeholder =
+                    model::ModelData::cube().upload(&device, &queue, &mut mipmaps, &texture_bind_group_layout);
+                SceneModel::new(&device, placeholder, asset_loader.load_model(&description.path))
+            })
+            .collect::<Vec<_>>();
+        let light_model = model::ModelData::cube().upload(&device, &queue, &mut mipmaps, &texture_bind_group_layout);
+
+        let camera = scene.camera.camera();
+        let projection = scene.camera.projection(width, height);
+
+        let mut camera_uniform = CameraUniform::new();
+        camera_uniform.update_view_proj(&camera, &projection);
+        let (camera_buffer, camera_bind_group_layout, camera_bind_group) = camera_uniform.create_camera_buffer_bind_group(&device);
+
+        let lights = LightList::new(&device, &queue, scene.lights.iter().map(|l| l.to_light()).collect());
+        let depth_pass = DepthPass::new(&device, format, width, height, sample_count);
+        let ssao = SsaoPass::new(
+            &device,
+            &camera_bind_group_layout,
+            &texture_bind_group_layout,
+            &depth_pass.texture,
+            projection.depth_mode(),
+            width,
+            height,
+            scene.ssao,
+        );
+        // Fitted to the scene once the renderer exists
+        let shadow_pass = ShadowPass::new(
+            &device,
+            &texture_bind_group_layout,
+            lights.shadow_caster().map(|index| &lights.lights[index]),
+            &BoundingSphere::default(),
+        );
+        let environment = Environment::new(&device, &queue, &scene.environment, projection.depth_mode(), sample_count)?;
+        let lighting_layout = create_lighting_layout(&device);
+        let lighting_bind_group =
+            create_lighting_bind_group(&device, &lighting_layout, &shadow_pass, ssao.view(), &environment);
+
+        //shader file & render pipeline
+        let render_pipeline_layout =
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Render Pipeline Layout"),
+                bind_group_layouts: &[
+                    &texture_bind_group_layout,
+                    &camera_bind_group_layout,
+                    &lights.layout,
+                    &lighting_layout,
+                ],
+                push_constant_ranges: &[],
+            });
+
+        let render_pipeline = {
+            let shader = wgpu::include_wgsl!("shader.wgsl");
+
+            create_render_pipeline(
+                &device,
+                &render_pipeline_layout,
+                &[HDR_FORMAT, DEBUG_FORMAT],
+                Some(texture::Texture::DEPTH_FORMAT),
+                projection.depth_mode(),
+                true,
+                sample_count,
+                wgpu::BlendState::REPLACE,
+                &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                shader,
+            )
+        };
+
+        // Tested against the depth of the opaque geometry without changing it
+        let transparent_pipeline = create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            &[HDR_FORMAT, DEBUG_FORMAT],
+            Some(texture::Texture::DEPTH_FORMAT),
+            projection.depth_mode(),
+            false,
+            sample_count,
+            wgpu::BlendState::ALPHA_BLENDING,
+            &[model::ModelVertex::desc(), InstanceRaw::desc()],
+            wgpu::include_wgsl!("shader.wgsl"),
+        );
+
+        let light_render_pipeline = {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Light Pipeline Layout"),
+                bind_group_layouts: &[
+                    &camera_bind_group_layout,
+                    &lights.layout,
+                ],
+                push_constant_ranges: &[],
+            });
+            let shader = wgpu::include_wgsl!("light.wgsl");
+
+            create_render_pipeline(
+                &device,
+                &layout,
+                &[HDR_FORMAT, DEBUG_FORMAT],
+                Some(texture::Texture::DEPTH_FORMAT),
+                projection.depth_mode(),
+                true,
+                sample_count,
+                wgpu::BlendState::REPLACE,
+                &[model::ModelVertex::desc()],
+                shader,
+            )
+        };
+
+        let msaa_view = create_msaa_view(&device, width, height, sample_count);
+        let post = PostProcessing::new(&device, &queue, format, width, height, scene.effects.clone());
+        let culling_pass = culling::is_supported(&device).then(|| CullingPass::new(&device));
+
+        let mut renderer = Self {
+            device,
+            queue,
+            format,
+            width,
+            height,
+            sample_count,
+            msaa_view,
+            post,
+
+            render_pipeline,
+            transparent_pipeline,
+            texture_bind_group_layout,
+            models,
+            scene_graph,
+            asset_loader,
+            mipmaps,
+            light_model,
+
+            camera,
+            projection,
+            focus_when_loaded: scene.camera.position.is_none(),
+            camera_uniform,
+            camera_buffer,
+            camera_bind_group,
+
+            lights,
+            light_rotation: cgmath::Deg(scene.light_rotation),
+            light_render_pipeline,
+
+            shadow_pass,
+            ssao,
+            environment,
+            lighting_layout,
+            lighting_bind_group,
+            debug: scene.debug,
+            depth_pass,
+            culling_pass,
+            frustum_culling: true,
+        };
+        renderer.write_instances();
+        renderer.update_shadow();
+        if renderer.focus_when_loaded {
+            // Look at the placeholders until the real models are there
+            renderer.focus(None);
+        }
+        Ok(renderer)
+    }
+
+    /// Creates a renderer without any window or surface.
+    ///
+    /// With `force_fallback_adapter` set, wgpu picks a software adapter
+    /// (e.g. llvmpipe), so frames can be produced on machines without a GPU.
+    pub async fn headless(
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        scene: &SceneDescription,
+        force_fallback_adapter: bool,
+    ) -> anyhow::Result<Self> {
+        if !is_rgba8_compatible(format) {
+            anyhow::bail!("Unsupported offscreen format {:?}, expected an 8-bit RGBA/BGRA format", format);
+        }
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        });
+
+        let adapter = instance.request_adapter(
+            &wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter,
+            },
+        ).await.ok_or_else(|| anyhow::anyhow!("No suitable adapter found"))?;
+        info!("Headless adapter: {:?}", adapter.get_info());
+
+        let (device, queue) = adapter.request_device(
+            &wgpu::DeviceDescriptor {
+                features: msaa_features(&adapter),
+                // Software adapters rarely reach the default limits
+                limits: wgpu::Limits::downlevel_defaults().using_resolution(adapter.limits()),
+                label: None,
+            },
+            None, // Trace path
+        ).await?;
+
+        let sample_count = supported_sample_count(&adapter, scene.msaa);
+        let mut renderer = Self::new(device, queue, format, width, height, sample_count, scene)?;
+        // Offscreen frames are expected to show the actual models
+        renderer.finish_loading()?;
+        if renderer.focus_when_loaded {
+            renderer.focus_when_loaded = false;
+            renderer.focus(None);
+        }
+        Ok(renderer)
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if width > 0 && height > 0 {
+            self.width = width;
+            self.height = height;
+            self.projection.resize(width, height);
+            self.depth_pass.resize(&self.device, width, height);
+            self.ssao.resize(&self.device, &self.depth_pass.texture, width, height);
+            self.lighting_bind_group = create_lighting_bind_group(
+                &self.device,
+                &self.lighting_layout,
+                &self.shadow_pass,
+                self.ssao.view(),
+                &self.environment,
+            );
+            self.msaa_view = create_msaa_view(&self.device, width, height, self.sample_count);
+            self.post.resize(&self.device, width, height);
+        }
+    }
+
+    /// Starts loading another model in the background, add nodes with the
+    /// returned id to [`Renderer::scene_graph`] to show it
+    pub fn add_model(&mut self, path: impl Into<std::path::PathBuf>) -> ModelId {
+        let placeholder = model::ModelData::cube().upload(
+            &self.device,
+            &self.queue,
+            &mut self.mipmaps,
+            &self.texture_bind_group_layout,
+        );
+        let handle = self.asset_loader.load_model(path);
+        self.models.push(SceneModel::new(&self.device, placeholder, handle));
+        ModelId(self.models.len() - 1)
+    }
+
+    /// Propagates scene graph changes into the instance buffers
+    fn write_instances(&mut self) {
+        if !self.scene_graph.update_world_matrices() {
+            return;
+        }
+
+        for (model, changes) in self.scene_graph.take_instance_changes() {
+            let Some(scene_model) = self.models.get_mut(model.0) else {
+                error!("Scene graph refers to a model that doesn't exist: {:?}", model);
+                continue;
+            };
+            let list = scene_model.instances.list_mut();
+            for change in changes {
+                list.apply(change);
+            }
+        }
+        for scene_model in &mut self.models {
+            if scene_model.instances.flush(&self.device, &self.queue) {
+                scene_model.reset_culling();
+            }
+        }
+    }
+
+    /// World space box around every instance of `model`, or of all models,
+    /// `None` when there is nothing to enclose
+    pub fn world_bounds(&mut self, model: Option<ModelId>) -> Option<Aabb> {
+        self.write_instances();
+        self.scene_graph
+            .iter()
+            .filter_map(|(_, node)| {
+                let id = node.model().filter(|&id| model.is_none_or(|model| model == id))?;
+                let scene_model = self.models.get(id.0)?;
+                Some(scene_model.model.aabb.transform(&node.world_matrix()))
+            })
+            .reduce(|a, b| a.union(&b))
+    }
+
+    /// Moves the camera so `model`, or all models, fill the view and fits
+    /// the depth range to them. Returns the framed bounds, or `None` when
+    /// there is nothing to show.
+    pub fn focus(&mut self, model: Option<ModelId>) -> Option<BoundingSphere> {
+        let bounds = self.world_bounds(model)?;
+        let sphere = BoundingSphere::from_points(bounds.corners());
+        self.camera.focus_on(&sphere, &mut self.projection);
+        self.camera_uniform.update_view_proj(&self.camera, &self.projection);
+        self.camera_uniform.debug_view = self.debug.view as u32;
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+        info!(
+            "Focus on {:?}, depth range {}..{}",
+            sphere,
+            self.projection.znear(),
+            self.projection.zfar()
+        );
+        Some(sphere)
+    }
+
+    /// Fits the shadow map of the shadow casting light to the whole scene
+    fn update_shadow(&mut self) {
+        let scene = self
+            .world_bounds(None)
+            .map(|bounds| BoundingSphere::from_points(bounds.corners()))
+            .unwrap_or_default();
+        let caster = self.lights.shadow_caster().map(|index| &self.lights.lights[index]);
+        self.shadow_pass.update(&self.queue, caster, &scene);
+    }
+
+    /// The instances of blended meshes as `(instance buffer, mesh, material,
+    /// instances)`, the farthest from the camera first. Runs of the same
+    /// mesh are merged into one range where that keeps the order.
+    fn sorted_transparent_draws(&self) -> Vec<(&wgpu::Buffer, &model::Mesh, &model::Material, Range<u32>)> {
+        let forward = self.camera.forward();
+        let mut draws = Vec::new();
+        for (model_index, scene_model) in self.models.iter().enumerate() {
+            let model = &scene_model.model;
+            for (mesh_index, mesh) in model.meshes.iter().enumerate().filter(|(_, mesh)| model.is_blended(mesh)) {
+                let instances = scene_model.instances.instances();
+                for (instance, raw) in instances.iter().enumerate().take(scene_model.instances.draw_range().len()) {
+                    let center = cgmath::Matrix4::from(raw.model) * mesh.bounding_sphere.center.to_homogeneous();
+                    let depth = (center.truncate() - self.camera.position.to_vec()).dot(forward);
+                    draws.push((depth, (model_index, mesh_index), instance as u32));
+                }
+            }
+        }
+        draws.sort_by(|a, b| b.0.total_cmp(&a.0));
+        batch_instances(draws.into_iter().map(|(_, key, instance)| (key, instance)))
+            .into_iter()
+            .map(|((model_index, mesh_index), instances)| {
+                let scene_model = &self.models[model_index];
+                let mesh = &scene_model.model.meshes[mesh_index];
+                let material = &scene_model.model.materials[mesh.material_idx];
+                (scene_model.instances.buffer(), mesh, material, instances)
+            })
+            .collect()
+    }
+
+    /// Creates missing culling outputs and updates the culling parameters
+    fn prepare_culling(&mut self) {
+        let Some(culling_pass) = &self.culling_pass else {
+            return;
+        };
+        if !self.frustum_culling {
+            return;
+        }
+        for scene_model in &mut self.models {
+            if scene_model.culled.is_none() && !scene_model.too_large_to_cull {
+                let (mesh_count, capacity) = (scene_model.model.meshes.len(), scene_model.instances.capacity());
+                if culling::fits_limits(&self.device, mesh_count, capacity) {
+                    scene_model.culled = Some(CulledInstances::new(
+                        &self.device,
+                        culling_pass,
+                        &self.camera_buffer,
+                        &scene_model.model,
+                        &scene_model.instances,
+                    ));
+                } else {
+                    warn!(
+                        "Culling {} meshes of {} instances exceeds the storage buffer limits, drawing them all",
+                        mesh_count, capacity
+                    );
+                    scene_model.too_large_to_cull = true;
+                }
+            }
+            if let Some(culled) = &mut scene_model.culled {
+                culled.update(&self.queue, scene_model.instances.draw_range().end);
+            }
+        }
+    }
+
+    /// Average loading progress of the models from 0 to 1, `None` once all
+    /// of them are swapped in
+    pub fn loading_progress(&self) -> Option<f32> {
+        if self.models.iter().all(|m| m.handle.is_none()) {
+            return None;
+        }
+        let progress = self
+            .models
+            .iter()
+            .map(|m| match m.handle {
+                Some(handle) => self.asset_loader.state(handle).map_or(1.0, |s| s.progress()),
+                None => 1.0,
+            })
+            .sum::<f32>();
+        Some(progress / self.models.len() as f32)
+    }
+
+    /// Blocks until every model has loaded and replaces the placeholders
+    pub fn finish_loading(&mut self) -> anyhow::Result<()> {
+        self.asset_loader
+            .wait(&self.device, &self.queue, &mut self.mipmaps, &self.texture_bind_group_layout);
+        self.swap_in_loaded_models()
+    }
+
+    /// Replaces placeholders of finished models, returning the first error.
+    /// Models that failed to load keep their placeholder.
+    fn swap_in_loaded_models(&mut self) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        for scene_model in &mut self.models {
+            let Some(handle) = scene_model.handle else {
+                continue;
+            };
+            let Some(loaded) = self.asset_loader.take_model(handle) else {
+                continue;
+            };
+            scene_model.handle = None;
+            match loaded {
+                Ok(model) => {
+                    info!("Model loaded");
+                    scene_model.model = model;
+                    scene_model.reset_culling();
+                }
+                Err(e) => {
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    pub fn update(&mut self, dt: instant::Duration) {
+        self.asset_loader
+            .poll(&self.device, &self.queue, &mut self.mipmaps, &self.texture_bind_group_layout);
+        if let Err(e) = self.swap_in_loaded_models() {
+            // Keep drawing the placeholder
+            error!("Failed to load model: {:#}", e);
+        }
+        if self.focus_when_loaded && self.loading_progress().is_none() {
+            self.focus_when_loaded = false;
+            self.focus(None);
+        }
+        self.write_instances();
+        self.prepare_culling();
+
+        self.camera_uniform.update_view_proj(&self.camera, &self.projection);
+        self.camera_uniform.debug_view = self.debug.view as u32;
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+
+        // Update the lights
+        let rotation = cgmath::Matrix3::from_axis_angle((0.0, 1.0, 0.0).into(), self.light_rotation * dt.as_secs_f32());
+        for light in &mut self.lights.lights {
+            light.position = rotation * light.position;
+            light.direction = rotation * light.direction;
+        }
+
+        self.lights.write(&self.device, &self.queue);
+        self.update_shadow();
+        self.ssao.update(&self.queue, &self.projection);
+        self.environment.update(&self.queue, &self.camera, &self.projection);
+        self.post.update(&self.device, &self.queue);
+    }
+
+    /// Draws one frame into `view`, which must have the renderer's format and size.
+    pub fn render(&self, view: &wgpu::TextureView, color: wgpu::Color) {
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+
+        let draws = self
+            .models
+            .iter()
+            .filter(|m| !m.instances.draw_range().is_empty())
+            .map(|m| (&m.model, m.instances.buffer(), m.instances.draw_range()))
+            .collect::<Vec<_>>();
+        self.shadow_pass.render(&mut encoder, &draws);
+        self.ssao.render(&mut encoder, &self.depth_pass.texture, &self.camera_bind_group, &draws);
+
+        // Shadows are drawn before culling, casters outside the view still count
+        let culling_pass = self.culling_pass.as_ref().filter(|_| self.frustum_culling);
+        if let Some(culling_pass) = culling_pass {
+            for scene_model in &self.models {
+                if let Some(culled) = &scene_model.culled {
+                    culling_pass.dispatch(&mut encoder, culled);
+                }
+            }
+        }
+
+        // With MSAA only the resolved image is kept
+        let (target, resolve_target, store) = match &self.msaa_view {
+            Some(msaa_view) => (msaa_view, Some(self.post.view()), wgpu::StoreOp::Discard),
+            None => (self.post.view(), None, wgpu::StoreOp::Store),
+        };
+        let (debug_target, debug_resolve_target) = self.depth_pass.debug_attachment();
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render Pass"),
+            color_attachments: &[
+                Some(wgpu::RenderPassColorAttachment {
+                    view: target,
+                    resolve_target,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(color),
+                        store,
+                    },
+                }),
+                Some(wgpu::RenderPassColorAttachment {
+                    view: debug_target,
+                    resolve_target: debug_resolve_target,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(self.debug.view.clear_color()),
+                        store,
+                    },
+                }),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: self.depth_pass.attachment(),
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(self.projection.depth_mode().clear_value()),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        info!("start light render pipeline");
+        render_pass.set_pipeline(&self.light_render_pipeline);
+        // One gizmo per light, light.wgsl looks the light up by instance index
+        render_pass.draw_light_model_instanced(
+            &self.light_model,
+            0..self.lights.uploaded_len() as u32,
+            &self.camera_bind_group,
+            &self.lights.bind_group,
+        );
+
+        info!("start model render pipeline");
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(3, &self.lighting_bind_group, &[]);
+        for scene_model in self.models.iter().filter(|m| !m.instances.draw_range().is_empty()) {
+            match scene_model.culled.as_ref().filter(|_| culling_pass.is_some()) {
+                Some(culled) => {
+                    render_pass.draw_model_indirect(
+                        &scene_model.model,
+                        &culled.visible_instances(),
+                        &culled.indirect_buffer,
+                        &self.camera_bind_group,
+                        &self.lights.bind_group,
+                    );
+                }
+                None => {
+                    render_pass.set_vertex_buffer(1, scene_model.instances.buffer().slice(..));
+                    render_pass.draw_model_instanced(
+                        &scene_model.model,
+                        scene_model.instances.draw_range(),
+                        &self.camera_bind_group,
+                        &self.lights.bind_group,
+                    );
+                }
+            }
+        }
+
+        // Only shows where the depth buffer is still clear
+        self.environment.render(&mut render_pass);
+
+        // Blending needs what is behind to be drawn first
+        render_pass.set_pipeline(&self.transparent_pipeline);
+        render_pass.set_bind_group(3, &self.lighting_bind_group, &[]);
+        for (instance_buffer, mesh, material, instances) in self.sorted_transparent_draws() {
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            render_pass.draw_mesh_instanced(
+                mesh,
+                material,
+                instances,
+                &self.camera_bind_group,
+                &self.lights.bind_group,
+            );
+        }
+
+        drop(render_pass);
+        self.post.process(&mut encoder, view);
+        self.depth_pass.render(view, &mut encoder, &self.debug);
+
+        // submit will accept anything that implements IntoIter
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    /// Renders one frame into an offscreen texture and reads it back.
+    pub async fn render_to_image(&self, color: wgpu::Color) -> anyhow::Result<image::RgbaImage> {
+        if !is_rgba8_compatible(self.format) {
+            anyhow::bail!("Cannot read back frames of format {:?}", self.format);
+        }
+
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Target"),
+            size: wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: self.format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.render(&view, color);
+
+        // Rows of a texture-to-buffer copy have to be padded to COPY_BYTES_PER_ROW_ALIGNMENT
+        let unpadded_bytes_per_row = 4 * self.width;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(align) * align;
+
+        let output_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Offscreen Readback Buffer"),
+            size: (padded_bytes_per_row * self.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &output_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_bytes_per_row),
+                    rows_per_image: Some(self.height),
+                },
+            },
+            texture.size(),
+        );
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let buffer_slice = output_buffer.slice(..);
+        let (tx, rx) = futures::channel::oneshot::channel();
+        buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
+            tx.send(result).ok();
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        rx.await??;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * self.height) as usize);
+        {
+            let data = buffer_slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        output_buffer.unmap();
+
+        if matches!(self.format, wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb) {
+            for pixel in pixels.chunks_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        image::RgbaImage::from_raw(self.width, self.height, pixels)
+            .ok_or_else(|| anyhow::anyhow!("Readback buffer does not match the frame size"))
+    }
+}
+
+/// Device features needed for sample counts other than 1 and 4, where the
+/// adapter has them
+pub fn msaa_features(adapter: &wgpu::Adapter) -> wgpu::Features {
+    adapter.features() & wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+}
+
+/// The highest sample count up to `requested` that the HDR target and the
+/// depth buffer support on `adapter`. Every adapter handles 1 and 4, 2 and 8
+/// need the device to be created with [`msaa_features`].
+pub fn supported_sample_count(adapter: &wgpu::Adapter, requested: u32) -> u32 {
+    let adapter_specific = adapter
+        .features()
+        .contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
+    let supported = |count: u32| {
+        count == 1
+            || (adapter_specific || count == 4)
+                && [HDR_FORMAT, DEBUG_FORMAT, texture::Texture::DEPTH_FORMAT]
+                    .iter()
+                    .all(|&format| adapter.get_texture_format_features(format).flags.sample_count_supported(count))
+    };
+    let count = [8, 4, 2, 1]
+        .into_iter()
+        .find(|&count| count <= requested && supported(count))
+        .unwrap_or(1);
+    if count != requested {
+        warn!("{}x MSAA is not supported, using {}x", requested, count);
+    }
+    count
+}
+
+fn create_lighting_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture = |binding, sample_type, view_dimension| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            sample_type,
+            multisampled: false,
+            view_dimension,
+        },
+        count: None,
+    };
+    let uniform = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    };
+    let sampler = |binding, ty| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Sampler(ty),
+        count: None,
+    };
+    let filterable = wgpu::TextureSampleType::Float { filterable: true };
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Lighting Layout"),
+        entries: &[
+            // Shadow map
+            texture(0, wgpu::TextureSampleType::Depth, wgpu::TextureViewDimension::D2),
+            sampler(1, wgpu::SamplerBindingType::Comparison),
+            uniform(2),
+            // Screen space ambient occlusion
+            texture(3, wgpu::TextureSampleType::Float { filterable: false }, wgpu::TextureViewDimension::D2),
+            // Image based lighting
+            texture(4, filterable, wgpu::TextureViewDimension::Cube),
+            texture(5, filterable, wgpu::TextureViewDimension::Cube),
+            texture(6, filterable, wgpu::TextureViewDimension::D2),
+            sampler(7, wgpu::SamplerBindingType::Filtering),
+            uniform(8),
+        ],
+    })
+}
+
+/// Recreated whenever the ambient occlusion texture changes
+fn create_lighting_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    shadow_pass: &ShadowPass,
+    ambient_occlusion: &wgpu::TextureView,
+    environment: &Environment,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&shadow_pass.texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&shadow_pass.texture.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: shadow_pass.buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(ambient_occlusion),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(&environment.irradiance_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&environment.prefiltered_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: wgpu::BindingResource::TextureView(&environment.brdf_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 7,
+                resource: wgpu::BindingResource::Sampler(&environment.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 8,
+                resource: environment.buffer().as_entire_binding(),
+            },
+        ],
+        label: Some("lighting_bind_group"),
+    })
+}
+
+fn create_msaa_view(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+) -> Option<wgpu::TextureView> {
+    if sample_count <= 1 {
+        return None;
+    }
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Multisampled Color Target"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: HDR_FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    Some(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+}
+
+/// Joins consecutive draws of the same key whose instances follow each
+/// other into one range. Only ascending runs are joined, since a draw call
+/// blends its instances in ascending order.
+fn batch_instances<K: PartialEq>(draws: impl IntoIterator<Item = (K, u32)>) -> Vec<(K, Range<u32>)> {
+    let mut batches: Vec<(K, Range<u32>)> = Vec::new();
+    for (key, instance) in draws {
+        match batches.last_mut() {
+            Some((last_key, range)) if *last_key == key && range.end == instance => range.end += 1,
+            _ => batches.push((key, instance..instance + 1)),
+        }
+    }
+    batches
+}
+
+fn is_rgba8_compatible(format: wgpu::TextureFormat) -> bool {
+    matches!(
+        format,
+        wgpu::TextureFormat::Rgba8Unorm
+            | wgpu::TextureFormat::Rgba8UnormSrgb
+            | wgpu::TextureFormat::Bgra8Unorm
+            | wgpu::TextureFormat::Bgra8UnormSrgb
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consecutive_instances_of_a_mesh_share_a_draw() {
+        let draws = [("a", 0), ("a", 1), ("a", 2), ("b", 3), ("b", 4), ("a", 3), ("a", 4)];
+        assert_eq!(batch_instances(draws), vec![("a", 0..3), ("b", 3..5), ("a", 3..5)]);
+    }
+
+    #[test]
+    fn out_of_order_instances_keep_separate_draws() {
+        let draws = [("a", 2), ("a", 1), ("a", 0), ("a", 4), ("a", 5)];
+        assert_eq!(batch_instances(draws), vec![("a", 2..3), ("a", 1..2), ("a", 0..1), ("a", 4..6)]);
+        assert!(batch_instances(Vec::<(&str, u32)>::new()).is_empty());
+    }
+}
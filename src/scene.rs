@@ -0,0 +1,605 @@
+//! Scene files describing what to load and where to put it.
+//!
+//! ```toml
+//! clear_color = [0.1, 0.2, 0.3]
+//!
+//! [camera]
+//! position = [0.0, 5.0, 10.0]
+//! yaw = -90.0
+//! pitch = -20.0
+//!
+//! [[light]]
+//! kind = "point"
+//! position = [2.0, 2.0, 2.0]
+//! intensity = 30.0
+//!
+//! [[model]]
+//! path = "../res/cube.obj"
+//! [[model.instance]]
+//! position = [0.0, 0.0, 0.0]
+//! rotation = [0.0, 45.0, 0.0]
+//! parent = "turntable"
+//!
+//! [[node]]
+//! name = "turntable"
+//! position = [0.0, 1.0, 0.0]
+//! ```
+//!
+//! Angles are in degrees and model paths are relative to the scene file.
+//! Instances and `[[node]]` tables, which only hold a transform, can be named
+//! and placed below another named one, their transform is then relative to
+//! that parent.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use cgmath::{Deg, InnerSpace, Rotation3};
+use serde::Deserialize;
+
+use crate::camera::{Camera, Projection};
+use crate::depth_pass::DebugSettings;
+use crate::environment::EnvironmentSettings;
+use crate::instance::Instance;
+use crate::light::Light;
+use crate::post::{self, Effect, PostEffect};
+use crate::scene_graph::{ModelId, SceneGraph};
+use crate::ssao::SsaoSettings;
+use crate::texture::DepthMode;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneDescription {
+    /// Linear RGB, each component in `0.0..=1.0`
+    pub clear_color: [f64; 3],
+    /// Degrees per second the lights orbit the y axis, `0.0` keeps them still
+    pub light_rotation: f32,
+    /// Samples per pixel: 1 (off), 2, 4 or 8. Lowered to what the adapter
+    /// supports.
+    pub msaa: u32,
+    pub ssao: SsaoSettings,
+    pub debug: DebugSettings,
+    /// Skybox and ambient light, image paths are relative to the scene file
+    pub environment: EnvironmentSettings,
+    /// Post-processing chain, applied in order
+    #[serde(rename = "effect")]
+    pub effects: Vec<PostEffect>,
+    pub camera: CameraDescription,
+    #[serde(rename = "light")]
+    pub lights: Vec<LightDescription>,
+    #[serde(rename = "model")]
+    pub models: Vec<ModelDescription>,
+    /// Transforms without a model, to group instances under
+    #[serde(rename = "node")]
+    pub nodes: Vec<InstanceDescription>,
+}
+
+impl Default for SceneDescription {
+    fn default() -> Self {
+        Self {
+            clear_color: [0.0; 3],
+            light_rotation: 60.0,
+            msaa: 1,
+            ssao: SsaoSettings::default(),
+            debug: DebugSettings::default(),
+            environment: EnvironmentSettings::default(),
+            effects: post::default_effects(),
+            camera: CameraDescription::default(),
+            lights: vec![LightDescription::Point {
+                position: [2.0, 2.0, 2.0],
+                color: [1.0; 3],
+                intensity: 30.0,
+                range: 0.0,
+                casts_shadow: true,
+            }],
+            models: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraDescription {
+    /// Left out to frame all models once they are loaded, which also fits
+    /// `znear` and `zfar` to them
+    pub position: Option<[f32; 3]>,
+    pub yaw: f32,
+    pub pitch: f32,
+    /// Vertical field of view
+    pub fovy: f32,
+    pub znear: f32,
+    /// Ignored by perspective projections with `reverse_z`, but the
+    /// orthographic mode still clips at it, so it must lie beyond `znear`
+    pub zfar: f32,
+    /// Reverse-Z depth with an infinite far plane, for scenes spanning
+    /// kilometers
+    pub reverse_z: bool,
+}
+
+impl Default for CameraDescription {
+    fn default() -> Self {
+        Self {
+            position: None,
+            yaw: -90.0,
+            pitch: -20.0,
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+            reverse_z: false,
+        }
+    }
+}
+
+impl CameraDescription {
+    pub fn camera(&self) -> Camera {
+        Camera::new(self.position.unwrap_or([0.0, 5.0, 10.0]), Deg(self.yaw), Deg(self.pitch))
+    }
+
+    pub fn projection(&self, width: u32, height: u32) -> Projection {
+        Projection::new(width, height, Deg(self.fovy), self.znear, self.zfar, self.depth_mode())
+    }
+
+    pub fn depth_mode(&self) -> DepthMode {
+        if self.reverse_z {
+            DepthMode::ReverseZ
+        } else {
+            DepthMode::Standard
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelDescription {
+    /// OBJ or glTF file
+    pub path: PathBuf,
+    /// Defaults to a single instance at the origin
+    #[serde(default = "default_instances", rename = "instance")]
+    pub instances: Vec<InstanceDescription>,
+}
+
+impl ModelDescription {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            instances: default_instances(),
+        }
+    }
+}
+
+fn default_instances() -> Vec<InstanceDescription> {
+    vec![InstanceDescription::default()]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InstanceDescription {
+    /// Lets other instances and nodes use this one as their parent
+    pub name: Option<String>,
+    /// Name of the instance or node this one is attached to
+    pub parent: Option<String>,
+    pub position: [f32; 3],
+    /// Euler angles, applied around x, then y, then z
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+impl Default for InstanceDescription {
+    fn default() -> Self {
+        Self {
+            name: None,
+            parent: None,
+            position: [0.0; 3],
+            rotation: [0.0; 3],
+            scale: [1.0; 3],
+        }
+    }
+}
+
+impl InstanceDescription {
+    pub fn to_instance(&self) -> Instance {
+        let [x, y, z] = self.rotation;
+        Instance {
+            position: self.position.into(),
+            rotation: cgmath::Quaternion::from_angle_z(Deg(z))
+                * cgmath::Quaternion::from_angle_y(Deg(y))
+                * cgmath::Quaternion::from_angle_x(Deg(x)),
+            scaling: self.scale.into(),
+        }
+    }
+}
+
+fn white() -> [f32; 3] {
+    [1.0; 3]
+}
+
+fn yes() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase", deny_unknown_fields)]
+pub enum LightDescription {
+    Point {
+        position: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        intensity: f32,
+        #[serde(default)]
+        range: f32,
+        /// The first light with this set gets the shadow map
+        #[serde(default = "yes")]
+        casts_shadow: bool,
+    },
+    Directional {
+        direction: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        intensity: f32,
+        /// The first light with this set gets the shadow map
+        #[serde(default = "yes")]
+        casts_shadow: bool,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        #[serde(default = "white")]
+        color: [f32; 3],
+        intensity: f32,
+        #[serde(default)]
+        range: f32,
+        /// Half angle of the fully lit cone
+        inner_angle: f32,
+        /// Half angle where the light has faded out
+        outer_angle: f32,
+        /// The first light with this set gets the shadow map
+        #[serde(default = "yes")]
+        casts_shadow: bool,
+    },
+}
+
+impl LightDescription {
+    pub fn to_light(&self) -> Light {
+        let (light, casts_shadow) = match *self {
+            LightDescription::Point {
+                position,
+                color,
+                intensity,
+                range,
+                casts_shadow,
+            } => (Light::point(position.into(), color.into(), intensity, range), casts_shadow),
+            LightDescription::Directional {
+                direction,
+                color,
+                intensity,
+                casts_shadow,
+            } => (Light::directional(direction.into(), color.into(), intensity), casts_shadow),
+            LightDescription::Spot {
+                position,
+                direction,
+                color,
+                intensity,
+                range,
+                inner_angle,
+                outer_angle,
+                casts_shadow,
+            } => (
+                Light::spot(
+                    position.into(),
+                    direction.into(),
+                    color.into(),
+                    intensity,
+                    range,
+                    Deg(inner_angle),
+                    Deg(outer_angle),
+                ),
+                casts_shadow,
+            ),
+        };
+        Light { casts_shadow, ..light }
+    }
+}
+
+impl SceneDescription {
+    /// Reads a TOML scene file and resolves its paths relative to it. Call
+    /// [`SceneDescription::validate`] once any overrides are applied.
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read scene file {:?}", path))?;
+        let mut scene: Self = toml::from_str(&text).with_context(|| format!("Invalid scene file {:?}", path))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for model in &mut scene.models {
+            model.path = base.join(&model.path);
+        }
+        let environment = &mut scene.environment;
+        for face in environment.faces.iter_mut().flatten() {
+            *face = base.join(&*face);
+        }
+        environment.equirectangular = environment.equirectangular.as_ref().map(|path| base.join(path));
+        for effect in &mut scene.effects {
+            if let Effect::ColorGrading(grading) = &mut effect.effect {
+                grading.lut = grading.lut.as_ref().map(|lut| base.join(lut));
+            }
+        }
+        Ok(scene)
+    }
+
+    /// The default scene showing a single model
+    pub fn from_model(path: impl Into<PathBuf>) -> Self {
+        Self {
+            models: vec![ModelDescription::new(path)],
+            ..Default::default()
+        }
+    }
+
+    pub fn clear_color(&self) -> wgpu::Color {
+        let [r, g, b] = self.clear_color;
+        wgpu::Color { r, g, b, a: 1.0 }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.clear_color.iter().any(|c| !(0.0..=1.0).contains(c)) {
+            bail!("clear_color {:?} must have components between 0 and 1", self.clear_color);
+        }
+        check_finite("light_rotation", &[self.light_rotation])?;
+        let ssao = &self.ssao;
+        check_finite("ssao", &[ssao.radius, ssao.bias, ssao.intensity])?;
+        if ssao.radius <= 0.0 || ssao.intensity <= 0.0 {
+            bail!("ssao radius and intensity must be positive, got {} and {}", ssao.radius, ssao.intensity);
+        }
+        if ![1, 2, 4, 8].contains(&self.msaa) {
+            bail!("msaa must be 1, 2, 4 or 8, got {}", self.msaa);
+        }
+
+        let camera = &self.camera;
+        check_finite("camera.position", &camera.position.unwrap_or_default())?;
+        check_finite("camera", &[camera.yaw, camera.pitch, camera.fovy, camera.znear, camera.zfar])?;
+        if camera.fovy <= 0.0 || camera.fovy >= 180.0 {
+            bail!("camera.fovy must be between 0 and 180 degrees, got {}", camera.fovy);
+        }
+        if camera.znear <= 0.0 || camera.zfar <= camera.znear {
+            bail!(
+                "camera needs 0 < znear < zfar, got znear = {} and zfar = {}",
+                camera.znear,
+                camera.zfar
+            );
+        }
+
+        for (i, light) in self.lights.iter().enumerate() {
+            validate_light(light).with_context(|| format!("light #{}", i + 1))?;
+        }
+        self.environment.validate().context("environment")?;
+        for (i, effect) in self.effects.iter().enumerate() {
+            let context = || format!("effect #{}", i + 1);
+            effect.effect.validate().with_context(context)?;
+            if let Effect::ColorGrading(post::ColorGrading { lut: Some(lut), .. }) = &effect.effect {
+                if !lut.is_file() {
+                    return Err(anyhow::anyhow!("LUT {:?} not found", lut)).with_context(context);
+                }
+            }
+        }
+
+        if self.models.is_empty() {
+            bail!("the scene has no models, add at least one [[model]] table");
+        }
+        for (i, model) in self.models.iter().enumerate() {
+            let context = || format!("model #{} ({:?})", i + 1, model.path);
+            if !model.path.is_file() {
+                return Err(anyhow::anyhow!("file not found")).with_context(context);
+            }
+            for (j, instance) in model.instances.iter().enumerate() {
+                validate_instance(instance)
+                    .with_context(|| format!("instance #{}", j + 1))
+                    .with_context(context)?;
+            }
+        }
+        for (i, node) in self.nodes.iter().enumerate() {
+            validate_instance(node).with_context(|| format!("node #{}", i + 1))?;
+        }
+        self.build_graph()?;
+        Ok(())
+    }
+
+    /// One node per instance and `[[node]]` table, instances of the n-th
+    /// model refer to `ModelId(n)`
+    pub fn build_graph(&self) -> anyhow::Result<SceneGraph> {
+        let descriptions = self
+            .models
+            .iter()
+            .enumerate()
+            .flat_map(|(i, model)| model.instances.iter().map(move |instance| (Some(ModelId(i)), instance)))
+            .chain(self.nodes.iter().map(|node| (None, node)))
+            .collect::<Vec<_>>();
+
+        let mut graph = SceneGraph::new();
+        let mut names = std::collections::HashMap::new();
+        let mut ids = Vec::with_capacity(descriptions.len());
+        for (model, description) in &descriptions {
+            let id = graph.add_node(description.name.clone(), None, description.to_instance(), *model)?;
+            if let Some(name) = &description.name {
+                if names.insert(name.as_str(), id).is_some() {
+                    bail!("more than one instance or node is called {:?}", name);
+                }
+            }
+            ids.push(id);
+        }
+
+        // Parents may be declared after their children
+        for (id, (_, description)) in ids.into_iter().zip(&descriptions) {
+            if let Some(parent) = &description.parent {
+                let Some(&parent_id) = names.get(parent.as_str()) else {
+                    bail!("parent {:?} is not the name of any instance or node", parent);
+                };
+                graph
+                    .reparent(id, Some(parent_id))
+                    .with_context(|| format!("{:?} can't be below {:?}, that would form a loop", description.name, parent))?;
+            }
+        }
+        Ok(graph)
+    }
+}
+
+fn check_finite(name: &str, values: &[f32]) -> anyhow::Result<()> {
+    if values.iter().any(|v| !v.is_finite()) {
+        bail!("{} contains a value that is not a finite number: {:?}", name, values);
+    }
+    Ok(())
+}
+
+fn validate_instance(instance: &InstanceDescription) -> anyhow::Result<()> {
+    check_finite("position", &instance.position)?;
+    check_finite("rotation", &instance.rotation)?;
+    check_finite("scale", &instance.scale)?;
+    if instance.scale.contains(&0.0) {
+        bail!("scale {:?} must not contain zeros", instance.scale);
+    }
+    Ok(())
+}
+
+fn validate_light(light: &LightDescription) -> anyhow::Result<()> {
+    let (color, intensity, range) = match light {
+        LightDescription::Point { color, intensity, range, .. } => (color, *intensity, *range),
+        LightDescription::Directional { color, intensity, .. } => (color, *intensity, 0.0),
+        LightDescription::Spot { color, intensity, range, .. } => (color, *intensity, *range),
+    };
+    check_finite("color", color)?;
+    check_finite("intensity/range", &[intensity, range])?;
+    if color.iter().any(|c| *c < 0.0) {
+        bail!("color {:?} must not be negative", color);
+    }
+    if intensity < 0.0 {
+        bail!("intensity must not be negative, got {}", intensity);
+    }
+    if range < 0.0 {
+        bail!("range must not be negative (0 means unbounded), got {}", range);
+    }
+
+    match light {
+        LightDescription::Point { position, .. } => check_finite("position", position)?,
+        LightDescription::Directional { direction, .. } => check_direction(direction)?,
+        LightDescription::Spot {
+            position,
+            direction,
+            inner_angle,
+            outer_angle,
+            ..
+        } => {
+            check_finite("position", position)?;
+            check_direction(direction)?;
+            check_finite("inner_angle/outer_angle", &[*inner_angle, *outer_angle])?;
+            if !(0.0..90.0).contains(outer_angle) {
+                bail!("outer_angle must be between 0 and 90 degrees, got {}", outer_angle);
+            }
+            if *inner_angle < 0.0 || inner_angle >= outer_angle {
+                bail!(
+                    "inner_angle ({}) must be at least 0 and less than outer_angle ({})",
+                    inner_angle,
+                    outer_angle
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_direction(direction: &[f32; 3]) -> anyhow::Result<()> {
+    check_finite("direction", direction)?;
+    if cgmath::Vector3::from(*direction).magnitude2() == 0.0 {
+        bail!("direction must not be zero");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `text` followed by a model that exists, so only the part under
+    /// test can fail validation
+    fn parse(text: &str) -> SceneDescription {
+        let cube = Path::new(env!("CARGO_MANIFEST_DIR")).join("res/cube.obj");
+        toml::from_str(&format!("{}\n[[model]]\npath = {:?}\n", text, cube)).unwrap()
+    }
+
+    fn validation_error(text: &str) -> String {
+        format!("{:#}", parse(text).validate().unwrap_err())
+    }
+
+    #[test]
+    fn minimal_scene_is_valid() {
+        parse("msaa = 4").validate().unwrap();
+    }
+
+    #[test]
+    fn msaa_must_be_a_supported_count() {
+        let error = validation_error("msaa = 3");
+        assert!(error.contains("msaa must be 1, 2, 4 or 8, got 3"), "{}", error);
+    }
+
+    #[test]
+    fn zfar_must_lie_beyond_znear_in_every_depth_mode() {
+        for reverse_z in [false, true] {
+            let error = validation_error(&format!("[camera]\nznear = 1.0\nzfar = 0.5\nreverse_z = {}", reverse_z));
+            assert!(error.contains("camera needs 0 < znear < zfar"), "{}", error);
+        }
+        parse("[camera]\nznear = 1.0\nzfar = 1.5\nreverse_z = true").validate().unwrap();
+    }
+
+    #[test]
+    fn spot_cone_needs_a_falloff() {
+        let spot = |inner: f32, outer: f32| {
+            format!(
+                "[[light]]\nkind = \"spot\"\nposition = [0.0, 2.0, 0.0]\ndirection = [0.0, -1.0, 0.0]\n\
+                 color = [1.0, 1.0, 1.0]\nintensity = 1.0\ninner_angle = {:?}\nouter_angle = {:?}",
+                inner, outer
+            )
+        };
+        parse(&spot(20.0, 30.0)).validate().unwrap();
+        for (inner, outer) in [(30.0, 30.0), (40.0, 30.0)] {
+            let error = validation_error(&spot(inner, outer));
+            assert!(error.contains("must be at least 0 and less than outer_angle"), "{}", error);
+        }
+    }
+
+    #[test]
+    fn names_must_be_unique() {
+        let error = validation_error(
+            r#"
+            [[node]]
+            name = "arm"
+            [[node]]
+            name = "arm"
+            position = [1.0, 0.0, 0.0]
+            "#,
+        );
+        assert!(error.contains("more than one instance or node is called \"arm\""), "{}", error);
+    }
+
+    #[test]
+    fn effects_are_validated() {
+        let error = validation_error(
+            r#"
+            [[effect]]
+            kind = "tonemap"
+            [[effect]]
+            kind = "bloom"
+            threshold = 0.0
+            "#,
+        );
+        assert!(error.contains("effect #2"), "{}", error);
+        assert!(error.contains("bloom threshold must be positive"), "{}", error);
+    }
+
+    #[test]
+    fn environment_needs_six_faces() {
+        let error = validation_error(
+            r#"
+            [environment]
+            faces = ["px.png", "nx.png", "py.png"]
+            "#,
+        );
+        assert!(error.contains("environment"), "{}", error);
+        assert!(error.contains("faces needs 6 images, got 3"), "{}", error);
+    }
+}
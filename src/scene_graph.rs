@@ -0,0 +1,466 @@
+//! Nodes with parent/child transforms.
+//!
+//! Every node has a local [`Instance`] transform relative to its parent and
+//! may reference a model. [`SceneGraph::update_world_matrices`] propagates
+//! the transforms down the tree, after which [`SceneGraph::instances`]
+//! collects the world matrices of each model into `InstanceRaw` data.
+//! Moving a node moves its whole subtree with it.
+//!
+//! Every node showing a model owns one slot in that model's instance list.
+//! The graph records which slots were added, moved or removed, so
+//! [`SceneGraph::take_instance_changes`] can hand the instance buffers just
+//! those changes instead of every instance.
+
+use std::collections::HashMap;
+
+use anyhow::bail;
+use cgmath::SquareMatrix;
+
+use crate::instance::{Instance, InstanceChange, InstanceRaw};
+
+/// Index of a model in the renderer
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ModelId(pub usize);
+
+/// Refers to a node of a [`SceneGraph`]. Ids of removed nodes are never
+/// reused, so a stale id doesn't accidentally point at a newer node.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub name: Option<String>,
+    transform: Instance,
+    model: Option<ModelId>,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    world: cgmath::Matrix4<f32>,
+    /// Slot in the instance list of `model`
+    instance: Option<usize>,
+}
+
+impl Node {
+    /// Transform relative to the parent
+    pub fn transform(&self) -> &Instance {
+        &self.transform
+    }
+
+    pub fn model(&self) -> Option<ModelId> {
+        self.model
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+
+    /// Only up to date after [`SceneGraph::update_world_matrices`]
+    pub fn world_matrix(&self) -> cgmath::Matrix4<f32> {
+        self.world
+    }
+}
+
+struct Slot {
+    generation: u32,
+    node: Option<Node>,
+}
+
+#[derive(Default)]
+pub struct SceneGraph {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    roots: Vec<NodeId>,
+    /// Set by every change, cleared when the world matrices are recomputed
+    dirty: bool,
+    /// The node owning each instance slot, per model
+    instance_nodes: HashMap<ModelId, Vec<NodeId>>,
+    /// Instance changes not taken yet, per model
+    instance_changes: HashMap<ModelId, Vec<InstanceChange>>,
+}
+
+impl SceneGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&Node> {
+        self.slots
+            .get(id.index)
+            .filter(|slot| slot.generation == id.generation)?
+            .node
+            .as_ref()
+    }
+
+    fn get_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        self.slots
+            .get_mut(id.index)
+            .filter(|slot| slot.generation == id.generation)?
+            .node
+            .as_mut()
+    }
+
+    fn node_mut(&mut self, id: NodeId) -> anyhow::Result<&mut Node> {
+        match self.get_mut(id) {
+            Some(node) => Ok(node),
+            None => bail!("node {:?} does not exist", id),
+        }
+    }
+
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.get(id).is_some()
+    }
+
+    /// Nodes without a parent
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    /// Every node, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            let id = NodeId {
+                index,
+                generation: slot.generation,
+            };
+            slot.node.as_ref().map(|node| (id, node))
+        })
+    }
+
+    /// The first node called `name`
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.iter()
+            .find(|(_, node)| node.name.as_deref() == Some(name))
+            .map(|(id, _)| id)
+    }
+
+    /// Adds a node below `parent`, or as a root when `parent` is `None`
+    pub fn add_node(
+        &mut self,
+        name: Option<String>,
+        parent: Option<NodeId>,
+        transform: Instance,
+        model: Option<ModelId>,
+    ) -> anyhow::Result<NodeId> {
+        if let Some(parent) = parent {
+            if !self.contains(parent) {
+                bail!("parent node {:?} does not exist", parent);
+            }
+        }
+
+        let node = Node {
+            name,
+            transform,
+            model,
+            parent,
+            children: Vec::new(),
+            world: cgmath::Matrix4::identity(),
+            instance: None,
+        };
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.node = Some(node);
+                NodeId {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    node: Some(node),
+                });
+                NodeId {
+                    index: self.slots.len() - 1,
+                    generation: 0,
+                }
+            }
+        };
+
+        match parent {
+            Some(parent) => self.node_mut(parent)?.children.push(id),
+            None => self.roots.push(id),
+        }
+        self.attach_instance(id);
+        self.dirty = true;
+        Ok(id)
+    }
+
+    /// Removes `id` together with all of its descendants
+    pub fn remove_node(&mut self, id: NodeId) -> anyhow::Result<()> {
+        let parent = self.node_mut(id)?.parent;
+        self.detach(id, parent);
+
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            self.detach_instance(id);
+            let slot = &mut self.slots[id.index];
+            if let Some(node) = slot.node.take() {
+                stack.extend(node.children);
+            }
+            slot.generation += 1;
+            self.free.push(id.index);
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Moves `id` below `parent`, or makes it a root. The local transform is
+    /// kept, so the node now follows its new parent.
+    pub fn reparent(&mut self, id: NodeId, parent: Option<NodeId>) -> anyhow::Result<()> {
+        let old_parent = self.node_mut(id)?.parent;
+        if let Some(parent) = parent {
+            if !self.contains(parent) {
+                bail!("parent node {:?} does not exist", parent);
+            }
+            if self.is_ancestor_or_self(id, parent) {
+                bail!("cannot move node {:?} below its own descendant {:?}", id, parent);
+            }
+        }
+
+        self.detach(id, old_parent);
+        self.node_mut(id)?.parent = parent;
+        match parent {
+            Some(parent) => self.node_mut(parent)?.children.push(id),
+            None => self.roots.push(id),
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Whether `ancestor` is `node` or one of its ancestors
+    fn is_ancestor_or_self(&self, ancestor: NodeId, node: NodeId) -> bool {
+        let mut current = Some(node);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            current = self.get(id).and_then(|node| node.parent);
+        }
+        false
+    }
+
+    /// Unlinks `id` from the child list of `parent`, or from the roots
+    fn detach(&mut self, id: NodeId, parent: Option<NodeId>) {
+        let siblings = match parent {
+            Some(parent) => match self.get_mut(parent) {
+                Some(parent) => &mut parent.children,
+                None => return,
+            },
+            None => &mut self.roots,
+        };
+        siblings.retain(|&child| child != id);
+    }
+
+    pub fn set_transform(&mut self, id: NodeId, transform: Instance) -> anyhow::Result<()> {
+        self.node_mut(id)?.transform = transform;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Mutable access to the local transform of `id`
+    pub fn transform_mut(&mut self, id: NodeId) -> Option<&mut Instance> {
+        if !self.contains(id) {
+            return None;
+        }
+        self.dirty = true;
+        self.get_mut(id).map(|node| &mut node.transform)
+    }
+
+    pub fn set_model(&mut self, id: NodeId, model: Option<ModelId>) -> anyhow::Result<()> {
+        if self.node_mut(id)?.model == model {
+            return Ok(());
+        }
+        self.detach_instance(id);
+        self.node_mut(id)?.model = model;
+        self.attach_instance(id);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Gives `id` a slot at the end of its model's instance list
+    fn attach_instance(&mut self, id: NodeId) {
+        let Some(node) = self.get_mut(id) else {
+            return;
+        };
+        let Some(model) = node.model else {
+            return;
+        };
+        let raw = InstanceRaw::from_matrix(node.world);
+        let nodes = self.instance_nodes.entry(model).or_default();
+        nodes.push(id);
+        let index = nodes.len() - 1;
+        self.node_mut(id).expect("node was just found").instance = Some(index);
+        self.instance_changes
+            .entry(model)
+            .or_default()
+            .push(InstanceChange::Push(raw));
+    }
+
+    /// Frees the instance slot of `id`, the last instance of the model
+    /// moves into it like in [`crate::instance::InstanceList::swap_remove`]
+    fn detach_instance(&mut self, id: NodeId) {
+        let Some(node) = self.get_mut(id) else {
+            return;
+        };
+        let (Some(model), Some(index)) = (node.model, node.instance.take()) else {
+            return;
+        };
+        let nodes = self.instance_nodes.get_mut(&model).expect("model has instances");
+        nodes.swap_remove(index);
+        if let Some(&moved) = nodes.get(index) {
+            self.node_mut(moved).expect("instance nodes exist").instance = Some(index);
+        }
+        self.instance_changes
+            .entry(model)
+            .or_default()
+            .push(InstanceChange::SwapRemove(index));
+    }
+
+    /// Recomputes the world matrices if anything changed since the last
+    /// call and returns whether it did
+    pub fn update_world_matrices(&mut self) -> bool {
+        if !self.dirty {
+            return false;
+        }
+
+        let mut stack = self
+            .roots
+            .iter()
+            .map(|&id| (id, cgmath::Matrix4::identity()))
+            .collect::<Vec<_>>();
+        while let Some((id, parent_world)) = stack.pop() {
+            let Some(node) = self.get_mut(id) else {
+                continue;
+            };
+            let world = parent_world * node.transform.to_matrix();
+            let changed = node.world != world;
+            node.world = world;
+            stack.extend(node.children.iter().map(|&child| (child, world)));
+            if let (true, Some(model), Some(index)) = (changed, node.model, node.instance) {
+                self.instance_changes
+                    .entry(model)
+                    .or_default()
+                    .push(InstanceChange::Update(index, InstanceRaw::from_matrix(world)));
+            }
+        }
+
+        self.dirty = false;
+        true
+    }
+
+    /// World transforms of every node showing a model, grouped by model,
+    /// in instance slot order
+    pub fn instances(&self) -> HashMap<ModelId, Vec<InstanceRaw>> {
+        self.instance_nodes
+            .iter()
+            .filter(|(_, nodes)| !nodes.is_empty())
+            .map(|(&model, nodes)| {
+                let instances = nodes
+                    .iter()
+                    .filter_map(|&id| self.get(id))
+                    .map(|node| InstanceRaw::from_matrix(node.world))
+                    .collect();
+                (model, instances)
+            })
+            .collect()
+    }
+
+    /// The instance changes since the last call, per model, to apply in
+    /// order with [`crate::instance::InstanceList::apply`]. Call
+    /// [`SceneGraph::update_world_matrices`] first so moved nodes are
+    /// included.
+    pub fn take_instance_changes(&mut self) -> HashMap<ModelId, Vec<InstanceChange>> {
+        std::mem::take(&mut self.instance_changes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Vector3;
+
+    use super::*;
+    use crate::instance::InstanceList;
+
+    fn at(x: f32) -> Instance {
+        Instance {
+            position: Vector3::new(x, 0.0, 0.0),
+            ..Instance::default()
+        }
+    }
+
+    /// Applies the pending changes to `lists` and checks they now hold the
+    /// same instances as the graph
+    fn sync(graph: &mut SceneGraph, lists: &mut HashMap<ModelId, InstanceList>) -> usize {
+        graph.update_world_matrices();
+        let changes = graph.take_instance_changes();
+        let count = changes.values().map(Vec::len).sum();
+        for (model, changes) in changes {
+            let list = lists.entry(model).or_default();
+            for change in changes {
+                list.apply(change);
+            }
+        }
+
+        let expected = graph.instances();
+        for (model, list) in lists.iter() {
+            let expected = expected.get(model).map(Vec::as_slice).unwrap_or_default();
+            assert_eq!(
+                bytemuck::cast_slice::<_, u8>(list.instances()),
+                bytemuck::cast_slice::<_, u8>(expected),
+                "instances of {:?}",
+                model
+            );
+        }
+        count
+    }
+
+    #[test]
+    fn instance_changes_follow_the_graph() {
+        let (a, b) = (ModelId(0), ModelId(1));
+        let mut graph = SceneGraph::new();
+        let mut lists = HashMap::new();
+
+        let car = graph.add_node(None, None, at(1.0), Some(a)).unwrap();
+        let wheels = (0..3)
+            .map(|i| graph.add_node(None, Some(car), at(i as f32), Some(b)).unwrap())
+            .collect::<Vec<_>>();
+        let other = graph.add_node(None, None, at(-5.0), Some(b)).unwrap();
+        sync(&mut graph, &mut lists);
+
+        // Only the moved node has a new world matrix
+        graph.set_transform(other, at(-6.0)).unwrap();
+        assert_eq!(sync(&mut graph, &mut lists), 1);
+
+        // Moving the parent moves every child with it
+        graph.set_transform(car, at(2.0)).unwrap();
+        assert_eq!(sync(&mut graph, &mut lists), 4);
+
+        graph.remove_node(wheels[0]).unwrap();
+        sync(&mut graph, &mut lists);
+        graph.set_model(wheels[1], Some(a)).unwrap();
+        sync(&mut graph, &mut lists);
+        graph.reparent(wheels[2], None).unwrap();
+        sync(&mut graph, &mut lists);
+        graph.remove_node(car).unwrap();
+        sync(&mut graph, &mut lists);
+
+        assert_eq!(lists[&a].len(), 0);
+        assert_eq!(lists[&b].len(), 2);
+        assert_eq!(sync(&mut graph, &mut lists), 0);
+    }
+}
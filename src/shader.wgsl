@@ -4,17 +4,37 @@
 struct CameraUniform {
     view_pos: vec4<f32>,
     view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    // znear, zfar, reverse-Z and orthographic flags
+    depth_range: vec4<f32>,
+    debug_view: u32,
 };
 
 @group(1) @binding(0) // 1.
 var<uniform> camera: CameraUniform;
 
+const LIGHT_KIND_POINT: u32 = 0u;
+const LIGHT_KIND_DIRECTIONAL: u32 = 1u;
+const LIGHT_KIND_SPOT: u32 = 2u;
+
 struct Light {
-    position: vec4<f32>,
-    color: vec4<f32>,
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    inner_cone_cos: f32,
+    outer_cone_cos: f32,
+}
+struct Lights {
+    count: u32,
+    // Light the shadow map belongs to, 0xffffffffu if none
+    shadow_index: u32,
+    data: array<Light>,
 }
 @group(2) @binding(0)
-var<uniform> light: Light;
+var<storage, read> lights: Lights;
 
 
 
@@ -42,9 +62,8 @@ struct VertexOutput {
     @location(0) tex_coords: vec2<f32>,
     @location(1) world_normal: vec3<f32>,
     @location(2) world_position: vec3<f32>,
-    // @location(3) tangent_position: vec3<f32>,
-    // @location(4) tangent_light_position: vec3<f32>,
-    // @location(5) tangent_view_position: vec3<f32>,
+    @location(3) world_tangent: vec3<f32>,
+    @location(4) world_bitangent: vec3<f32>,
 }
 
 @vertex
@@ -52,61 +71,34 @@ fn vs_main(
     model: VertexInput,
     instance: InstanceInput,
 ) -> VertexOutput {
-//       let model_matrix = mat4x4<f32>(
-//         instance.model_matrix_0,
-//         instance.model_matrix_1,
-//         instance.model_matrix_2,
-//         instance.model_matrix_3,
-//     );
-
-//     let normal_matrix = mat3x3<f32>(
-//         instance.normal_matrix_0,
-//         instance.normal_matrix_1,
-//         instance.normal_matrix_2,
-//     );
-
-//     // Construct the tangent matrix
-//     let world_normal = normalize(normal_matrix * model.normal);
-//     let world_tangent = normalize(normal_matrix * model.tangent);
-//     let world_bitangent = normalize(normal_matrix * model.bitangent);
-//     let tangent_matrix = transpose(mat3x3<f32>(
-//         world_tangent,
-//         world_bitangent,
-//         world_normal,
-//     ));
-
-//     let world_position = model_matrix * vec4<f32>(model.position, 1.0);
-
-//     var out: VertexOutput;
-//     out.clip_position = camera.view_proj * world_position;
-//     out.tex_coords = model.tex_coords;
-    
-//     out.world_normal = normal_matrix * model.normal; // UPDATED!
-//     var world_position: vec4<f32> = model_matrix * vec4<f32>(model.position, 1.0);
-//     out.world_position = world_position.xyz;
-    
-//     out.tangent_position = tangent_matrix * world_position.xyz;
-//     out.tangent_view_position = tangent_matrix * camera.view_pos.xyz;
-//     out.tangent_light_position = tangent_matrix * light.position.xyz;
-//     return out;
-
-
     let model_matrix = mat4x4<f32>(
-                                   instance.model_matrix_0,
-                                   instance.model_matrix_1,
-                                   instance.model_matrix_2,
-                                   instance.model_matrix_3,
-                                   );
-    // NEW!
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+
     let normal_matrix = mat3x3<f32>(
-                                    instance.normal_matrix_0,
-                                    instance.normal_matrix_1,
-                                    instance.normal_matrix_2,
-                                    );
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.world_normal = normal_matrix * model.normal; // UPDATED!
-    var world_position: vec4<f32> = model_matrix * vec4<f32>(model.position, 1.0);
+    // The tangent frame goes to the fragment shader in world space, where the
+    // sampled normal is brought out of tangent space, since the number of lights
+    // isn't known here. Tangents lie in the surface and follow the model
+    // matrix, only the normal needs the inverse-transpose
+    let tangent_matrix = mat3x3<f32>(model_matrix[0].xyz, model_matrix[1].xyz, model_matrix[2].xyz);
+    out.world_normal = normalize(normal_matrix * model.normal);
+    out.world_tangent = normalize(tangent_matrix * model.tangent);
+    // The bitangent is rebuilt from the other two with the handedness of the
+    // mesh, which a mirroring instance flips along with the cross product
+    let handedness = select(1.0, -1.0, dot(cross(model.normal, model.tangent), model.bitangent) < 0.0);
+    let mirrored = select(1.0, -1.0, determinant(tangent_matrix) < 0.0);
+    out.world_bitangent = cross(out.world_normal, out.world_tangent) * handedness * mirrored;
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
     out.world_position = world_position.xyz;
     out.clip_position = camera.view_proj * world_position;
     return out;
@@ -121,36 +113,329 @@ fn vs_main(
 var t_diffuse: texture_2d<f32>;
 @group(0) @binding(1)
 var s_diffuse: sampler;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var s_normal: sampler;
+@group(0) @binding(4)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(5)
+var s_metallic_roughness: sampler;
+@group(0) @binding(6)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(7)
+var s_occlusion: sampler;
+@group(0) @binding(8)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(9)
+var s_emissive: sampler;
+
+// AlphaMode in model.rs
+const ALPHA_MASK: u32 = 1u;
+const ALPHA_BLEND: u32 = 2u;
+
+struct Material {
+    base_color_factor: vec4<f32>,
+    emissive_factor: vec3<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    occlusion_strength: f32,
+    alpha_cutoff: f32,
+    alpha_mode: u32,
+}
+@group(0) @binding(10)
+var<uniform> material: Material;
+
+struct Shadow {
+    light_view_proj: mat4x4<f32>,
+}
+@group(3) @binding(0)
+var t_shadow: texture_depth_2d;
+@group(3) @binding(1)
+var s_shadow: sampler_comparison;
+@group(3) @binding(2)
+var<uniform> shadow: Shadow;
+// Screen space ambient occlusion, 1 where nothing occludes
+@group(3) @binding(3)
+var t_ssao: texture_2d<f32>;
+
+// Image based lighting, baked from the environment by ibl.wgsl
+struct Environment {
+    inv_view_proj: mat4x4<f32>,
+    intensity: f32,
+    // Mip level of the prefiltered map for roughness 1
+    max_lod: f32,
+    far_depth: f32,
+}
+@group(3) @binding(4)
+var t_irradiance: texture_cube<f32>;
+@group(3) @binding(5)
+var t_prefiltered: texture_cube<f32>;
+// Scale and bias of f0 by n_dot_v and roughness
+@group(3) @binding(6)
+var t_brdf: texture_2d<f32>;
+@group(3) @binding(7)
+var s_environment: sampler;
+@group(3) @binding(8)
+var<uniform> environment: Environment;
+
+// Returns 1.0 for fully lit and 0.0 for fully shadowed, using a 3x3 PCF kernel
+fn fetch_shadow(world_position: vec3<f32>) -> f32 {
+    let light_space = shadow.light_view_proj * vec4<f32>(world_position, 1.0);
+    if (light_space.w <= 0.0) {
+        return 1.0;
+    }
+    let ndc = light_space.xyz / light_space.w;
+    // Clip space y points up, texture v points down
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5, 0.5);
+    if (any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+
+    let texel = 1.0 / vec2<f32>(textureDimensions(t_shadow));
+    var visibility = 0.0;
+    for (var y = -1; y <= 1; y++) {
+        for (var x = -1; x <= 1; x++) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel;
+            visibility += textureSampleCompareLevel(t_shadow, s_shadow, uv + offset, ndc.z);
+        }
+    }
+    return visibility / 9.0;
+}
+
+// Smoothly reaches zero at `range`, a range of 0 never cuts off
+fn range_attenuation(distance: f32, range: f32) -> f32 {
+    var window = 1.0;
+    if (range > 0.0) {
+        let ratio = distance / range;
+        window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+        window *= window;
+    }
+    return window / (distance * distance + 1.0);
+}
+
+const PI: f32 = 3.14159265359;
+
+// Surface parameters shared by every light
+struct Surface {
+    albedo: vec3<f32>,
+    metallic: f32,
+    roughness: f32,
+    f0: vec3<f32>,
+}
+
+// Trowbridge-Reitz GGX normal distribution
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let denom = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * denom * denom);
+}
+
+// Smith's method with the Schlick-GGX approximation for direct lighting
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let ggx_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let ggx_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return ggx_v * ggx_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Accounts for rough surfaces reflecting less at grazing angles
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Diffuse and specular light from the environment
+fn ambient_light(surface: Surface, normal: vec3<f32>, view_dir: vec3<f32>) -> vec3<f32> {
+    let n_dot_v = max(dot(normal, view_dir), 1e-4);
+    let f = fresnel_schlick_roughness(n_dot_v, surface.f0, surface.roughness);
+    let kd = (vec3<f32>(1.0) - f) * (1.0 - surface.metallic);
+    let irradiance = textureSample(t_irradiance, s_environment, normal).rgb;
+    let reflected = reflect(-view_dir, normal);
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflected, surface.roughness * environment.max_lod).rgb;
+    let brdf = textureSample(t_brdf, s_environment, vec2<f32>(n_dot_v, surface.roughness)).rg;
+    let specular = prefiltered * (f * brdf.x + brdf.y);
+    return (kd * irradiance * surface.albedo + specular) * environment.intensity;
+}
+
+// Cook-Torrance radiance reflected towards `view_dir` from `light`
+fn light_contribution(light: Light, surface: Surface, world_position: vec3<f32>, normal: vec3<f32>, view_dir: vec3<f32>) -> vec3<f32> {
+    var light_dir: vec3<f32>;
+    var attenuation = 1.0;
+    if (light.kind == LIGHT_KIND_DIRECTIONAL) {
+        light_dir = -normalize(light.direction);
+    } else {
+        let to_light = light.position - world_position;
+        let distance = length(to_light);
+        light_dir = to_light / distance;
+        attenuation = range_attenuation(distance, light.range);
+        if (light.kind == LIGHT_KIND_SPOT) {
+            let cos_angle = dot(-light_dir, normalize(light.direction));
+            // smoothstep is undefined for equal edges, a hard cone then
+            if (light.inner_cone_cos > light.outer_cone_cos) {
+                attenuation *= smoothstep(light.outer_cone_cos, light.inner_cone_cos, cos_angle);
+            } else {
+                attenuation *= step(light.outer_cone_cos, cos_angle);
+            }
+        }
+    }
+
+    let n_dot_l = max(dot(normal, light_dir), 0.0);
+    if (n_dot_l <= 0.0) {
+        return vec3<f32>(0.0);
+    }
+    let n_dot_v = max(dot(normal, view_dir), 1e-4);
+    let half_dir = normalize(view_dir + light_dir);
+    let n_dot_h = max(dot(normal, half_dir), 0.0);
+    let h_dot_v = max(dot(half_dir, view_dir), 0.0);
+
+    let d = distribution_ggx(n_dot_h, surface.roughness);
+    let g = geometry_smith(n_dot_v, n_dot_l, surface.roughness);
+    let f = fresnel_schlick(h_dot_v, surface.f0);
+    let specular = d * g * f / (4.0 * n_dot_v * n_dot_l + 1e-4);
+
+    // Metals have no diffuse reflection
+    let kd = (vec3<f32>(1.0) - f) * (1.0 - surface.metallic);
+    let diffuse = kd * surface.albedo / PI;
+
+    let radiance = light.color * light.intensity * attenuation;
+    return (diffuse + specular) * radiance * n_dot_l;
+}
+
+// Must match DebugView in depth_pass.rs
+const DEBUG_LINEAR_DEPTH: u32 = 1u;
+const DEBUG_LOG_DEPTH: u32 = 2u;
+const DEBUG_NORMALS: u32 = 3u;
+const DEBUG_UVS: u32 = 4u;
+const DEBUG_ALBEDO: u32 = 5u;
+const DEBUG_LIGHTING: u32 = 6u;
+
+// Distance in front of the camera of a depth buffer value, inverting the
+// projections of camera.rs
+fn view_distance(depth: f32) -> f32 {
+    let near = camera.depth_range.x;
+    let far = camera.depth_range.y;
+    let reverse_z = camera.depth_range.z > 0.5;
+    if (camera.depth_range.w > 0.5) {
+        if (reverse_z) {
+            return far - depth * (far - near);
+        }
+        return near + depth * (far - near);
+    }
+    if (reverse_z) {
+        return near / depth;
+    }
+    return near * far / (far - depth * (far - near));
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let c = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+    return select(1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3<f32>(0.0031308));
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // What the debug view shows for this fragment, see depth_pass.rs
+    @location(1) debug: vec4<f32>,
+}
 
 @fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-  //    return textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, in.tex_coords);
-    
-    // We don't need (or want) much ambient light, so 0.1 is fine
-    // Create the lighting vectors
-    // let tangent_normal = object_normal.xyz * 2.0 - 1.0;
-    // let light_dir = normalize(in.tangent_light_position - in.tangent_position);
-    // let view_dir = normalize(in.tangent_view_position - in.tangent_position);
-    
-    let ambient_strength = 0.1;
-    let ambient_color = light.color.xyz * ambient_strength;
-
-    let light_dir = normalize(light.position.xyz - in.world_position);
-
-    let diffuse_strength = max(dot(in.world_normal, light_dir), 0.0);
-    let diffuse_color = light.color.xyz * diffuse_strength;
-
-    
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    let base_color = textureSample(t_diffuse, s_diffuse, in.tex_coords) * material.base_color_factor;
+    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, in.tex_coords);
+    let metallic_roughness = textureSample(t_metallic_roughness, s_metallic_roughness, in.tex_coords);
+    let occlusion_sample = textureSample(t_occlusion, s_occlusion, in.tex_coords).r;
+    let emissive = textureSample(t_emissive, s_emissive, in.tex_coords).rgb * material.emissive_factor;
+
+    // Tangent space -> world space
+    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+    let tangent_matrix = mat3x3<f32>(
+        normalize(in.world_tangent),
+        normalize(in.world_bitangent),
+        normalize(in.world_normal),
+    );
+    let normal = normalize(tangent_matrix * tangent_normal);
+
+    var surface: Surface;
+    surface.albedo = base_color.rgb;
+    surface.metallic = clamp(metallic_roughness.b * material.metallic_factor, 0.0, 1.0);
+    // Fully smooth surfaces turn the specular highlight into a singularity
+    surface.roughness = clamp(metallic_roughness.g * material.roughness_factor, 0.04, 1.0);
+    surface.f0 = mix(vec3<f32>(0.04), surface.albedo, surface.metallic);
+    let ssao = textureLoad(t_ssao, vec2<i32>(in.clip_position.xy), 0).r;
+    let occlusion = mix(1.0, occlusion_sample, material.occlusion_strength) * ssao;
+
     let view_dir = normalize(camera.view_pos.xyz - in.world_position);
-    let half_dir = normalize(view_dir + light_dir);
-    let specular_strength = pow(max(dot(in.world_normal, half_dir), 0.0), 32.0);
-    let specular_color = specular_strength * light.color.xyz;
+    let ambient_color = ambient_light(surface, normal, view_dir) * occlusion;
+
+    // Only one light has a shadow map
+    let shadow_factor = fetch_shadow(in.world_position);
 
-    
+    var light_color = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.count; i++) {
+        var contribution = light_contribution(lights.data[i], surface, in.world_position, normal, view_dir);
+        if (i == lights.shadow_index) {
+            contribution *= shadow_factor;
+        }
+        light_color += contribution;
+    }
 
-    //    let result = specular_color;
-    let result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
+    let result = ambient_color + light_color + emissive;
 
-    return vec4<f32>(result, object_color.a);
+    var out: FragmentOutput;
+
+    let near = camera.depth_range.x;
+    let far = camera.depth_range.y;
+    let distance = view_distance(in.clip_position.z);
+    switch camera.debug_view {
+        case DEBUG_LINEAR_DEPTH: {
+            out.debug = vec4<f32>(vec3<f32>((distance - near) / (far - near)), 1.0);
+        }
+        case DEBUG_LOG_DEPTH: {
+            out.debug = vec4<f32>(vec3<f32>(log(distance / near) / log(far / near)), 1.0);
+        }
+        case DEBUG_NORMALS: {
+            out.debug = vec4<f32>(normal * 0.5 + 0.5, 1.0);
+        }
+        case DEBUG_UVS: {
+            out.debug = vec4<f32>(fract(in.tex_coords), 0.0, 1.0);
+        }
+        case DEBUG_ALBEDO: {
+            out.debug = vec4<f32>(linear_to_srgb(surface.albedo), 1.0);
+        }
+        case DEBUG_LIGHTING: {
+            // Lit again as if white, only while this view is on
+            var white = surface;
+            white.albedo = vec3<f32>(1.0);
+            white.f0 = mix(vec3<f32>(0.04), white.albedo, white.metallic);
+            var lighting = ambient_light(white, normal, view_dir) * occlusion;
+            for (var i = 0u; i < lights.count; i++) {
+                var contribution = light_contribution(lights.data[i], white, in.world_position, normal, view_dir);
+                if (i == lights.shadow_index) {
+                    contribution *= shadow_factor;
+                }
+                lighting += contribution;
+            }
+            out.debug = vec4<f32>(linear_to_srgb(lighting), 1.0);
+        }
+        default: {
+            out.debug = vec4<f32>(0.0);
+        }
+    }
+
+    var alpha = base_color.a;
+    if (material.alpha_mode != ALPHA_BLEND) {
+        // After all texture samples, which need every pixel of the quad
+        if (material.alpha_mode == ALPHA_MASK && alpha < material.alpha_cutoff) {
+            discard;
+        }
+        alpha = 1.0;
+    }
+    out.color = vec4<f32>(result, alpha);
+    return out;
 }
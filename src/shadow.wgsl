@@ -0,0 +1,69 @@
+// Vertex shader
+
+struct Shadow {
+    light_view_proj: mat4x4<f32>,
+}
+@group(0) @binding(0)
+var<uniform> shadow: Shadow;
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+};
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    var out: VertexOutput;
+    out.clip_position = shadow.light_view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    out.tex_coords = model.tex_coords;
+    return out;
+}
+
+// Fragment shader, only for masked materials
+
+// The material group of shader.wgsl, of which only the base color is used
+@group(1) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(1) @binding(1)
+var s_diffuse: sampler;
+
+struct Material {
+    base_color_factor: vec4<f32>,
+    emissive_factor: vec3<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    occlusion_strength: f32,
+    alpha_cutoff: f32,
+    alpha_mode: u32,
+}
+@group(1) @binding(10)
+var<uniform> material: Material;
+
+@fragment
+fn fs_masked(in: VertexOutput) {
+    let alpha = textureSample(t_diffuse, s_diffuse, in.tex_coords).a * material.base_color_factor.a;
+    if (alpha < material.alpha_cutoff) {
+        discard;
+    }
+}
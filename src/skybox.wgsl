@@ -0,0 +1,53 @@
+// Draws the environment cube map behind everything, see environment.rs
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
+
+struct Environment {
+    // Inverse of the projection times the camera rotation, maps the screen to
+    // directions
+    inv_view_proj: mat4x4<f32>,
+    intensity: f32,
+    max_lod: f32,
+    // Depth of pixels nothing was drawn on
+    far_depth: f32,
+}
+
+@group(0) @binding(0)
+var t_sky: texture_cube<f32>;
+@group(0) @binding(1)
+var s_sky: sampler;
+@group(0) @binding(2)
+var<uniform> environment: Environment;
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.ndc = in.position.xy;
+    // Only passes the depth test where the depth buffer is still cleared
+    out.clip_position = vec4<f32>(in.position.xy, environment.far_depth, 1.0);
+    return out;
+}
+
+struct FragmentOutput {
+    @location(0) color: vec4<f32>,
+    // Masked, the debug view keeps its clear color
+    @location(1) debug: vec4<f32>,
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> FragmentOutput {
+    let point = environment.inv_view_proj * vec4<f32>(in.ndc, 0.5, 1.0);
+    let direction = normalize(point.xyz / point.w);
+    var out: FragmentOutput;
+    out.color = vec4<f32>(textureSample(t_sky, s_sky, direction).rgb * environment.intensity, 1.0);
+    out.debug = vec4<f32>(0.0);
+    return out;
+}
@@ -0,0 +1,458 @@
+//! Screen space ambient occlusion.
+//!
+//! A prepass draws the view space normals of the scene along with its depth,
+//! into the single sampled texture of [`DepthPass`](crate::depth_pass::DepthPass).
+//! For every pixel, points in the hemisphere around the normal are checked
+//! against the depth buffer, the more are behind geometry the less ambient
+//! light reaches the pixel. The noisy result is blurred into
+//! [`SsaoPass::view`], which the main shader multiplies its ambient term by.
+
+use std::ops::Range;
+
+use cgmath::{InnerSpace, SquareMatrix, Vector3};
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use wgpu::util::DeviceExt;
+
+use crate::camera::Projection;
+use crate::instance::InstanceRaw;
+use crate::model::{self, Vertex};
+use crate::quad::Quad;
+use crate::texture;
+use crate::create_render_pipeline;
+
+/// Must match `KERNEL_SIZE` in ssao.wgsl
+const KERNEL_SIZE: usize = 16;
+const NORMAL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+const OCCLUSION_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R8Unorm;
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SsaoSettings {
+    /// Without SSAO the ambient light reaches everywhere
+    pub enabled: bool,
+    /// How far around a point occluders are looked for, in world units
+    pub radius: f32,
+    /// Depth difference ignored, against self occlusion of flat surfaces
+    pub bias: f32,
+    /// Exponent of the visibility, higher darkens the occluded parts more
+    pub intensity: f32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            radius: 0.5,
+            bias: 0.025,
+            intensity: 1.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SsaoUniform {
+    proj: [[f32; 4]; 4],
+    inv_proj: [[f32; 4]; 4],
+    kernel: [[f32; 4]; KERNEL_SIZE],
+    radius: f32,
+    bias: f32,
+    intensity: f32,
+    background_depth: f32,
+}
+
+pub struct SsaoPass {
+    pub settings: SsaoSettings,
+    uniform: SsaoUniform,
+    buffer: wgpu::Buffer,
+
+    normal_pipeline: wgpu::RenderPipeline,
+    ssao_pipeline: wgpu::RenderPipeline,
+    blur_pipeline: wgpu::RenderPipeline,
+    ssao_layout: wgpu::BindGroupLayout,
+    blur_layout: wgpu::BindGroupLayout,
+    quad: Quad,
+
+    normal: texture::Texture,
+    /// Before blurring
+    occlusion: texture::Texture,
+    /// Blurred, what the main shader reads
+    blurred: texture::Texture,
+    ssao_bind_group: wgpu::BindGroup,
+    blur_bind_group: wgpu::BindGroup,
+}
+
+impl SsaoPass {
+    /// `depth` is the single sampled depth texture the prepass fills, it is
+    /// cleared to `depth_mode`'s clear value
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        camera_layout: &wgpu::BindGroupLayout,
+        material_layout: &wgpu::BindGroupLayout,
+        depth: &texture::Texture,
+        depth_mode: texture::DepthMode,
+        width: u32,
+        height: u32,
+        settings: SsaoSettings,
+    ) -> Self {
+        let uniform = SsaoUniform {
+            proj: cgmath::Matrix4::identity().into(),
+            inv_proj: cgmath::Matrix4::identity().into(),
+            kernel: sample_kernel(),
+            radius: settings.radius,
+            bias: settings.bias,
+            intensity: settings.intensity,
+            background_depth: depth_mode.clear_value(),
+        };
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("SSAO Uniform Buffer"),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let texture_entry = |binding, filterable| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let ssao_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SSAO Layout"),
+            entries: &[
+                // Depth is read as a plain float texture, the GL backend
+                // can't load from depth textures
+                texture_entry(0, false),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::NonFiltering),
+                    count: None,
+                },
+                texture_entry(2, false),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+        });
+        let blur_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("SSAO Blur Layout"),
+            entries: &[texture_entry(4, false)],
+        });
+
+        let normal_pipeline = {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("SSAO Normal Pipeline Layout"),
+                bind_group_layouts: &[camera_layout, material_layout],
+                push_constant_ranges: &[],
+            });
+            create_render_pipeline(
+                device,
+                &layout,
+                &[NORMAL_FORMAT],
+                Some(texture::Texture::DEPTH_FORMAT),
+                depth_mode,
+                true,
+                1,
+                wgpu::BlendState::REPLACE,
+                &[model::ModelVertex::desc(), InstanceRaw::desc()],
+                wgpu::include_wgsl!("ssao_normal.wgsl"),
+            )
+        };
+
+        let shader = device.create_shader_module(wgpu::include_wgsl!("ssao.wgsl"));
+        let fullscreen_pipeline = |label, layout: &wgpu::BindGroupLayout, entry_point| {
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[Quad::desc()],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point,
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: OCCLUSION_FORMAT,
+                        blend: None,
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            })
+        };
+        let ssao_pipeline = fullscreen_pipeline("SSAO Pipeline", &ssao_layout, "fs_ssao");
+        let blur_pipeline = fullscreen_pipeline("SSAO Blur Pipeline", &blur_layout, "fs_blur");
+
+        let normal = create_target(device, width, height, NORMAL_FORMAT, "ssao_normal_texture");
+        let occlusion = create_target(device, width, height, OCCLUSION_FORMAT, "ssao_texture");
+        let blurred = create_target(device, width, height, OCCLUSION_FORMAT, "ssao_blurred_texture");
+        let ssao_bind_group = create_ssao_bind_group(device, &ssao_layout, depth, &normal, &buffer);
+        let blur_bind_group = create_blur_bind_group(device, &blur_layout, &occlusion);
+
+        Self {
+            settings,
+            uniform,
+            buffer,
+            normal_pipeline,
+            ssao_pipeline,
+            blur_pipeline,
+            ssao_layout,
+            blur_layout,
+            quad: Quad::fullscreen(device),
+            normal,
+            occlusion,
+            blurred,
+            ssao_bind_group,
+            blur_bind_group,
+        }
+    }
+
+    /// `depth` is the recreated depth texture, the bind group of the main
+    /// shader has to be recreated with the new [`SsaoPass::view`]
+    pub fn resize(&mut self, device: &wgpu::Device, depth: &texture::Texture, width: u32, height: u32) {
+        self.normal = create_target(device, width, height, NORMAL_FORMAT, "ssao_normal_texture");
+        self.occlusion = create_target(device, width, height, OCCLUSION_FORMAT, "ssao_texture");
+        self.blurred = create_target(device, width, height, OCCLUSION_FORMAT, "ssao_blurred_texture");
+        self.ssao_bind_group = create_ssao_bind_group(device, &self.ssao_layout, depth, &self.normal, &self.buffer);
+        self.blur_bind_group = create_blur_bind_group(device, &self.blur_layout, &self.occlusion);
+    }
+
+    /// Ambient visibility per pixel, 1 where nothing occludes
+    pub fn view(&self) -> &wgpu::TextureView {
+        &self.blurred.view
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, projection: &Projection) {
+        let proj = projection.calc_matrix();
+        self.uniform.proj = proj.into();
+        self.uniform.inv_proj = proj.invert().unwrap_or_else(cgmath::Matrix4::identity).into();
+        self.uniform.radius = self.settings.radius;
+        self.uniform.bias = self.settings.bias;
+        self.uniform.intensity = self.settings.intensity;
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    /// Draws each `(model, instance buffer, instances)` into the normal and
+    /// `depth` textures, then computes the occlusion from them. When
+    /// disabled the result is cleared to fully visible.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        depth: &texture::Texture,
+        camera_bind_group: &wgpu::BindGroup,
+        draws: &[(&model::Model, &wgpu::Buffer, Range<u32>)],
+    ) {
+        if !self.settings.enabled {
+            clear(encoder, &self.blurred.view);
+            return;
+        }
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SSAO Normal Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.normal.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &depth.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(self.uniform.background_depth),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(&self.normal_pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        for (model, instance_buffer, instances) in draws {
+            render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            // Blended surfaces are drawn over the ambient occlusion
+            for mesh in model.meshes.iter().filter(|mesh| !model.is_blended(mesh)) {
+                render_pass.set_bind_group(1, &model.materials[mesh.material_idx].bind_group, &[]);
+                render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..mesh.num_elements, 0, instances.clone());
+            }
+        }
+        drop(render_pass);
+
+        self.fullscreen(encoder, &self.ssao_pipeline, &self.ssao_bind_group, &self.occlusion.view);
+        self.fullscreen(encoder, &self.blur_pipeline, &self.blur_bind_group, &self.blurred.view);
+    }
+
+    fn fullscreen(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        bind_group: &wgpu::BindGroup,
+        target: &wgpu::TextureView,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("SSAO Render Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        self.quad.draw(&mut render_pass);
+    }
+}
+
+fn clear(encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+    encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("SSAO Clear Pass"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+            view: target,
+            resolve_target: None,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                store: wgpu::StoreOp::Store,
+            },
+        })],
+        depth_stencil_attachment: None,
+        occlusion_query_set: None,
+        timestamp_writes: None,
+    });
+}
+
+/// Points in the unit hemisphere around +z, scaled so more of them are near
+/// the center where occluders matter most. Seeded so frames don't flicker
+/// between runs.
+fn sample_kernel() -> [[f32; 4]; KERNEL_SIZE] {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x55a0);
+    std::array::from_fn(|i| {
+        let direction = Vector3::new(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(0.0..1.0f32),
+        )
+        .normalize();
+        let t = i as f32 / KERNEL_SIZE as f32;
+        let scale = 0.1 + 0.9 * t * t;
+        let sample = direction * rng.gen_range(0.0..1.0f32) * scale;
+        [sample.x, sample.y, sample.z, 0.0]
+    })
+}
+
+fn create_target(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    label: &str,
+) -> texture::Texture {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    // Only read with textureLoad
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+    texture::Texture { texture, view, sampler }
+}
+
+fn create_ssao_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    depth: &texture::Texture,
+    normal: &texture::Texture,
+    buffer: &wgpu::Buffer,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&depth.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&depth.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&normal.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: buffer.as_entire_binding(),
+            },
+        ],
+        label: Some("ssao.bind_group"),
+    })
+}
+
+fn create_blur_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    occlusion: &texture::Texture,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 4,
+            resource: wgpu::BindingResource::TextureView(&occlusion.view),
+        }],
+        label: Some("ssao.blur_bind_group"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kernel_stays_in_the_hemisphere() {
+        for [x, y, z, _] in sample_kernel() {
+            assert!(z >= 0.0);
+            assert!(Vector3::new(x, y, z).magnitude() <= 1.0);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Screen space ambient occlusion, see ssao.rs
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_main(in: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.tex_coords = in.tex_coords;
+    out.clip_position = vec4<f32>(in.position, 1.0);
+    return out;
+}
+
+const KERNEL_SIZE: u32 = 16u;
+const TAU: f32 = 6.28318530718;
+
+struct Ssao {
+    proj: mat4x4<f32>,
+    inv_proj: mat4x4<f32>,
+    // Points in the unit hemisphere around +z, denser towards the center
+    kernel: array<vec4<f32>, KERNEL_SIZE>,
+    radius: f32,
+    bias: f32,
+    intensity: f32,
+    // Depth where nothing was drawn
+    background_depth: f32,
+}
+
+@group(0) @binding(0)
+var t_depth: texture_2d<f32>;
+@group(0) @binding(1)
+var s_depth: sampler;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var<uniform> ssao: Ssao;
+// Only bound for the blur
+@group(0) @binding(4)
+var t_occlusion: texture_2d<f32>;
+
+fn depth_at(uv: vec2<f32>) -> f32 {
+    return textureSampleLevel(t_depth, s_depth, uv, 0.0).x;
+}
+
+fn view_position(uv: vec2<f32>, depth: f32) -> vec3<f32> {
+    // Texture v points down, clip space y up
+    let ndc = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, depth, 1.0);
+    let position = ssao.inv_proj * ndc;
+    return position.xyz / position.w;
+}
+
+@fragment
+fn fs_ssao(in: VertexOutput) -> @location(0) vec4<f32> {
+    let depth = depth_at(in.tex_coords);
+    if (depth == ssao.background_depth) {
+        return vec4<f32>(1.0);
+    }
+    let origin = view_position(in.tex_coords, depth);
+    let normal = normalize(textureLoad(t_normal, vec2<i32>(in.clip_position.xy), 0).xyz);
+
+    // Rotates the kernel per pixel in a 4x4 pattern, which the blur evens out
+    let pixel = vec2<u32>(in.clip_position.xy) % 4u;
+    let angle = f32((pixel.x + pixel.y * 4u) * 7u % 16u) / 16.0 * TAU;
+    var random = vec3<f32>(cos(angle), sin(angle), 0.0);
+    if (abs(dot(random, normal)) > 0.99) {
+        random = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let tangent = normalize(random - normal * dot(random, normal));
+    let tbn = mat3x3<f32>(tangent, cross(normal, tangent), normal);
+
+    var occlusion = 0.0;
+    for (var i = 0u; i < KERNEL_SIZE; i++) {
+        let sample = origin + tbn * ssao.kernel[i].xyz * ssao.radius;
+        let clip = ssao.proj * vec4<f32>(sample, 1.0);
+        let uv = clip.xy / clip.w * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+        let sample_depth = depth_at(uv);
+        if (sample_depth == ssao.background_depth) {
+            continue;
+        }
+        // View space looks down -z, so closer surfaces have a larger z
+        let surface_z = view_position(uv, sample_depth).z;
+        // Surfaces far in front of the sample don't occlude it
+        let in_range = smoothstep(0.0, 1.0, ssao.radius / abs(origin.z - surface_z));
+        if (surface_z >= sample.z + ssao.bias) {
+            occlusion += in_range;
+        }
+    }
+    let visibility = pow(1.0 - occlusion / f32(KERNEL_SIZE), ssao.intensity);
+    return vec4<f32>(visibility, 0.0, 0.0, 1.0);
+}
+
+// Averages the 4x4 pixels the noise pattern repeats over
+@fragment
+fn fs_blur(in: VertexOutput) -> @location(0) vec4<f32> {
+    let size = vec2<i32>(textureDimensions(t_occlusion));
+    let center = vec2<i32>(in.clip_position.xy);
+    var sum = 0.0;
+    for (var y = -2; y < 2; y++) {
+        for (var x = -2; x < 2; x++) {
+            let coords = clamp(center + vec2<i32>(x, y), vec2<i32>(0), size - 1);
+            sum += textureLoad(t_occlusion, coords, 0).r;
+        }
+    }
+    return vec4<f32>(sum / 16.0, 0.0, 0.0, 1.0);
+}
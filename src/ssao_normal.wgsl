@@ -0,0 +1,92 @@
+// SSAO prepass, see ssao.rs: draws the view space normals of the scene and
+// fills the depth buffer the occlusion is computed from. Masked materials
+// are cut out like in shader.wgsl.
+
+struct CameraUniform {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+// The material group of shader.wgsl, of which only the base color is used
+@group(1) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(1) @binding(1)
+var s_diffuse: sampler;
+
+struct Material {
+    base_color_factor: vec4<f32>,
+    emissive_factor: vec3<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    occlusion_strength: f32,
+    alpha_cutoff: f32,
+    alpha_mode: u32,
+}
+@group(1) @binding(10)
+var<uniform> material: Material;
+
+const ALPHA_MASK: u32 = 1u;
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+};
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) view_normal: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+}
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+    // The view matrix is a rotation plus translation, so it transforms
+    // normals as is
+    let view_rotation = mat3x3<f32>(camera.view[0].xyz, camera.view[1].xyz, camera.view[2].xyz);
+
+    var out: VertexOutput;
+    out.view_normal = view_rotation * normal_matrix * model.normal;
+    out.tex_coords = model.tex_coords;
+    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    if (material.alpha_mode == ALPHA_MASK) {
+        let alpha = textureSample(t_diffuse, s_diffuse, in.tex_coords).a * material.base_color_factor.a;
+        if (alpha < material.alpha_cutoff) {
+            discard;
+        }
+    }
+    return vec4<f32>(normalize(in.view_normal), 1.0);
+}
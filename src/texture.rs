@@ -1,43 +1,218 @@
-use std::io;
 use std::io::prelude::*;
 use std::fs::File;
 use std::result::Result::Ok;
 
 use image::GenericImageView;
 use anyhow::*;
-use tracing::warn;
-use wgpu::util::DeviceExt;
 
+use crate::mipmap;
+
+/// Layout of a `model::Material` bind group: base color, normal,
+/// metallic-roughness, occlusion and emissive textures each followed by their
+/// sampler, then the material factors uniform.
 pub fn create_texture_bind_group_layout(
     device: &wgpu::Device,
 ) -> wgpu::BindGroupLayout {
-    let texture_bind_group_layout =
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[
-                wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: wgpu::TextureViewDimension::D2,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
-                    },
-                    count: None,
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
                 },
-                wgpu::BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    // This should match the filterable field of the
-                    // corresponding Texture entry above.
-                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                    count: None,
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                // This should match the filterable field of the
+                // corresponding Texture entry above.
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            // normal map
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
                 },
-            ],
-            label: Some("texture_bind_group_layout"),
-        });
-    texture_bind_group_layout
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            // metallic (b) + roughness (g)
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            // occlusion (r)
+            wgpu::BindGroupLayoutEntry {
+                binding: 6,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 7,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            // emissive
+            wgpu::BindGroupLayoutEntry {
+                binding: 8,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 9,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            // MaterialUniform
+            wgpu::BindGroupLayoutEntry {
+                binding: 10,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some("texture_bind_group_layout"),
+    })
+}
+
+
+/// Minification filtering of a texture's sampler
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SamplerFilter {
+    Nearest,
+    /// Linear filtering within and between mip levels
+    Trilinear,
+    /// Trilinear filtering with up to the given number of anisotropic samples
+    /// (1 to 16), silently trilinear on adapters without support
+    Anisotropic(u16),
 }
 
+/// How the mip chain of a texture is filled
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MipmapMode {
+    /// Only the full resolution level
+    None,
+    /// Blits on the GPU, falling back to `Cpu` for formats that can't be rendered to
+    Gpu,
+    /// Box-filters on the CPU and uploads every level
+    Cpu,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TextureOptions {
+    pub filter: SamplerFilter,
+    pub mipmaps: MipmapMode,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self {
+            filter: SamplerFilter::Anisotropic(16),
+            mipmaps: MipmapMode::Gpu,
+        }
+    }
+}
+
+impl SamplerFilter {
+    fn sampler_descriptor(self, label: Option<&str>) -> wgpu::SamplerDescriptor<'_> {
+        let (min_filter, mipmap_filter, anisotropy_clamp) = match self {
+            SamplerFilter::Nearest => (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest, 1),
+            SamplerFilter::Trilinear => (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, 1),
+            SamplerFilter::Anisotropic(n) => (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, n.clamp(1, 16)),
+        };
+        wgpu::SamplerDescriptor {
+            label,
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter,
+            mipmap_filter,
+            anisotropy_clamp,
+            ..Default::default()
+        }
+    }
+}
+
+/// Which end of the depth range is near the camera
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DepthMode {
+    /// 0 at the near plane and 1 at the far plane
+    #[default]
+    Standard,
+    /// 1 at the near plane and 0 at the far plane, or at infinity for
+    /// perspective projections. Floats are most precise close to 0, which
+    /// evens out the precision over distance and avoids z-fighting far away.
+    ReverseZ,
+}
+
+impl DepthMode {
+    /// Depth test of opaque geometry
+    pub fn compare(self) -> wgpu::CompareFunction {
+        match self {
+            DepthMode::Standard => wgpu::CompareFunction::Less,
+            DepthMode::ReverseZ => wgpu::CompareFunction::Greater,
+        }
+    }
+
+    /// Like [`DepthMode::compare`] but passing equal depths too
+    pub fn compare_equal(self) -> wgpu::CompareFunction {
+        match self {
+            DepthMode::Standard => wgpu::CompareFunction::LessEqual,
+            DepthMode::ReverseZ => wgpu::CompareFunction::GreaterEqual,
+        }
+    }
+
+    /// The depth of empty pixels, behind everything
+    pub fn clear_value(self) -> f32 {
+        match self {
+            DepthMode::Standard => 1.0,
+            DepthMode::ReverseZ => 0.0,
+        }
+    }
+}
 
 pub struct Texture {
     pub texture: wgpu::Texture,
@@ -50,7 +225,9 @@ impl Texture {
         path: &std::path::Path,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        label: Option<&str>
+        mipmaps: &mut mipmap::MipmapGenerator,
+        label: Option<&str>,
+        is_linear: bool,
     ) -> Result<Self> {
         let f = File::open(path)?;
         let mut reader = std::io::BufReader::new(f);
@@ -58,27 +235,44 @@ impl Texture {
         // read the whole file
         reader.read_to_end(&mut buffer)?;
         let img = image::load_from_memory(&buffer)?;
-        Self::from_image(device, queue, &img, label)
-            
-            
+        Self::from_image(device, queue, mipmaps, &img, label, is_linear)
     }
     
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        mipmaps: &mut mipmap::MipmapGenerator,
         bytes: &[u8], 
-        label: &str
+        label: &str,
+        is_linear: bool,
     ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        Self::from_image(device, queue, mipmaps, &img, Some(label), is_linear)
     }
 
 
+    /// Uploads `img` with a full mip chain and anisotropic filtering
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
+        mipmaps: &mut mipmap::MipmapGenerator,
         img: &image::DynamicImage,
-        label: Option<&str>
+        label: Option<&str>,
+        is_linear: bool,
+    ) -> Result<Self> {
+        Self::from_image_with_options(device, queue, mipmaps, img, label, is_linear, TextureOptions::default())
+    }
+
+    /// `mipmaps` fills the mip chain on the GPU, share one generator
+    /// between textures so its pipelines are reused
+    pub fn from_image_with_options(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mipmaps: &mut mipmap::MipmapGenerator,
+        img: &image::DynamicImage,
+        label: Option<&str>,
+        is_linear: bool,
+        options: TextureOptions,
     ) -> Result<Self> {
         let rgba = img.to_rgba8();
         let dimensions = img.dimensions();
@@ -104,51 +298,74 @@ impl Texture {
         //     &rgba,
         // );
 
-        
+        // Normal and metallic-roughness maps store data rather than colors, so they must not be gamma decoded
+        let format = if is_linear {
+            wgpu::TextureFormat::Rgba8Unorm
+        } else {
+            wgpu::TextureFormat::Rgba8UnormSrgb
+        };
+
+        let mip_level_count = match options.mipmaps {
+            MipmapMode::None => 1,
+            MipmapMode::Gpu | MipmapMode::Cpu => mipmap::mip_level_count(dimensions.0, dimensions.1),
+        };
+        let use_gpu = mip_level_count > 1
+            && options.mipmaps == MipmapMode::Gpu
+            && mipmap::supports_gpu_generation(device, format);
+
+        let mut usage = wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST;
+        if use_gpu {
+            usage |= wgpu::TextureUsages::COPY_SRC;
+        }
+
         let texture = device.create_texture(
             &wgpu::TextureDescriptor {
                 label,
                 size,
-                mip_level_count: 1,
+                mip_level_count,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
-                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                format,
+                usage,
                 view_formats: &[],
             }
         );
 
-
         //this will write Image data to GPU, other variables are just descriptors
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * dimensions.0),
-                rows_per_image: Some(dimensions.1),
-            },
-            size,
-        );
+        let write_level = |level: u32, data: &image::RgbaImage| {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * data.width()),
+                    rows_per_image: Some(data.height()),
+                },
+                wgpu::Extent3d {
+                    width: data.width(),
+                    height: data.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        };
+        write_level(0, &rgba);
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let sampler = device.create_sampler(
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
+        if use_gpu {
+            mipmaps.generate(device, queue, &texture);
+        } else if mip_level_count > 1 {
+            for (level, data) in mipmap::generate_mip_chain(&rgba, !is_linear).iter().enumerate() {
+                write_level(level as u32 + 1, data);
             }
-        );
-        
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&options.filter.sampler_descriptor(label));
+
         Ok(Self { texture, view, sampler })
     }
 
@@ -158,9 +375,9 @@ impl Texture {
         device: &wgpu::Device,
         texture_bind_group_layout: &wgpu::BindGroupLayout
     ) -> wgpu::BindGroup {
-        let diffuse_bind_group = device.create_bind_group(
+        device.create_bind_group(
             &wgpu::BindGroupDescriptor {
-                layout: &texture_bind_group_layout,
+                layout: texture_bind_group_layout,
                 entries: &[
                     wgpu::BindGroupEntry {
                         binding: 0,
@@ -173,17 +390,24 @@ impl Texture {
                 ],
                 label: Some("diffuse_bind_group"),
             }
-        );
-        diffuse_bind_group
+        )
     }
 
 
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float; // 1.
     
-    pub fn create_depth_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, label: &str) -> Self {
+    /// `mode` sets the comparison of the sampler, which passes for samples
+    /// at least as close as the reference
+    pub fn create_depth_texture(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        mode: DepthMode,
+        label: &str,
+    ) -> Self {
         let size = wgpu::Extent3d { // 2.
-            width: config.width,
-            height: config.height,
+            width,
+            height,
             depth_or_array_layers: 1,
         };
         let desc = wgpu::TextureDescriptor {
@@ -208,7 +432,7 @@ impl Texture {
                 mag_filter: wgpu::FilterMode::Linear,
                 min_filter: wgpu::FilterMode::Linear,
                 mipmap_filter: wgpu::FilterMode::Nearest,
-                compare: Some(wgpu::CompareFunction::LessEqual), // 5.
+                compare: Some(mode.compare_equal()), // 5.
                 lod_min_clamp: 0.0,
                 lod_max_clamp: 100.0,
                 ..Default::default()
@@ -218,25 +442,33 @@ impl Texture {
         Self { texture, view, sampler }
     }
 
+    /// Multisampled depth, with a `sample_count` above 1, can't be bound for
+    /// sampling. Resolving next to a sampleable one fails on the GL backend.
     #[allow(unused)]
     pub fn create_depth_texture_non_comparison_sampler(
         device: &wgpu::Device,
-        config: &wgpu::SurfaceConfiguration,
+        width: u32,
+        height: u32,
+        sample_count: u32,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
-            width: config.width,
-            height: config.height,
+            width,
+            height,
             depth_or_array_layers: 1,
         };
         let desc = wgpu::TextureDescriptor {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            usage: if sample_count > 1 {
+                wgpu::TextureUsages::RENDER_ATTACHMENT
+            } else {
+                wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING
+            },
             view_formats: &[Self::DEPTH_FORMAT],
         };
         let texture = device.create_texture(&desc);
@@ -262,3 +494,178 @@ impl Texture {
     }
 }
 
+
+/// Format of cube maps, HDR so environments can be brighter than 1.0
+pub const CUBE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+/// Direction through the texel at `u`, `v` (0..1, v pointing down) of cube
+/// `face`, faces are in wgpu's layer order +x, -x, +y, -y, +z, -z
+pub fn cube_face_direction(face: usize, u: f32, v: f32) -> cgmath::Vector3<f32> {
+    let (u, v) = (u * 2.0 - 1.0, v * 2.0 - 1.0);
+    match face {
+        0 => cgmath::Vector3::new(1.0, -v, -u),
+        1 => cgmath::Vector3::new(-1.0, -v, u),
+        2 => cgmath::Vector3::new(u, 1.0, v),
+        3 => cgmath::Vector3::new(u, -1.0, -v),
+        4 => cgmath::Vector3::new(u, -v, 1.0),
+        _ => cgmath::Vector3::new(-u, -v, -1.0),
+    }
+}
+
+/// Linear RGBA of `img`, 8 and 16 bit images are taken as sRGB encoded
+fn linear_rgba(img: &image::DynamicImage) -> image::Rgba32FImage {
+    let mut rgba = img.to_rgba32f();
+    let is_float = matches!(img, image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_));
+    if !is_float {
+        for pixel in rgba.pixels_mut() {
+            for c in &mut pixel.0[..3] {
+                *c = if *c <= 0.04045 { *c / 12.92 } else { ((*c + 0.055) / 1.055).powf(2.4) };
+            }
+        }
+    }
+    rgba
+}
+
+impl Texture {
+    /// `faces` in the order +x, -x, +y, -y, +z, -z, all square and of the
+    /// same size
+    pub fn create_cube_from_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        faces: &[image::DynamicImage],
+        label: &str,
+    ) -> Result<Self> {
+        if faces.len() != 6 {
+            bail!("a cube map needs 6 faces, got {}", faces.len());
+        }
+        let size = faces[0].width();
+        if let Some(face) = faces.iter().find(|f| f.dimensions() != (size, size)) {
+            bail!(
+                "cube map faces must be square and of the same size, got {:?} next to {}x{}",
+                face.dimensions(),
+                size,
+                size
+            );
+        }
+        let faces = faces
+            .iter()
+            .map(|face| linear_rgba(face).pixels().map(|p| p.0).collect())
+            .collect::<Vec<_>>();
+        Ok(Self::create_cube(device, queue, size, &faces, label))
+    }
+
+    /// Resamples a latitude-longitude image, the usual layout of `.hdr`
+    /// environments, into faces of `size` x `size` texels. The center of the
+    /// image ends up towards -z.
+    pub fn create_cube_from_equirectangular(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        img: &image::DynamicImage,
+        size: u32,
+        label: &str,
+    ) -> Result<Self> {
+        use cgmath::InnerSpace;
+
+        let rgba = linear_rgba(img);
+        let (width, height) = rgba.dimensions();
+        if width < 2 || height < 2 {
+            bail!("equirectangular image is too small: {}x{}", width, height);
+        }
+        // Bilinear, wrapping around horizontally
+        let sample = |x: f32, y: f32| {
+            let (x, y) = (x - 0.5, (y - 0.5).clamp(0.0, (height - 1) as f32));
+            let (x0, y0) = (x.floor(), y.floor());
+            let (fx, fy) = (x - x0, y - y0);
+            let texel = |x: f32, y: f32| {
+                let x = (x as i64).rem_euclid(width as i64) as u32;
+                let y = (y as u32).min(height - 1);
+                rgba.get_pixel(x, y).0
+            };
+            let (a, b, c, d) = (texel(x0, y0), texel(x0 + 1.0, y0), texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+            std::array::from_fn(|i| {
+                let top = a[i] + (b[i] - a[i]) * fx;
+                let bottom = c[i] + (d[i] - c[i]) * fx;
+                top + (bottom - top) * fy
+            })
+        };
+
+        let faces = (0..6)
+            .map(|face| {
+                (0..size * size)
+                    .map(|i| {
+                        let u = ((i % size) as f32 + 0.5) / size as f32;
+                        let v = ((i / size) as f32 + 0.5) / size as f32;
+                        let dir = cube_face_direction(face, u, v).normalize();
+                        let longitude = dir.x.atan2(-dir.z) / std::f32::consts::TAU + 0.5;
+                        let latitude = dir.y.clamp(-1.0, 1.0).acos() / std::f32::consts::PI;
+                        sample(longitude * width as f32, latitude * height as f32)
+                    })
+                    .collect()
+            })
+            .collect::<Vec<_>>();
+        Ok(Self::create_cube(device, queue, size, &faces, label))
+    }
+
+    /// Uploads six faces of `size` x `size` linear RGBA texels, see
+    /// [`cube_face_direction`] for their order and orientation
+    pub fn create_cube(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        size: u32,
+        faces: &[Vec<[f32; 4]>],
+        label: &str,
+    ) -> Self {
+        let extent = wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: CUBE_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        let data = faces
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|&c| half::f16::from_f32(c).to_bits())
+            .collect::<Vec<u16>>();
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(&data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(8 * size),
+                rows_per_image: Some(size),
+            },
+            extent,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self { texture, view, sampler }
+    }
+}